@@ -0,0 +1,160 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use rocket::serde::Serialize;
+
+// Stable codes for clients to act on, the messages are only for people
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum ErrorCode {
+    NOTCONNECTED,
+    NOTFOUND,
+    ALREADYEXISTS,
+    // The printer can't do that right now, e.g. pausing when nothing is printing
+    INVALIDSTATE,
+    INVALIDINPUT,
+    // The request made sense but some of its values didn't
+    VALIDATIONFAILED,
+    UNAUTHORIZED,
+    FORBIDDEN,
+    // Too many failed logins, wait a bit before trying again
+    TOOMANYREQUESTS,
+    TOOLARGE,
+    STORAGEFULL,
+    TIMEDOUT,
+    // The printer itself reported a problem
+    PRINTERERROR,
+    INTERNAL
+}
+
+impl ErrorCode {
+    pub fn http_status(self) -> u16 {
+        match self {
+            ErrorCode::NOTCONNECTED => 503,
+            ErrorCode::NOTFOUND => 404,
+            ErrorCode::ALREADYEXISTS | ErrorCode::INVALIDSTATE => 409,
+            ErrorCode::INVALIDINPUT => 400,
+            ErrorCode::VALIDATIONFAILED => 422,
+            ErrorCode::UNAUTHORIZED => 401,
+            ErrorCode::FORBIDDEN => 403,
+            ErrorCode::TOOMANYREQUESTS => 429,
+            ErrorCode::TOOLARGE => 413,
+            ErrorCode::STORAGEFULL => 507,
+            ErrorCode::TIMEDOUT => 504,
+            ErrorCode::PRINTERERROR => 502,
+            ErrorCode::INTERNAL => 500
+        }
+    }
+
+    // For errors that didn't come with a code, e.g. from Rocket's catchers
+    pub fn from_http_status(status: u16) -> ErrorCode {
+        match status {
+            400 => ErrorCode::INVALIDINPUT,
+            401 => ErrorCode::UNAUTHORIZED,
+            403 => ErrorCode::FORBIDDEN,
+            404 => ErrorCode::NOTFOUND,
+            409 => ErrorCode::ALREADYEXISTS,
+            413 => ErrorCode::TOOLARGE,
+            415 | 422 => ErrorCode::VALIDATIONFAILED,
+            429 => ErrorCode::TOOMANYREQUESTS,
+            503 => ErrorCode::NOTCONNECTED,
+            504 => ErrorCode::TIMEDOUT,
+            507 => ErrorCode::STORAGEFULL,
+            _ => ErrorCode::INTERNAL
+        }
+    }
+
+    fn from_kind(kind: ErrorKind) -> ErrorCode {
+        match kind {
+            ErrorKind::NotFound => ErrorCode::NOTFOUND,
+            ErrorKind::AlreadyExists => ErrorCode::ALREADYEXISTS,
+            ErrorKind::InvalidInput | ErrorKind::InvalidData => ErrorCode::INVALIDINPUT,
+            ErrorKind::PermissionDenied => ErrorCode::FORBIDDEN,
+            ErrorKind::NotConnected => ErrorCode::NOTCONNECTED,
+            ErrorKind::StorageFull => ErrorCode::STORAGEFULL,
+            ErrorKind::TimedOut => ErrorCode::TIMEDOUT,
+            _ => ErrorCode::INTERNAL
+        }
+    }
+
+    // The closest io kind, so code that only looks at the kind still works
+    fn io_kind(self) -> ErrorKind {
+        match self {
+            ErrorCode::NOTCONNECTED => ErrorKind::NotConnected,
+            ErrorCode::NOTFOUND => ErrorKind::NotFound,
+            ErrorCode::ALREADYEXISTS => ErrorKind::AlreadyExists,
+            ErrorCode::INVALIDSTATE | ErrorCode::INVALIDINPUT | ErrorCode::VALIDATIONFAILED => ErrorKind::InvalidInput,
+            ErrorCode::UNAUTHORIZED | ErrorCode::FORBIDDEN | ErrorCode::TOOMANYREQUESTS => ErrorKind::PermissionDenied,
+            ErrorCode::TOOLARGE => ErrorKind::InvalidInput,
+            ErrorCode::STORAGEFULL => ErrorKind::StorageFull,
+            ErrorCode::TIMEDOUT => ErrorKind::TimedOut,
+            ErrorCode::PRINTERERROR | ErrorCode::INTERNAL => ErrorKind::Other
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String
+}
+
+// Rides along inside a std::io::Error, so everything can keep returning std::io::Result
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodedError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub fields: Vec<FieldError>
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+impl CodedError {
+    pub fn from_io(err: &Error) -> CodedError {
+        match err.get_ref().and_then(|inner| inner.downcast_ref::<CodedError>()) {
+            Some(coded) => coded.clone(),
+            None => CodedError { code: ErrorCode::from_kind(err.kind()), message: err.to_string(), fields: Vec::new() }
+        }
+    }
+
+    pub fn into_io(self) -> Error {
+        Error::new(self.code.io_kind(), self)
+    }
+}
+
+pub fn coded_error<M: Into<String>>(code: ErrorCode, message: M) -> Error {
+    CodedError { code, message: message.into(), fields: Vec::new() }.into_io()
+}
+
+// A single value in a request that isn't acceptable
+pub fn field_error<F: Into<String>, M: Into<String>>(field: F, message: M) -> Error {
+    let (field, message) = (field.into(), message.into());
+    CodedError { code: ErrorCode::VALIDATIONFAILED, message: format!("{}: {}", field, message), fields: vec![FieldError { field, message }] }.into_io()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_survive_io_errors() {
+        let err = field_error("target", "must be between 0 and 400");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "target: must be between 0 and 400");
+        let coded = CodedError::from_io(&err);
+        assert_eq!(coded.code, ErrorCode::VALIDATIONFAILED);
+        assert_eq!(coded.fields, vec![FieldError { field: "target".to_string(), message: "must be between 0 and 400".to_string() }]);
+
+        // Plain io errors get the closest code
+        let coded = CodedError::from_io(&Error::new(ErrorKind::AlreadyExists, "file already exists"));
+        assert_eq!((coded.code, coded.code.http_status()), (ErrorCode::ALREADYEXISTS, 409));
+        assert_eq!(CodedError::from_io(&Error::new(ErrorKind::BrokenPipe, "gone")).code, ErrorCode::INTERNAL);
+        assert_eq!(CodedError::from_io(&coded_error(ErrorCode::NOTCONNECTED, "No printer")).code.http_status(), 503);
+    }
+}
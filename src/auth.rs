@@ -0,0 +1,448 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use argon2::password_hash::rand_core::OsRng;
+use log::{info, warn};
+use rocket::serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::api_error::{coded_error, ErrorCode};
+use crate::config::AuthConfig;
+
+pub const USERS_FILE: &str = "users.json";
+// The first start leaves the admin's password in here, for only the owner of the data dir to read
+pub const ADMIN_PASSWORD_FILE: &str = "admin_password";
+
+// Failed logins in a row before they get slowed down, the longest they have to wait,
+// and how long before failures are forgotten
+const FREE_LOGIN_ATTEMPTS: u32 = 5;
+const MAX_LOGIN_BACKOFF: Duration = Duration::from_secs(15 * 60);
+const FAILED_LOGIN_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Each role can do everything the ones before it can
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Role {
+    // Look at the printer and files
+    VIEWER,
+    // Upload files, print, move and heat the printer
+    OPERATOR,
+    // Connect printers, use the console, change firmware settings, manage users and keys
+    ADMIN
+}
+
+// How the request proved who it's from
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Credential {
+    // Logged in to the web UI with the user's password
+    SESSION,
+    APIKEY,
+    // Auth is turned off
+    NONE
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+    pub credential: Credential
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct UserInfo {
+    pub name: String,
+    pub role: Role
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct User {
+    name: String,
+    role: Role,
+    // Argon2 PHC string
+    password_hash: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ApiKey {
+    id: String,
+    name: String,
+    role: Role,
+    created_secs_since_epoch: u64,
+    // Keys are random, so a plain hash is enough
+    key_sha256: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+struct Accounts {
+    users: Vec<User>,
+    api_keys: Vec<ApiKey>
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    pub role: Role,
+    pub created_secs_since_epoch: u64
+}
+
+impl From<&ApiKey> for ApiKeyInfo {
+    fn from(key: &ApiKey) -> Self {
+        ApiKeyInfo { id: key.id.clone(), name: key.name.clone(), role: key.role, created_secs_since_epoch: key.created_secs_since_epoch }
+    }
+}
+
+struct Session {
+    user: String,
+    expires: Instant
+}
+
+struct FailedLogins {
+    count: u32,
+    last: Instant
+}
+
+impl FailedLogins {
+    // Doubles with every failure after the free ones
+    fn wait(&self) -> Duration {
+        let backoff = match self.count.checked_sub(FREE_LOGIN_ATTEMPTS) {
+            Some(over) => Duration::from_secs(1u64 << over.min(20)).min(MAX_LOGIN_BACKOFF),
+            None => Duration::ZERO
+        };
+        backoff.saturating_sub(self.last.elapsed())
+    }
+}
+
+// Users, API keys and web UI sessions. Sessions only live in memory, restarting logs everyone out.
+pub struct Auth {
+    pub config: AuthConfig,
+    path: PathBuf,
+    accounts: Mutex<Accounts>,
+    // By the hash of the session token
+    sessions: Mutex<HashMap<String, Session>>,
+    // By user name and by address, so neither guessing one user's password nor trying many users goes fast
+    failed_logins: Mutex<HashMap<String, FailedLogins>>
+}
+
+fn random_token() -> String {
+    rand::random::<[u8; 32]>().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sha256(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn hash_password(password: &str) -> std::io::Result<String> {
+    if password.len() < 8 {
+        return Err(Error::new(ErrorKind::InvalidInput, "Passwords must be at least 8 characters"));
+    }
+    Argon2::default().hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+    .map(|hash| hash.to_string())
+    .map_err(|e| Error::other(e.to_string()))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+impl Auth {
+    // Without any users nobody could log in, so the first start creates an admin
+    pub fn load(data_dir: &Path, config: &AuthConfig) -> std::io::Result<Auth> {
+        let path = data_dir.join(USERS_FILE);
+        let accounts = match std::fs::read_to_string(&path) {
+            Ok(contents) => rocket::serde::json::from_str::<Accounts>(&contents)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Accounts::default(),
+            Err(e) => return Err(e)
+        };
+
+        let auth = Auth { config: config.clone(), path, accounts: Mutex::new(accounts), sessions: Mutex::new(HashMap::new()),
+            failed_logins: Mutex::new(HashMap::new()) };
+        if config.enabled && auth.accounts.lock().unwrap().users.is_empty() {
+            let password = random_token()[..16].to_string();
+            let password_path = data_dir.join(ADMIN_PASSWORD_FILE);
+            // Written before the user exists, so the password can't get lost
+            match std::fs::remove_file(&password_path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&password_path)?;
+            file.write_all(password.as_bytes())?;
+            auth.set_user("admin", Some(&password), Role::ADMIN)?;
+            warn!("Created user admin, the password is in {:?}. Log in and change it, then delete the file", password_path);
+        }
+        Ok(auth)
+    }
+
+    fn save(&self, accounts: &Accounts) -> std::io::Result<()> {
+        let as_string = rocket::serde::json::to_string(accounts)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let tmp_path = self.path.with_extension("json.tmp");
+        let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp_path)?;
+        file.write_all(as_string.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, &self.path)
+    }
+
+    fn failed_login_keys(name: &str, from: Option<IpAddr>) -> Vec<String> {
+        let mut keys = vec![format!("user {}", name)];
+        keys.extend(from.map(|ip| format!("ip {}", ip)));
+        keys
+    }
+
+    // Returns the session token for the cookie
+    pub fn login(&self, name: &str, password: &str, from: Option<IpAddr>) -> std::io::Result<(String, Identity)> {
+        let keys = Self::failed_login_keys(name, from);
+        {
+            let mut failed_logins = self.failed_logins.lock().unwrap();
+            failed_logins.retain(|_, f| f.last.elapsed() < FAILED_LOGIN_MEMORY);
+            let wait = keys.iter().filter_map(|k| failed_logins.get(k)).map(FailedLogins::wait).max().unwrap_or_default();
+            if !wait.is_zero() {
+                return Err(coded_error(ErrorCode::TOOMANYREQUESTS, format!("Too many failed logins, wait {}s before trying again", wait.as_secs() + 1)));
+            }
+        }
+
+        // Checking the password is slow on purpose, so it's done without holding up everyone else
+        let user = self.accounts.lock().unwrap().users.iter()
+            .find(|u| u.name == name)
+            .map(|u| (u.role, u.password_hash.clone()));
+        let role = match user {
+            Some((role, password_hash)) if verify_password(password, &password_hash) => role,
+            _ => {
+                let mut failed_logins = self.failed_logins.lock().unwrap();
+                for key in keys {
+                    let failed = failed_logins.entry(key).or_insert(FailedLogins { count: 0, last: Instant::now() });
+                    failed.count += 1;
+                    failed.last = Instant::now();
+                }
+                warn!("Failed login as {} from {:?}", name, from);
+                return Err(coded_error(ErrorCode::UNAUTHORIZED, "Wrong user name or password"));
+            }
+        };
+        let mut failed_logins = self.failed_logins.lock().unwrap();
+        for key in keys {
+            failed_logins.remove(&key);
+        }
+        drop(failed_logins);
+
+        let token = random_token();
+        let expires = Instant::now() + Duration::from_secs(self.config.session_hours * 3600);
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.expires > Instant::now());
+        sessions.insert(sha256(&token), Session { user: name.to_string(), expires });
+        info!("{} logged in", name);
+        Ok((token, Identity { name: name.to_string(), role, credential: Credential::SESSION }))
+    }
+
+    pub fn logout(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(&sha256(token));
+    }
+
+    // Roles are looked up every time, so changing or deleting a user applies straight away
+    pub fn check_session(&self, token: &str) -> Option<Identity> {
+        let user = self.sessions.lock().unwrap().get(&sha256(token))
+            .filter(|s| s.expires > Instant::now())
+            .map(|s| s.user.clone())?;
+        self.accounts.lock().unwrap().users.iter().find(|u| u.name == user)
+        .map(|u| Identity { name: u.name.clone(), role: u.role, credential: Credential::SESSION })
+    }
+
+    pub fn check_api_key(&self, key: &str) -> Option<Identity> {
+        let key_sha256 = sha256(key);
+        self.accounts.lock().unwrap().api_keys.iter().find(|k| k.key_sha256 == key_sha256)
+        .map(|k| Identity { name: k.name.clone(), role: k.role, credential: Credential::APIKEY })
+    }
+
+    pub fn users(&self) -> Vec<UserInfo> {
+        self.accounts.lock().unwrap().users.iter().map(|u| UserInfo { name: u.name.clone(), role: u.role }).collect()
+    }
+
+    // Creates the user, or changes their password and role. New users need a password.
+    pub fn set_user(&self, name: &str, password: Option<&str>, role: Role) -> std::io::Result<()> {
+        if name.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "User name can't be empty"));
+        }
+        let password_hash = password.map(hash_password).transpose()?;
+
+        let mut accounts = self.accounts.lock().unwrap();
+        match accounts.users.iter().position(|u| u.name == name) {
+            Some(idx) => {
+                if role != Role::ADMIN && accounts.users[idx].role == Role::ADMIN
+                    && accounts.users.iter().filter(|u| u.role == Role::ADMIN).count() == 1 {
+                    return Err(Error::new(ErrorKind::InvalidInput, "There must be at least one admin"));
+                }
+                accounts.users[idx].role = role;
+                if let Some(password_hash) = password_hash {
+                    accounts.users[idx].password_hash = password_hash;
+                }
+            }
+            None => {
+                let password_hash = password_hash.ok_or(Error::new(ErrorKind::InvalidInput, "New users need a password"))?;
+                accounts.users.push(User { name: name.to_string(), role, password_hash });
+            }
+        }
+        self.save(&accounts)
+    }
+
+    // Users changing their own password. API keys can't, even if they're named after a user.
+    pub fn change_password(&self, identity: &Identity, password: &str) -> std::io::Result<()> {
+        if identity.credential != Credential::SESSION {
+            return Err(coded_error(ErrorCode::FORBIDDEN, "Log in as the user to change their password"));
+        }
+        let password_hash = hash_password(password)?;
+        let mut accounts = self.accounts.lock().unwrap();
+        let user = accounts.users.iter_mut().find(|u| u.name == identity.name)
+            .ok_or(Error::new(ErrorKind::NotFound, format!("No user called {}", identity.name)))?;
+        user.password_hash = password_hash;
+        self.save(&accounts)
+    }
+
+    pub fn delete_user(&self, name: &str) -> std::io::Result<()> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let idx = accounts.users.iter().position(|u| u.name == name)
+            .ok_or(Error::new(ErrorKind::NotFound, format!("No user called {}", name)))?;
+        if accounts.users[idx].role == Role::ADMIN && accounts.users.iter().filter(|u| u.role == Role::ADMIN).count() == 1 {
+            return Err(Error::new(ErrorKind::InvalidInput, "There must be at least one admin"));
+        }
+        accounts.users.remove(idx);
+        self.save(&accounts)
+    }
+
+    pub fn api_keys(&self) -> Vec<ApiKeyInfo> {
+        self.accounts.lock().unwrap().api_keys.iter().map(ApiKeyInfo::from).collect()
+    }
+
+    fn add_api_key(&self, name: &str, key: &str, role: Role) -> std::io::Result<ApiKeyInfo> {
+        let api_key = ApiKey { id: random_token()[..8].to_string(), name: name.to_string(), role,
+            created_secs_since_epoch: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs(),
+            key_sha256: sha256(key) };
+        let info = ApiKeyInfo::from(&api_key);
+
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts.api_keys.push(api_key);
+        self.save(&accounts)?;
+        Ok(info)
+    }
+
+    // The key itself is only ever returned here
+    pub fn create_api_key(&self, name: &str, role: Role) -> std::io::Result<(ApiKeyInfo, String)> {
+        let key = random_token();
+        Ok((self.add_api_key(name, &key, role)?, key))
+    }
+
+    // For a key that's already in use somewhere, like the one slicers were set up with before there were API keys.
+    // None if it's been imported before.
+    pub fn import_api_key(&self, name: &str, key: &str, role: Role) -> std::io::Result<Option<ApiKeyInfo>> {
+        if key.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "API key can't be empty"));
+        }
+        if self.check_api_key(key).is_some() {
+            return Ok(None);
+        }
+        self.add_api_key(name, key, role).map(Some)
+    }
+
+    pub fn delete_api_key(&self, id: &str) -> std::io::Result<()> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let count = accounts.api_keys.len();
+        accounts.api_keys.retain(|k| k.id != id);
+        if accounts.api_keys.len() == count {
+            return Err(Error::new(ErrorKind::NotFound, format!("No API key with id {}", id)));
+        }
+        self.save(&accounts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::api_error::CodedError;
+
+    #[test]
+    fn users_keys_and_sessions() {
+        let dir = std::env::temp_dir().join(format!("yoctoprint_auth_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = AuthConfig::default();
+
+        let auth = Auth::load(&dir, &config).unwrap();
+        assert_eq!(auth.users(), vec![UserInfo { name: "admin".to_string(), role: Role::ADMIN }]);
+        assert!(auth.delete_user("admin").is_err());
+        let password_path = dir.join(ADMIN_PASSWORD_FILE);
+        assert_eq!(std::fs::metadata(&password_path).unwrap().permissions().mode() & 0o777, 0o600);
+        let admin_password = std::fs::read_to_string(&password_path).unwrap();
+        let (_, admin) = auth.login("admin", &admin_password, None).unwrap();
+
+        auth.set_user("bob", Some("correct horse"), Role::OPERATOR).unwrap();
+        assert!(auth.set_user("eve", Some("short"), Role::VIEWER).is_err());
+        assert!(auth.login("bob", "wrong password", None).is_err());
+        let (token, identity) = auth.login("bob", "correct horse", None).unwrap();
+        assert_eq!(identity.role, Role::OPERATOR);
+        assert_eq!(identity.credential, Credential::SESSION);
+
+        // Changes apply to sessions that are already open
+        auth.set_user("bob", None, Role::VIEWER).unwrap();
+        assert_eq!(auth.check_session(&token).map(|i| i.role), Some(Role::VIEWER));
+        auth.logout(&token);
+        assert_eq!(auth.check_session(&token), None);
+
+        let (info, key) = auth.create_api_key("slicer", Role::OPERATOR).unwrap();
+        assert_eq!(auth.check_api_key(&key).map(|i| i.name), Some("slicer".to_string()));
+
+        // A key named after a user can't take over their account
+        let (_, admin_key) = auth.create_api_key("admin", Role::ADMIN).unwrap();
+        let key_identity = auth.check_api_key(&admin_key).unwrap();
+        assert_eq!(auth.change_password(&key_identity, "taken over").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(auth.login("admin", &admin_password, None).is_ok());
+        auth.change_password(&admin, "new admin password").unwrap();
+        assert!(auth.login("admin", "new admin password", None).is_ok());
+
+        // Everything but the sessions survives a restart, without storing the secrets
+        let stored = std::fs::read_to_string(dir.join(USERS_FILE)).unwrap();
+        assert!(!stored.contains(&key) && !stored.contains("correct horse"));
+        let auth = Auth::load(&dir, &config).unwrap();
+        assert_eq!(auth.users().len(), 2);
+        assert!(auth.check_api_key(&key).is_some());
+        assert!(std::fs::read_to_string(&password_path).is_ok_and(|p| p == admin_password));
+        auth.delete_api_key(&info.id).unwrap();
+        assert!(auth.check_api_key(&key).is_none());
+
+        assert!(auth.import_api_key("octoprint", "old key", Role::OPERATOR).unwrap().is_some());
+        assert!(auth.import_api_key("octoprint", "old key", Role::OPERATOR).unwrap().is_none());
+        assert_eq!(auth.check_api_key("old key").map(|i| i.role), Some(Role::OPERATOR));
+        assert_eq!(auth.api_keys().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_logins_back_off() {
+        let dir = std::env::temp_dir().join(format!("yoctoprint_auth_backoff_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let auth = Auth::load(&dir, &AuthConfig::default()).unwrap();
+        auth.set_user("bob", Some("correct horse"), Role::OPERATOR).unwrap();
+        let ip = Some(IpAddr::from([192, 168, 1, 2]));
+
+        for _ in 0..FREE_LOGIN_ATTEMPTS {
+            assert_eq!(auth.login("bob", "wrong password", ip).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        }
+        // Even the right password has to wait now, from anywhere
+        let error = auth.login("bob", "correct horse", None).unwrap_err();
+        assert_eq!(CodedError::from_io(&error).code, ErrorCode::TOOMANYREQUESTS);
+        // And so does the same address trying other users
+        let error = auth.login("carol", "anything", ip).unwrap_err();
+        assert_eq!(CodedError::from_io(&error).code, ErrorCode::TOOMANYREQUESTS);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+use std::path::Path;
+
+use rocket::serde::{Serialize, Deserialize};
+
+pub const BED_MESH_FILE: &str = "bed_mesh.json";
+// The mesh from the probe before last, to see how the bed changed
+pub const PREVIOUS_BED_MESH_FILE: &str = "bed_mesh_previous.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BedMesh {
+    pub secs_since_epoch: u64,
+    // Probe coordinates for each column and row of the grid
+    pub x_positions: Vec<f64>,
+    pub y_positions: Vec<f64>,
+    // Z offsets, indexed [y][x]. None for points that weren't probed.
+    pub z: Vec<Vec<Option<f64>>>
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BedMeshDiff {
+    // New - old, indexed [y][x]
+    pub z: Vec<Vec<Option<f64>>>,
+    pub max_abs_change: f64,
+    pub mean_change: f64
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BedMeshReport {
+    pub mesh: BedMesh,
+    pub previous: Option<BedMesh>,
+    pub diff: Option<BedMeshDiff>
+}
+
+impl BedMeshReport {
+    pub fn new(mesh: BedMesh, previous: Option<BedMesh>) -> BedMeshReport {
+        let diff = previous.as_ref().and_then(|prev| mesh.diff(prev).ok());
+        BedMeshReport { mesh, previous, diff }
+    }
+}
+
+// Collects the mesh rows and probe points as the printer reports them.
+#[derive(Default, Debug)]
+pub struct PendingMesh {
+    rows: Vec<(u32, Vec<Option<f64>>)>,
+    probe_points: Vec<(f64, f64, f64)>,
+    complete: bool
+}
+
+impl PendingMesh {
+    pub fn add_row(&mut self, index: u32, values: Vec<Option<f64>>) {
+        self.rows.retain(|(i, _)| *i != index);
+        self.rows.push((index, values));
+        self.complete = false;
+    }
+
+    pub fn add_probe_point(&mut self, x: f64, y: f64, z: f64) {
+        self.probe_points.push((x, y, z));
+    }
+
+    // The printer said ok, if we've already got the grid, it's done.
+    pub fn got_ok(&mut self) {
+        if !self.rows.is_empty() {
+            self.complete = true;
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    pub fn probe_point_count(&self) -> u32 {
+        self.probe_points.len() as u32
+    }
+
+    fn unique_sorted(mut values: Vec<f64>) -> Vec<f64> {
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        values.dedup_by(|a, b| (*a - *b).abs() < 0.01);
+        values
+    }
+
+    fn evenly_spaced(count: usize, min: f64, max: f64) -> Vec<f64> {
+        if count < 2 {
+            return vec![min; count];
+        }
+        (0..count).map(|i| min + (max - min) * (i as f64) / ((count - 1) as f64)).collect()
+    }
+
+    // Build the mesh. If we saw the points being probed, use their coordinates, otherwise assume they're spread evenly across the given area.
+    pub fn into_mesh(mut self, secs_since_epoch: u64, x_range: (f64, f64), y_range: (f64, f64)) -> Option<BedMesh> {
+        if self.rows.is_empty() {
+            return None;
+        }
+        self.rows.sort_by_key(|(i, _)| *i);
+        let z : Vec<Vec<Option<f64>>> = self.rows.into_iter().map(|(_, row)| row).collect();
+        let n_cols = z.iter().map(|r| r.len()).max().unwrap_or(0);
+
+        let mut x_positions = PendingMesh::unique_sorted(self.probe_points.iter().map(|p| p.0).collect());
+        let mut y_positions = PendingMesh::unique_sorted(self.probe_points.iter().map(|p| p.1).collect());
+        if x_positions.len() != n_cols || y_positions.len() != z.len() {
+            x_positions = PendingMesh::evenly_spaced(n_cols, x_range.0, x_range.1);
+            y_positions = PendingMesh::evenly_spaced(z.len(), y_range.0, y_range.1);
+        }
+
+        Some(BedMesh { secs_since_epoch, x_positions, y_positions, z })
+    }
+}
+
+impl BedMesh {
+    pub fn diff(&self, old: &BedMesh) -> std::io::Result<BedMeshDiff> {
+        if self.z.len() != old.z.len() || self.z.iter().zip(old.z.iter()).any(|(a, b)| a.len() != b.len()) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Meshes have different dimensions, cannot compare them"));
+        }
+
+        let z : Vec<Vec<Option<f64>>> = self.z.iter().zip(old.z.iter())
+        .map(|(new_row, old_row)| {
+            new_row.iter().zip(old_row.iter()).map(|(new, old)| {
+                match (new, old) {
+                    (Some(n), Some(o)) => Some(n - o),
+                    _ => None
+                }
+            }).collect()
+        }).collect();
+
+        let changes : Vec<f64> = z.iter().flatten().filter_map(|v| *v).collect();
+        let max_abs_change = changes.iter().fold(0., |acc: f64, v| acc.max(v.abs()));
+        let mean_change = if changes.is_empty() {0.} else {changes.iter().sum::<f64>() / changes.len() as f64};
+
+        Ok(BedMeshDiff { z, max_abs_change, mean_change })
+    }
+}
+
+fn load_file(path: &Path) -> std::io::Result<BedMesh> {
+    let contents = std::fs::read_to_string(path)?;
+    rocket::serde::json::from_str::<BedMesh>(&contents)
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+pub fn load(data_dir: &Path) -> std::io::Result<BedMesh> {
+    load_file(&data_dir.join(BED_MESH_FILE))
+}
+
+pub fn load_previous(data_dir: &Path) -> std::io::Result<BedMesh> {
+    load_file(&data_dir.join(PREVIOUS_BED_MESH_FILE))
+}
+
+// Only for freshly probed meshes, the one it replaces becomes the previous mesh
+pub fn save(data_dir: &Path, mesh: &BedMesh) -> std::io::Result<()> {
+    let as_string = rocket::serde::json::to_string(mesh)
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let path = data_dir.join(BED_MESH_FILE);
+    if path.exists() {
+        std::fs::rename(&path, data_dir.join(PREVIOUS_BED_MESH_FILE))?;
+    }
+    std::fs::write(path, as_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+
+    use super::*;
+
+    fn mesh(z: Vec<Vec<Option<f64>>>) -> BedMesh {
+        BedMesh { secs_since_epoch: 0, x_positions: vec![0.; z[0].len()], y_positions: vec![0.; z.len()], z }
+    }
+
+    #[test]
+    fn evenly_spaced_without_probe_points() {
+        let mut pending = PendingMesh::default();
+        pending.add_row(1, vec![Some(0.1), Some(0.2), Some(0.3)]);
+        pending.add_row(0, vec![Some(0.), Some(-0.1), None]);
+        assert!(!pending.is_complete());
+        pending.got_ok();
+        assert!(pending.is_complete());
+
+        let mesh = pending.into_mesh(10, (10., 210.), (20., 200.)).unwrap();
+        assert_eq!(mesh.x_positions, vec![10., 110., 210.]);
+        assert_eq!(mesh.y_positions, vec![20., 200.]);
+        assert_eq!(mesh.z[0], vec![Some(0.), Some(-0.1), None]);
+    }
+
+    #[test]
+    fn coordinates_from_probe_points() {
+        let mut pending = PendingMesh::default();
+        for (x, y) in [(15., 15.), (115., 15.), (115., 115.), (15., 115.)] {
+            pending.add_probe_point(x, y, 0.);
+        }
+        pending.add_row(0, vec![Some(0.), Some(0.)]);
+        pending.add_row(1, vec![Some(0.), Some(0.)]);
+
+        let mesh = pending.into_mesh(10, (0., 220.), (0., 220.)).unwrap();
+        assert_eq!(mesh.x_positions, vec![15., 115.]);
+        assert_eq!(mesh.y_positions, vec![15., 115.]);
+    }
+
+    #[test]
+    fn no_rows_no_mesh() {
+        let mut pending = PendingMesh::default();
+        pending.got_ok();
+        assert!(!pending.is_complete());
+        assert!(pending.into_mesh(0, (0., 1.), (0., 1.)).is_none());
+    }
+
+    #[test]
+    fn diff_meshes() {
+        let old = mesh(vec![vec![Some(0.1), Some(0.2)], vec![Some(0.), None]]);
+        let new = mesh(vec![vec![Some(0.15), Some(0.1)], vec![Some(0.), Some(0.3)]]);
+
+        let diff = new.diff(&old).unwrap();
+        assert_approx_eq!(diff.z[0][0].unwrap(), 0.05);
+        assert_approx_eq!(diff.z[0][1].unwrap(), -0.1);
+        assert_eq!(diff.z[1][1], None);
+        assert_approx_eq!(diff.max_abs_change, 0.1);
+        assert_approx_eq!(diff.mean_change, -0.05 / 3.);
+
+        assert!(new.diff(&mesh(vec![vec![Some(0.)]])).is_err());
+    }
+
+    #[test]
+    fn saving_keeps_the_previous_mesh() {
+        let data_dir = std::env::temp_dir().join(format!("yoctoprint-bed-mesh-{}", std::process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+
+        let first = mesh(vec![vec![Some(0.1)]]);
+        let second = mesh(vec![vec![Some(0.2)]]);
+        save(&data_dir, &first).unwrap();
+        assert!(load_previous(&data_dir).is_err());
+        save(&data_dir, &second).unwrap();
+        assert_eq!(load(&data_dir).unwrap(), second);
+        assert_eq!(load_previous(&data_dir).unwrap(), first);
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
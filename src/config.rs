@@ -0,0 +1,208 @@
+use std::path::{Path, PathBuf};
+
+use log::{error, info};
+use rocket::serde::{Serialize, Deserialize};
+
+pub const CONFIG_FILE: &str = "config.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HeaterWatchConfig {
+    // While heating, the temperature must rise by watch_increase every watch_period_secs
+    pub watch_period_secs: u64,
+    pub watch_increase: f64,
+    // Once within hysteresis of the target, the heater is considered to have reached it
+    pub hysteresis: f64,
+    // While printing, dropping more than max_deviation below target for deviation_period_secs is a fault
+    pub max_deviation: f64,
+    pub deviation_period_secs: u64,
+    pub max_temperature: f64
+}
+
+impl HeaterWatchConfig {
+    fn hotend_default() -> Self {
+        HeaterWatchConfig { watch_period_secs: 30, watch_increase: 2., hysteresis: 3., max_deviation: 15., deviation_period_secs: 40, max_temperature: 285. }
+    }
+
+    fn bed_default() -> Self {
+        HeaterWatchConfig { watch_period_secs: 90, watch_increase: 2., hysteresis: 3., max_deviation: 10., deviation_period_secs: 60, max_temperature: 120. }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ThermalConfig {
+    pub enabled: bool,
+    pub hotend: HeaterWatchConfig,
+    pub bed: HeaterWatchConfig,
+    // Fault if a heater is on and we haven't heard a temperature report for this long
+    pub stale_report_secs: u64
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        ThermalConfig { enabled: true, hotend: HeaterWatchConfig::hotend_default(), bed: HeaterWatchConfig::bed_default(), stale_report_secs: 20 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct IdleConfig {
+    // Turn heaters, fans and steppers off after this long without activity while heaters are on. 0 to disable.
+    pub timeout_secs: u64
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        IdleConfig { timeout_secs: 15 * 60 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PrinterProfile {
+    pub bed_size_x: f64,
+    pub bed_size_y: f64,
+    pub max_height: f64,
+    // Distance from the bed edges to the outermost mesh points, used when the printer doesn't tell us where it probed
+    pub mesh_inset: f64,
+    // Where the nozzle can actually go, for printers where that isn't 0 to the bed size, e.g. homing off the edge of the bed
+    pub x_range: Option<AxisRange>,
+    pub y_range: Option<AxisRange>,
+    pub z_range: Option<AxisRange>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AxisRange {
+    pub min: f64,
+    pub max: f64
+}
+
+impl Default for PrinterProfile {
+    fn default() -> Self {
+        PrinterProfile { bed_size_x: 220., bed_size_y: 220., max_height: 250., mesh_inset: 10., x_range: None, y_range: None, z_range: None }
+    }
+}
+
+impl PrinterProfile {
+    pub fn axis_ranges(&self) -> [(char, AxisRange); 3] {
+        [('X', self.x_range.unwrap_or(AxisRange { min: 0., max: self.bed_size_x })),
+         ('Y', self.y_range.unwrap_or(AxisRange { min: 0., max: self.bed_size_y })),
+         ('Z', self.z_range.unwrap_or(AxisRange { min: 0., max: self.max_height }))]
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct UploadConfig {
+    // Biggest gcode file we accept
+    pub max_size_mb: u64,
+    // Refuse uploads that would leave less than this much free on the disk
+    pub min_free_space_mb: u64
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig { max_size_mb: 50, min_free_space_mb: 100 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HotFolderConfig {
+    // Directory to watch for new gcode files, nothing is watched if unset
+    pub path: Option<PathBuf>,
+    // Folder inside the gcode dir they're imported into
+    pub target_folder: String,
+    // Hard link instead of copying, if the watched directory is on the same filesystem
+    pub link: bool,
+    // Only import once the size hasn't changed for this long, so half written files are left alone
+    pub stable_secs: u64,
+    // Also look through the whole directory this often, inotify misses changes made on the other end of network shares
+    pub rescan_secs: u64,
+    // Load each imported file into the printer, if it isn't busy
+    pub auto_select: bool
+}
+
+impl Default for HotFolderConfig {
+    fn default() -> Self {
+        HotFolderConfig { path: None, target_folder: "hot_folder".to_string(), link: false, stable_secs: 5, rescan_secs: 30, auto_select: false }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AuthConfig {
+    // Turning this off lets anyone do anything
+    pub enabled: bool,
+    // How long a web UI login lasts
+    pub session_hours: u64,
+    // Other sites allowed to call the API from a browser, like a UI dev server
+    pub allowed_origins: Vec<String>
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig { enabled: true, session_hours: 7 * 24, allowed_origins: Vec::new() }
+    }
+}
+
+// From before there were API keys. Only read to move the key into the API keys, see Auth::import_api_key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct OctoPrintConfig {
+    pub api_key: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct Config {
+    pub thermal: ThermalConfig,
+    pub idle: IdleConfig,
+    pub printer: PrinterProfile,
+    pub upload: UploadConfig,
+    pub hot_folder: HotFolderConfig,
+    pub auth: AuthConfig,
+    pub octoprint: OctoPrintConfig
+}
+
+// Load the config from the base dir, falling back to defaults for anything missing.
+pub fn load(base_dir: &Path) -> Config {
+    let path = base_dir.join(CONFIG_FILE);
+
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => {
+            match rocket::serde::json::from_str::<Config>(&contents) {
+                Ok(config) => {
+                    info!("Loaded config from {:?}", path);
+                    config
+                }
+                Err(e) => {
+                    error!("Error parsing config file {:?}, using defaults: {}", path, e);
+                    Config::default()
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!("No config file @ {:?}, using defaults", path);
+            Config::default()
+        }
+        Err(e) => {
+            error!("Error reading config file {:?}, using defaults: {}", path, e);
+            Config::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_config_uses_defaults() {
+        let config = rocket::serde::json::from_str::<Config>(r#"{"thermal": {"stale_report_secs": 30}}"#).unwrap();
+
+        assert_eq!(config.thermal.stale_report_secs, 30);
+        assert_eq!(config.thermal.hotend, ThermalConfig::default().hotend);
+        assert!(config.thermal.enabled);
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+use log::{info, warn};
+use rocket::serde::Serialize;
+use rocket::serde::json::{to_value, Value};
+use rocket::serde::json::serde_json::Map;
+
+use crate::internal_api::{Alert, LayerProgress, PrintState, PrinterStatus, Temperature};
+use crate::print_history::JobRecord;
+
+// How many events a client can fall behind before we give up on it
+const MAX_QUEUED_EVENTS: usize = 256;
+
+#[derive(Serialize, Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum PrinterEvent {
+    // The status fields that changed, the first one a client gets has all of them
+    STATUS(Map<String, Value>),
+    STATE{from: PrintState, to: PrintState},
+    TEMPERATURES(Vec<Temperature>),
+    ALERT(Alert),
+    PROGRESS{file: String, lines_done: u32, lines_total: u32, elapsed: Option<Duration>, remaining: Option<Duration>, layer: Option<LayerProgress>},
+    // A print ended
+    JOB(JobRecord)
+}
+
+#[derive(Default)]
+struct Subscribers {
+    senders: Vec<Sender<PrinterEvent>>,
+    last_status: Option<PrinterStatus>
+}
+
+// Sends whatever changed in the printer status to everyone listening
+#[derive(Clone, Default)]
+pub struct EventBus(Arc<Mutex<Subscribers>>);
+
+impl EventBus {
+    pub fn subscribe(&self) -> Receiver<PrinterEvent> {
+        let (send, recv) = crossbeam::channel::bounded(MAX_QUEUED_EVENTS);
+        let mut subscribers = self.0.lock().unwrap();
+        if let Some(status) = &subscribers.last_status {
+            let _ = send.try_send(PrinterEvent::STATUS(status_fields(status)));
+        }
+        subscribers.senders.push(send);
+        info!("Event subscriber added, {} listening", subscribers.senders.len());
+        recv
+    }
+
+    pub fn update(&self, status: PrinterStatus) {
+        let mut subscribers = self.0.lock().unwrap();
+        let events = match &subscribers.last_status {
+            Some(last) => diff(last, &status),
+            None => vec![PrinterEvent::STATUS(status_fields(&status))]
+        };
+        subscribers.last_status = Some(status);
+
+        subscribers.senders.retain(|sender| {
+            for event in &events {
+                match sender.try_send(event.clone()) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        warn!("Event subscriber fell too far behind, dropping it");
+                        return false;
+                    }
+                    Err(TrySendError::Disconnected(_)) => return false
+                }
+            }
+            true
+        });
+    }
+}
+
+fn status_fields(status: &PrinterStatus) -> Map<String, Value> {
+    match to_value(status) {
+        Ok(Value::Object(fields)) => fields,
+        _ => Map::new()
+    }
+}
+
+// Everything that happened between two status updates
+pub fn diff(old: &PrinterStatus, new: &PrinterStatus) -> Vec<PrinterEvent> {
+    let mut events = Vec::new();
+    let old_fields = status_fields(old);
+    let changed: Map<String, Value> = status_fields(new).into_iter()
+        .filter(|(name, value)| old_fields.get(name) != Some(value))
+        .collect();
+    if changed.is_empty() {
+        return events;
+    }
+
+    if old.state != new.state {
+        events.push(PrinterEvent::STATE{from: old.state, to: new.state});
+    }
+    if old.temperatures != new.temperatures {
+        events.push(PrinterEvent::TEMPERATURES(new.temperatures.clone()));
+    }
+    for alert in new.alerts.iter().filter(|a| !old.alerts.iter().any(|o| o.secs_since_epoch == a.secs_since_epoch && o.message == a.message)) {
+        events.push(PrinterEvent::ALERT(alert.clone()));
+    }
+    if let Some((file, lines_done, lines_total)) = &new.gcode_lines_done_total {
+        if old.gcode_lines_done_total != new.gcode_lines_done_total || old.layer != new.layer {
+            events.push(PrinterEvent::PROGRESS{file: file.clone(), lines_done: *lines_done, lines_total: *lines_total,
+                elapsed: new.print_time_elapsed, remaining: new.print_time_remaining, layer: new.layer.clone()});
+        }
+    }
+    if let Some(job) = new.last_job.as_ref().filter(|_| changed.contains_key("last_job")) {
+        events.push(PrinterEvent::JOB(job.clone()));
+    }
+
+    events.push(PrinterEvent::STATUS(changed));
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal_api::ProbePoint;
+
+    #[test]
+    fn sends_what_changed() {
+        let bus = EventBus::default();
+        let mut status = PrinterStatus::default();
+        bus.update(status.clone());
+
+        let events = bus.subscribe();
+        assert!(matches!(events.try_recv(), Ok(PrinterEvent::STATUS(fields)) if fields.contains_key("temperatures")));
+
+        bus.update(status.clone());
+        assert!(events.try_recv().is_err());
+
+        status.state = PrintState::CONNECTED;
+        status.temperatures = vec![Temperature{measured_from: ProbePoint::HOTEND, index: 0, power: 0., current: 25., target: 200.}];
+        status.alerts = vec![Alert{secs_since_epoch: 1, message: "Hot".to_string()}];
+        bus.update(status.clone());
+
+        let received: Vec<PrinterEvent> = events.try_iter().collect();
+        assert!(matches!(received[0], PrinterEvent::STATE{from: PrintState::DEAD, to: PrintState::CONNECTED}));
+        assert!(matches!(&received[1], PrinterEvent::TEMPERATURES(t) if t.len() == 1));
+        assert!(matches!(&received[2], PrinterEvent::ALERT(a) if a.message == "Hot"));
+        match &received[3] {
+            PrinterEvent::STATUS(fields) => {
+                let mut names: Vec<&String> = fields.keys().collect();
+                names.sort();
+                assert_eq!(names, vec!["alerts", "state", "temperatures"]);
+            }
+            other => panic!("Expected a status event, got {:?}", other)
+        }
+        assert_eq!(received.len(), 4);
+    }
+}
@@ -1,16 +1,27 @@
-use std::io::{BufReader, BufRead, Seek, Read};
+use std::io::{BufReader, BufRead, Read, Seek};
 use std::ops::Div;
-use std::str::FromStr;
 use std::vec::Vec;
-use std::path::{Path,PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::fs::{File};
-use log::{debug, info};
+use log::{debug, info, warn};
 use std::cell::RefCell;
 use std::time::Duration;
 
-use crate::internal_api::{self, FileInfo};
+use crate::internal_api::{self, FileInfo, LayerDuration, LayerProgress};
+use crate::gcode_metadata::{GCodeMetadata, LayerChange, MetadataParser};
+use crate::motion_analyser::{MotionAnalyser, MotionLimits};
+use base64::Engine;
+use regex::Regex;
+use sha2::{Digest, Sha256};
 
 pub const GCODE_DIR: &str = "gcode";
+pub const THUMBNAIL_DIR: &str = "thumbnails";
+
+lazy_static! {
+    // Matches "; thumbnail begin 300x300 12345" and "; thumbnail_QOI begin 16x16 1234". Format in group 1, size in 2 and 3
+    static ref THUMBNAIL_BEGIN_REGEX: Regex = Regex::new(r"^;\s*thumbnail(?:_(\w+))?\s+begin\s+(\d+)x(\d+)").unwrap();
+    static ref THUMBNAIL_END_REGEX: Regex = Regex::new(r"^;\s*thumbnail(?:_\w+)?\s+end").unwrap();
+}
 
 // Find all gcode files recursively starting from start_dir
 pub fn find_gcode_files(start_dir: &Path) -> std::io::Result<Vec<internal_api::FileInfo>> {
@@ -21,10 +32,12 @@ pub fn find_gcode_files(start_dir: &Path) -> std::io::Result<Vec<internal_api::F
         for entry in std::fs::read_dir(start_dir)? {
             let entry = entry?;
             let path = entry.path();
-            if path.is_dir() {
+            if entry.file_type()?.is_symlink() && path.is_dir() {
+                debug!("Not following linked directory {:?}", path);
+            } else if path.is_dir() {
                 debug!("Found directory {:?}", path);
                 files.append(&mut find_gcode_files(&path)?);
-            } else if path.extension().is_some() && path.extension().unwrap().eq_ignore_ascii_case("gcode") {
+            } else if is_gcode_file(&path) {
                 let metadata = std::fs::metadata(&path).unwrap();
                 debug!("Found a gcode file {:?}", path);
                 files.push(FileInfo{path: path, size: metadata.len(), last_modified_since_epoch: metadata.modified()
@@ -40,28 +53,213 @@ pub fn find_gcode_files(start_dir: &Path) -> std::io::Result<Vec<internal_api::F
     Ok(files)
 }
 
-// Convert a gcode file into an absolute path if it isn't already.
-pub fn get_abs_gcode_path(base_path: &PathBuf, file: &PathBuf) -> PathBuf {
-    // Accept both with and without base path
-    if !file.is_absolute() {
-        return base_path.join(GCODE_DIR).join(file);
+// Plain .gcode, or compressed as .gcode.gz or .gcode.zst
+pub fn is_gcode_file(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+    [".gcode", ".gcode.gz", ".gcode.zst"].iter().any(|ext| name.ends_with(ext))
+}
+
+// Reads a gcode file, decompressing it on the fly if it's stored compressed
+#[allow(clippy::upper_case_acronyms)]
+pub enum GCodeSource {
+    PLAIN(File),
+    GZIP(flate2::read::GzDecoder<File>),
+    ZSTD(zstd::Decoder<'static, BufReader<File>>)
+}
+
+impl GCodeSource {
+    pub fn open(path: &Path) -> std::io::Result<GCodeSource> {
+        let file = File::open(path)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+        if name.ends_with(".gz") {
+            Ok(GCodeSource::GZIP(flate2::read::GzDecoder::new(file)))
+        } else if name.ends_with(".zst") {
+            Ok(GCodeSource::ZSTD(zstd::Decoder::new(file)?))
+        } else {
+            Ok(GCodeSource::PLAIN(file))
+        }
+    }
+
+    // Back to the top of the file that's open, even if the path has been replaced since.
+    // Decompressors can't seek, so the file underneath is and it gets a new one.
+    fn rewound(&self) -> std::io::Result<GCodeSource> {
+        let mut file = match self {
+            GCodeSource::PLAIN(f) => f.try_clone()?,
+            GCodeSource::GZIP(d) => d.get_ref().try_clone()?,
+            GCodeSource::ZSTD(d) => d.get_ref().get_ref().try_clone()?
+        };
+        file.rewind()?;
+        Ok(match self {
+            GCodeSource::PLAIN(_) => GCodeSource::PLAIN(file),
+            GCodeSource::GZIP(_) => GCodeSource::GZIP(flate2::read::GzDecoder::new(file)),
+            GCodeSource::ZSTD(_) => GCodeSource::ZSTD(zstd::Decoder::new(file)?)
+        })
+    }
+}
+
+impl Read for GCodeSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            GCodeSource::PLAIN(f) => f.read(buf),
+            GCodeSource::GZIP(f) => f.read(buf),
+            GCodeSource::ZSTD(f) => f.read(buf)
+        }
+    }
+}
+
+// Find all folders recursively starting from start_dir, including empty ones
+pub fn find_gcode_folders(start_dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut folders : Vec<PathBuf> = Vec::new();
+
+    for entry in std::fs::read_dir(start_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            folders.push(entry.path());
+            folders.append(&mut find_gcode_folders(&entry.path())?);
+        }
+    }
+
+    Ok(folders)
+}
+
+fn outside_gcode_dir(file: &Path) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{:?} is not inside the gcode directory", file))
+}
+
+// Convert a path relative to the gcode dir into an absolute one.
+// Refuses anything that would end up outside of it: "..", absolute paths and symlinks that lead elsewhere.
+pub fn get_abs_gcode_path(base_path: &Path, file: &Path) -> std::io::Result<PathBuf> {
+    let gcode_dir = base_path.join(GCODE_DIR);
+    let mut abs_path = gcode_dir.clone();
+    for component in file.components() {
+        match component {
+            Component::Normal(part) => abs_path.push(part),
+            Component::CurDir => {}
+            _ => return Err(outside_gcode_dir(file))
+        }
+    }
+
+    // Whatever part of the path already exists has to really be in the gcode dir
+    let existing = abs_path.ancestors().find(|p| p.symlink_metadata().is_ok()).unwrap_or(&gcode_dir);
+    match existing.canonicalize() {
+        Ok(real_path) if real_path.starts_with(gcode_dir.canonicalize()?) => Ok(abs_path),
+        _ => Err(outside_gcode_dir(file))
+    }
+}
+
+// The path of a gcode file or folder relative to the gcode dir, the way the API shows it
+pub fn get_rel_gcode_path(base_path: &Path, abs_path: &Path) -> String {
+    let rel_path = abs_path.strip_prefix(base_path.join(GCODE_DIR)).unwrap_or(abs_path);
+    rel_path.to_string_lossy().to_string()
+}
+
+// Same as get_abs_gcode_path, but the gcode dir itself isn't allowed
+fn get_abs_gcode_path_not_root(base_path: &Path, file: &Path) -> std::io::Result<PathBuf> {
+    let abs_path = get_abs_gcode_path(base_path, file)?;
+    if abs_path == base_path.join(GCODE_DIR) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Cannot change the gcode directory itself"));
+    }
+    Ok(abs_path)
+}
+
+pub fn create_gcode_folder(base_path: &Path, folder: &Path) -> std::io::Result<()> {
+    let abs_path = get_abs_gcode_path_not_root(base_path, folder)?;
+    if abs_path.exists() {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{:?} already exists", folder)));
+    }
+    std::fs::create_dir_all(abs_path)
+}
+
+// Where the file to delete is, if it's there and can be deleted
+pub fn gcode_file_to_delete(base_path: &Path, file: &Path) -> std::io::Result<PathBuf> {
+    let abs_path = get_abs_gcode_path_not_root(base_path, file)?;
+    if !abs_path.is_file() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No such file {:?}", file)));
+    }
+    Ok(abs_path)
+}
+
+// Where the folder to delete is, if it's there and can be deleted
+pub fn gcode_folder_to_delete(base_path: &Path, folder: &Path) -> std::io::Result<PathBuf> {
+    let abs_path = get_abs_gcode_path_not_root(base_path, folder)?;
+    if !abs_path.is_dir() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No such folder {:?}", folder)));
+    }
+    Ok(abs_path)
+}
+
+// Delete a folder along with everything in it
+pub fn delete_gcode_folder(base_path: &Path, folder: &Path) -> std::io::Result<()> {
+    std::fs::remove_dir_all(gcode_folder_to_delete(base_path, folder)?)
+}
+
+// Move or rename a file or folder, creating the folders it goes into if needed
+pub fn move_gcode(base_path: &Path, from: &Path, to: &Path) -> std::io::Result<()> {
+    let (abs_from, abs_to) = gcode_move_paths(base_path, from, to)?;
+    if let Some(parent) = abs_to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(abs_from, abs_to)
+}
+
+// Where a move goes from and to, if it's allowed
+pub fn gcode_move_paths(base_path: &Path, from: &Path, to: &Path) -> std::io::Result<(PathBuf, PathBuf)> {
+    let abs_from = get_abs_gcode_path_not_root(base_path, from)?;
+    let abs_to = get_abs_gcode_path_not_root(base_path, to)?;
+    if abs_from.symlink_metadata().is_err() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No such file or folder {:?}", from)));
+    }
+    if abs_to.symlink_metadata().is_ok() {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{:?} already exists", to)));
+    }
+    if abs_to.starts_with(&abs_from) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Cannot move {:?} into itself", from)));
+    }
+    Ok((abs_from, abs_to))
+}
+
+// Checksum of a file, as hex
+pub fn sha256(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n_read => hasher.update(&buf[..n_read])
+        }
     }
-    return file.clone();
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Name to cache things we found in a gcode file under. It goes by where the file is in the gcode dir,
+// so it stays the same when the data dir moves.
+pub fn cache_key(data_dir: &Path, gcode_path: &Path) -> String {
+    format!("{:x}", Sha256::digest(get_rel_gcode_path(data_dir, gcode_path).as_bytes()))
 }
 
 pub struct GCodeFile {
     pub line_count: u32,
     pub cur_line_in_file: u32,
-    pub file: BufReader<std::fs::File>,
+    pub file: BufReader<GCodeSource>,
     pub path: PathBuf,
+    // What to call the file, its path relative to the gcode dir if it's in there
+    pub name: String,
     pub last_line: String,
     pub command_line_no: u32, // Keeps track of lines of actual GCode commands
     pub resend_last: bool,
-    print_duration: Option<PrintDurationEstimator>
+    pub metadata: GCodeMetadata,
+    // Of the file as it was loaded, for the print history
+    pub sha256: String,
+    print_duration: Option<PrintDurationEstimator>,
+    // How many of metadata.layers we've started printing, and how long each finished one took
+    layers_started: usize,
+    layer_started_at: Duration,
+    layer_durations: Vec<LayerDuration>
 }
 
-const TIME: &str = ";TIME:";
-const TIME_ELAPSED: &str = ";TIME_ELAPSED:";
+// How often to add a time point when we have to estimate the duration ourselves
+const ANALYSER_POINT_INTERVAL: u32 = 1000;
 
 struct PrintDurationEstimator {
     line_no_elapsed: std::vec::Vec<(u32, Duration)>,
@@ -134,63 +332,102 @@ impl PrintDurationEstimator{
 }
 
 impl GCodeFile {
-    fn parse_metadata<T: FromStr>(line: &str, prefix: &str) -> std::result::Result<T, T::Err> {
-        line.trim_start_matches(prefix).parse::<T>()
-    }
-
-    pub fn new(gcode_file: &Path) -> std::io::Result<GCodeFile> {
-        match File::open(gcode_file) {
+    // The motion limits are used to estimate the print duration if the slicer didn't tell us
+    pub fn new(gcode_file: &Path, limits: &MotionLimits) -> std::io::Result<GCodeFile> {
+        match GCodeSource::open(gcode_file) {
             Err(e) => Err(e),
             Ok(f) => {
                 let mut ret_file = GCodeFile{line_count:0 as u32, 
                     cur_line_in_file: 0, file: BufReader::new(f), 
                     path:gcode_file.to_path_buf(), 
+                    name: gcode_file.file_name().unwrap_or_default().to_string_lossy().to_string(),
                     last_line: String::new(), 
                     command_line_no: 0, 
                     resend_last:false,
-                    print_duration: Some(PrintDurationEstimator::new())};
+                    metadata: GCodeMetadata::default(),
+                    sha256: String::new(),
+                    print_duration: Some(PrintDurationEstimator::new()),
+                    layers_started: 0,
+                    layer_started_at: Duration::ZERO,
+                    layer_durations: Vec::new()};
 
                 let reader = BufReader::new(ret_file.file.by_ref());
-                let mut total_time: f64 = -1.;
+                let mut parser = MetadataParser::default();
+                let mut analyser = MotionAnalyser::new(limits.clone());
+                let mut analysed_points : Vec<(f64, u32)> = Vec::new();
+                let mut z_layers : Vec<LayerChange> = Vec::new();
                 
                 for line in reader.lines() {
-                    let line_str = line.unwrap();
+                    let line_str = line?;
                     ret_file.line_count += 1;
 
-                    if line_str.starts_with(TIME) {
-                        total_time = GCodeFile::parse_metadata::<f64>(&line_str, TIME).unwrap_or_default();
-                    } else if line_str.starts_with(TIME_ELAPSED) {
-                        let time_point = GCodeFile::parse_metadata::<f64>(&line_str, TIME_ELAPSED).unwrap_or_default();
-                        ret_file.print_duration.as_mut().unwrap().add_time_point(time_point, ret_file.line_count);
+                    analyser.process_line(&line_str);
+                    if let Some(z) = analyser.take_layer_change() {
+                        z_layers.push(LayerChange { line: ret_file.line_count, z: Some(z) });
                     }
+                    if ret_file.line_count.is_multiple_of(ANALYSER_POINT_INTERVAL) {
+                        analysed_points.push((analyser.elapsed_secs(), ret_file.line_count));
+                    }
+                    parser.process_line(&line_str, ret_file.line_count);
                 }
 
-                if total_time > 0. {
-                    ret_file.print_duration.as_mut().unwrap().add_time_point(total_time, ret_file.line_count);
-                } else if ret_file.print_duration.as_ref().unwrap().count_points() == 0 {
-                    ret_file.print_duration = None;
+                ret_file.metadata = parser.finish(ret_file.line_count);
+                if ret_file.metadata.bounding_box.is_none() {
+                    ret_file.metadata.bounding_box = analyser.extrusion_bounds();
+                }
+                if ret_file.metadata.layers.is_empty() && !z_layers.is_empty() {
+                    // No layer comments, go by the height we're extruding at
+                    ret_file.metadata.layer_count = Some(z_layers.len() as u32);
+                    ret_file.metadata.layers = z_layers;
+                }
+                for (time_point, at_lines) in ret_file.metadata.time_points.iter() {
+                    ret_file.print_duration.as_mut().unwrap().add_time_point(*time_point, *at_lines);
+                }
+
+                if ret_file.print_duration.as_ref().unwrap().count_points() == 0 {
+                    if analyser.elapsed_secs() > 0. {
+                        debug!("No duration metadata in {:?}, estimated {:.0}s from the moves", gcode_file, analyser.elapsed_secs());
+                        analysed_points.retain(|(_, at_lines)| *at_lines < ret_file.line_count);
+                        let estimator = ret_file.print_duration.as_mut().unwrap();
+                        for (time_point, at_lines) in analysed_points {
+                            estimator.add_time_point(time_point, at_lines);
+                        }
+                        estimator.add_time_point(analyser.elapsed_secs(), ret_file.line_count);
+                        ret_file.metadata.estimated_duration_secs = Some(analyser.elapsed_secs());
+                    } else {
+                        ret_file.print_duration = None;
+                    }
                 }
                 
-                ret_file.file.rewind().unwrap();
+                ret_file.rewind()?;
+                ret_file.sha256 = sha256(gcode_file)?;
                 
                 Ok(ret_file)
             }
         }
     }
     
-    pub fn resend_gcode_line(&mut self, gcode_lineno: u32) {
+    fn rewind(&mut self) -> std::io::Result<()> {
+        self.file = BufReader::new(self.file.get_ref().rewound()?);
+        Ok(())
+    }
+
+    pub fn resend_gcode_line(&mut self, gcode_lineno: u32) -> std::io::Result<()> {
         // If we NACK the last line, just mark it to be replayed, since we buffered it
         if self.cur_line_in_file == gcode_lineno {
             self.resend_last = true;
         } else {
-            self.file.rewind().expect("Failed to rewind file!");
+            self.rewind()?;
             self.cur_line_in_file = 0;
             self.command_line_no = 0;
 
-            while self.command_line_no < gcode_lineno - 1 {
-                self.next_line().expect("Failed to fetch next line");
+            while self.command_line_no < gcode_lineno.saturating_sub(1) {
+                if self.next_line()?.1.is_empty() {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Line {} is past the end of the file", gcode_lineno)));
+                }
             }
         }
+        Ok(())
     }
 
     pub fn next_line(&mut self) -> std::io::Result<(u32, &str)> {
@@ -232,7 +469,7 @@ impl GCodeFile {
     }
 
     pub fn name(&self) -> &str {
-        self.path.file_name().unwrap_or_default().to_str().unwrap_or_default()
+        &self.name
     }
 
     pub fn get_remaining_time(&self, cur_time_secs: Duration) -> Option<Duration> {
@@ -244,6 +481,40 @@ impl GCodeFile {
         }
     }
 
+    // Call as lines get printed, to keep track of which layer we're on and how long each one took
+    pub fn update_layer(&mut self, elapsed: Duration) {
+        while self.layers_started < self.metadata.layers.len() && self.metadata.layers[self.layers_started].line <= self.cur_line_in_file {
+            self.finish_layer(elapsed);
+            self.layers_started += 1;
+        }
+    }
+
+    // Record how long the current layer took, e.g: when the print is done
+    pub fn finish_layer(&mut self, elapsed: Duration) {
+        if self.layers_started > self.layer_durations.len() {
+            let layer = self.layers_started as u32;
+            let z = self.metadata.layers[self.layers_started - 1].z;
+            self.layer_durations.push(LayerDuration { layer, z, secs: elapsed.saturating_sub(self.layer_started_at).as_secs_f64() });
+        }
+        self.layer_started_at = elapsed;
+    }
+
+    pub fn get_layer_progress(&self) -> Option<LayerProgress> {
+        if self.metadata.layers.is_empty() {
+            return None;
+        }
+
+        Some(LayerProgress {
+            current: self.layers_started as u32,
+            total: self.metadata.layer_count.unwrap_or(self.metadata.layers.len() as u32),
+            z: self.layers_started.checked_sub(1).and_then(|idx| self.metadata.layers[idx].z)
+        })
+    }
+
+    pub fn get_layer_durations(&self) -> &Vec<LayerDuration> {
+        &self.layer_durations
+    }
+
     pub fn get_duration_lines(&self) -> Option<(u32, Duration)> {
         match &self.print_duration {
             Some(estimator) => {
@@ -255,6 +526,103 @@ impl GCodeFile {
 }
 
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    // Image format as a file extension: png, jpg or qoi
+    pub format: String,
+    pub data: Vec<u8>
+}
+
+// Decode the base64 thumbnails slicers embed in the header comments
+pub fn read_thumbnails<R: BufRead>(reader: R) -> std::io::Result<Vec<Thumbnail>> {
+    let mut thumbnails = Vec::new();
+    let mut current : Option<(Thumbnail, String)> = None;
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if let Some((thumbnail, encoded)) = current.as_mut() {
+            if THUMBNAIL_END_REGEX.is_match(line) {
+                match base64::engine::general_purpose::STANDARD.decode(encoded.as_bytes()) {
+                    Ok(data) => thumbnails.push(Thumbnail { data, ..thumbnail.clone() }),
+                    Err(e) => warn!("Ignoring {}x{} thumbnail, it isn't valid base64: {}", thumbnail.width, thumbnail.height, e)
+                }
+                current = None;
+            } else {
+                encoded.push_str(line.trim_start_matches(';').trim());
+            }
+        } else if let Some(caps) = THUMBNAIL_BEGIN_REGEX.captures(line) {
+            let format = caps.get(1).map_or("png".to_string(), |f| f.as_str().to_lowercase());
+            let thumbnail = Thumbnail { width: caps[2].parse().unwrap_or(0), height: caps[3].parse().unwrap_or(0), format, data: Vec::new() };
+            current = Some((thumbnail, String::new()));
+        } else if !line.is_empty() && !line.starts_with(';') {
+            // Thumbnails only ever come before the first command, no need to read the rest
+            break;
+        }
+    }
+
+    Ok(thumbnails)
+}
+
+fn thumbnail_dir(data_dir: &Path, gcode_path: &Path) -> PathBuf {
+    data_dir.join(THUMBNAIL_DIR).join(cache_key(data_dir, gcode_path))
+}
+
+// Save the thumbnails of a gcode file as WIDTHxHEIGHT.format images, replacing any from before
+pub fn extract_thumbnails(data_dir: &Path, gcode_path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let thumbnails = read_thumbnails(BufReader::new(GCodeSource::open(gcode_path)?))?;
+    let dir = thumbnail_dir(data_dir, gcode_path);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+
+    let mut paths = Vec::new();
+    for thumbnail in thumbnails {
+        let path = dir.join(format!("{}x{}.{}", thumbnail.width, thumbnail.height, thumbnail.format));
+        std::fs::write(&path, &thumbnail.data)?;
+        paths.push(path);
+    }
+    info!("Extracted {} thumbnails from {:?}", paths.len(), gcode_path);
+
+    Ok(paths)
+}
+
+// Get the thumbnail with the given size ("300x300"), or the biggest one if there's no size.
+// Browsers can't show QOI, so PNG and JPG are preferred when there's a choice.
+pub fn get_thumbnail(data_dir: &Path, gcode_path: &Path, size: Option<&str>) -> std::io::Result<PathBuf> {
+    let dir = thumbnail_dir(data_dir, gcode_path);
+    let up_to_date = match (std::fs::metadata(&dir).and_then(|m| m.modified()), std::fs::metadata(gcode_path).and_then(|m| m.modified())) {
+        (Ok(extracted), Ok(modified)) => extracted >= modified,
+        _ => false
+    };
+
+    let paths = if up_to_date {
+        std::fs::read_dir(&dir)?.map(|entry| entry.map(|e| e.path())).collect::<std::io::Result<Vec<PathBuf>>>()?
+    } else {
+        extract_thumbnails(data_dir, gcode_path)?
+    };
+
+    let format_rank = |path: &PathBuf| match path.extension().and_then(|e| e.to_str()) {
+        Some("png") => 0,
+        Some("jpg") => 1,
+        _ => 2
+    };
+    let pixels = |path: &PathBuf| path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.split_once('x'))
+        .and_then(|(w, h)| Some(w.parse::<u32>().ok()? * h.parse::<u32>().ok()?)).unwrap_or(0);
+
+    paths.into_iter()
+    .filter(|p| size.is_none_or(|size| p.file_stem().is_some_and(|s| s == size)))
+    .min_by_key(|p| (format_rank(p), std::cmp::Reverse(pixels(p))))
+    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("No {} thumbnail in {:?}", size.unwrap_or(""), gcode_path)))
+}
+
+// Forget the thumbnails of a file that's gone
+pub fn remove_thumbnails(data_dir: &Path, gcode_path: &Path) {
+    let _ = std::fs::remove_dir_all(thumbnail_dir(data_dir, gcode_path));
+}
+
 #[cfg(test)]
 mod tests {
     use assert_approx_eq::assert_approx_eq;
@@ -324,7 +692,7 @@ mod tests {
     #[test]
     fn estimator_real_file() {
         
-        let mut file = GCodeFile::new(&std::env::current_dir().unwrap().join(Path::new("samples/PSME_cane-clip-2.gcode"))).expect("Cannot open sample file!");
+        let mut file = GCodeFile::new(&std::env::current_dir().unwrap().join(Path::new("samples/PSME_cane-clip-2.gcode")), &MotionLimits::default()).expect("Cannot open sample file!");
         
         while file.get_progress().0 < 223400 {
             let _ = file.next_line().unwrap();
@@ -335,4 +703,126 @@ mod tests {
         assert_approx_eq!(remaining.as_secs_f64(), 60.45, EPSILON);
         println!("{:?}", remaining);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn layers_from_comments() {
+        let mut file = GCodeFile::new(&std::env::current_dir().unwrap().join(Path::new("samples/PSME_gruge-gard.gcode")), &MotionLimits::default()).expect("Cannot open sample file!");
+        assert_eq!(file.get_layer_progress(), Some(LayerProgress{current: 0, total: 229, z: None}));
+
+        let mut secs = 0;
+        while file.cur_line_in_file < 2807 {
+            let _ = file.next_line().unwrap();
+            secs += 1;
+            file.update_layer(Duration::from_millis(secs));
+        }
+
+        assert_eq!(file.get_layer_progress(), Some(LayerProgress{current: 2, total: 229, z: Some(0.4)}));
+        assert_eq!(file.get_layer_durations().len(), 1);
+        assert_eq!(file.get_layer_durations()[0].layer, 1);
+        assert_eq!(file.get_layer_durations()[0].z, Some(0.3));
+    }
+
+    #[test]
+    fn thumbnails_from_header() {
+        let gcode = "; generated by PrusaSlicer 2.6.0\n\
+            \n\
+            ; thumbnail begin 2x2 24\n\
+            ; iVBORw0KGgoAAAAN\n\
+            ; SUhEUgAAAAI=\n\
+            ; thumbnail end\n\
+            ; thumbnail_QOI begin 1x1 8\n\
+            ; cW9pZgAAAAE=\n\
+            ; thumbnail_QOI end\n\
+            G28\n\
+            ; thumbnail begin 4x4 4\n\
+            ; AAAA\n\
+            ; thumbnail end\n";
+
+        let thumbnails = read_thumbnails(std::io::Cursor::new(gcode)).unwrap();
+        assert_eq!(thumbnails.len(), 2);
+        assert_eq!((thumbnails[0].width, thumbnails[0].height, thumbnails[0].format.as_str()), (2, 2, "png"));
+        assert_eq!(&thumbnails[0].data[1..4], b"PNG");
+        assert_eq!((thumbnails[1].width, thumbnails[1].format.as_str()), (1, "qoi"));
+        assert_eq!(&thumbnails[1].data[..4], b"qoif");
+    }
+
+    #[test]
+    fn cache_key_from_gcode_path() {
+        let key = cache_key(Path::new("/home/pi/.yoctoprint"), Path::new("/home/pi/.yoctoprint/gcode/parts/clip.gcode"));
+        // Same for every build and wherever the data dir is
+        assert_eq!(key, "69ab76801293d4d9e4be84b6f600e55bb199657f793a437b27f661ec15887468");
+        assert_eq!(cache_key(Path::new("/srv/yoctoprint"), Path::new("/srv/yoctoprint/gcode/parts/clip.gcode")), key);
+        assert_ne!(cache_key(Path::new("/srv/yoctoprint"), Path::new("/srv/yoctoprint/gcode/clip.gcode")), key);
+    }
+
+    #[test]
+    fn paths_stay_in_gcode_dir() {
+        let base_path = std::env::temp_dir().join(format!("yoctoprint-gcode-paths-{}", std::process::id()));
+        let gcode_dir = base_path.join(GCODE_DIR);
+        std::fs::create_dir_all(gcode_dir.join("parts")).unwrap();
+        std::os::unix::fs::symlink(std::env::temp_dir(), gcode_dir.join("escape")).unwrap();
+
+        assert_eq!(get_abs_gcode_path(&base_path, Path::new("parts/clip.gcode")).unwrap(), gcode_dir.join("parts/clip.gcode"));
+        assert_eq!(get_abs_gcode_path(&base_path, Path::new("./new/clip.gcode")).unwrap(), gcode_dir.join("new/clip.gcode"));
+        for bad in ["../config.json", "parts/../../config.json", "/etc/passwd", "escape/clip.gcode", "escape"] {
+            assert_eq!(get_abs_gcode_path(&base_path, Path::new(bad)).unwrap_err().kind(), std::io::ErrorKind::PermissionDenied, "{}", bad);
+        }
+
+        std::fs::write(gcode_dir.join("parts/clip.gcode"), "G28\n").unwrap();
+        move_gcode(&base_path, Path::new("parts"), Path::new("done/parts")).unwrap();
+        assert!(gcode_dir.join("done/parts/clip.gcode").is_file());
+        assert!(move_gcode(&base_path, Path::new("done"), Path::new("done/parts/done")).is_err());
+        assert!(move_gcode(&base_path, Path::new("done/parts/clip.gcode"), Path::new("../clip.gcode")).is_err());
+        assert!(delete_gcode_folder(&base_path, Path::new("")).is_err());
+        assert!(gcode_file_to_delete(&base_path, Path::new("done")).is_err());
+
+        assert_eq!(get_rel_gcode_path(&base_path, &gcode_dir.join("done/parts/clip.gcode")), "done/parts/clip.gcode");
+        let files = find_gcode_files(&gcode_dir).unwrap();
+        assert_eq!(files.len(), 1);
+
+        delete_gcode_folder(&base_path, Path::new("done")).unwrap();
+        assert!(!gcode_dir.join("done").exists());
+        std::fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[test]
+    fn compressed_files() {
+        let plain_path = std::env::current_dir().unwrap().join(Path::new("samples/PSME_gruge-gard.gcode"));
+        let plain = GCodeFile::new(&plain_path, &MotionLimits::default()).unwrap();
+        let contents = std::fs::read(&plain_path).unwrap();
+        let dir = std::env::temp_dir().join(format!("yoctoprint-compressed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let gz_path = dir.join("gruge-gard.gcode.gz");
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&gz_path).unwrap(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, &contents).unwrap();
+        encoder.finish().unwrap();
+        let zst_path = dir.join("gruge-gard.gcode.zst");
+        std::fs::write(&zst_path, zstd::encode_all(contents.as_slice(), 0).unwrap()).unwrap();
+
+        for path in [&gz_path, &zst_path] {
+            assert!(is_gcode_file(path));
+            let mut file = GCodeFile::new(path, &MotionLimits::default()).unwrap();
+            assert_eq!(file.line_count, plain.line_count);
+            assert_eq!(file.metadata.layer_count, plain.metadata.layer_count);
+            // The file as it is on disk, not what's in it
+            assert_eq!(file.sha256, sha256(path).unwrap());
+            assert_ne!(file.sha256, plain.sha256);
+
+            let mut lines = Vec::new();
+            for _ in 0..40 {
+                lines.push(file.next_line().unwrap().1.to_string());
+            }
+            // Going back reads the open file again from the top, whatever is at the path now
+            let replacement = dir.join("replacement");
+            std::fs::write(&replacement, "G28\n").unwrap();
+            std::fs::rename(&replacement, path).unwrap();
+            file.resend_gcode_line(10).unwrap();
+            assert_eq!(file.next_line().unwrap(), (10, lines[9].as_str()));
+            assert!(file.resend_gcode_line(u32::MAX).is_err());
+        }
+
+        assert_eq!(find_gcode_files(&dir).unwrap().len(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+
+use rocket::serde::{Serialize, Deserialize};
+
+use crate::api_error::{CodedError, ErrorCode, FieldError};
+
+// Parameter letter -> value, e.g: X -> 80.0 for steps per mm
+pub type SettingValues = BTreeMap<char, f64>;
+
+// The groups the firmware keeps separately for each extruder
+pub const EXTRUDER_GROUPS: &[&str] = &["steps_per_mm", "max_feedrate", "max_acceleration", "hotend_pid", "linear_advance"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct FirmwareSettings {
+    pub steps_per_mm: SettingValues,
+    pub max_feedrate: SettingValues,
+    pub max_acceleration: SettingValues,
+    pub acceleration: SettingValues,
+    pub jerk: SettingValues,
+    pub home_offset: SettingValues,
+    pub probe_offset: SettingValues,
+    pub hotend_pid: SettingValues,
+    pub bed_pid: SettingValues,
+    pub linear_advance: SettingValues,
+    // Settings for the extruders after the first, keyed by the index the firmware gives them
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub extruders: BTreeMap<u32, FirmwareSettings>
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SettingChange {
+    pub setting: String,
+    pub param: char,
+    pub old: Option<f64>,
+    pub new: f64
+}
+
+impl FirmwareSettings {
+    pub fn groups(&self) -> Vec<(&'static str, &SettingValues)> {
+        vec![("steps_per_mm", &self.steps_per_mm), ("max_feedrate", &self.max_feedrate), ("max_acceleration", &self.max_acceleration),
+            ("acceleration", &self.acceleration), ("jerk", &self.jerk), ("home_offset", &self.home_offset),
+            ("probe_offset", &self.probe_offset), ("hotend_pid", &self.hotend_pid), ("bed_pid", &self.bed_pid),
+            ("linear_advance", &self.linear_advance)]
+    }
+
+    pub fn group(&self, name: &str) -> Option<&SettingValues> {
+        self.groups().into_iter().find(|(n, _)| *n == name).map(|(_, values)| values)
+    }
+
+    pub fn group_mut(&mut self, name: &str) -> Option<&mut SettingValues> {
+        match name {
+            "steps_per_mm" => Some(&mut self.steps_per_mm),
+            "max_feedrate" => Some(&mut self.max_feedrate),
+            "max_acceleration" => Some(&mut self.max_acceleration),
+            "acceleration" => Some(&mut self.acceleration),
+            "jerk" => Some(&mut self.jerk),
+            "home_offset" => Some(&mut self.home_offset),
+            "probe_offset" => Some(&mut self.probe_offset),
+            "hotend_pid" => Some(&mut self.hotend_pid),
+            "bed_pid" => Some(&mut self.bed_pid),
+            "linear_advance" => Some(&mut self.linear_advance),
+            _ => None
+        }
+    }
+
+    // The first extruder's settings are the top level ones
+    pub fn extruder_mut(&mut self, index: u32) -> &mut FirmwareSettings {
+        if index == 0 {
+            return self;
+        }
+        self.extruders.entry(index).or_default()
+    }
+
+    // Every group including the other extruders', with the name to report it by
+    fn named_groups(&self) -> Vec<(String, &'static str, &SettingValues)> {
+        let mut groups : Vec<(String, &'static str, &SettingValues)> = self.groups().into_iter().map(|(name, values)| (name.to_string(), name, values)).collect();
+        for (index, extruder) in self.extruders.iter() {
+            groups.extend(extruder.groups().into_iter().map(|(name, values)| (format!("extruders.{}.{}", index, name), name, values)));
+        }
+        groups
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.named_groups().iter().all(|(_, _, values)| values.is_empty())
+    }
+
+    // What's wrong with a value for the given group, if anything
+    fn check_value(group: &str, value: f64) -> Option<String> {
+        match group {
+            _ if !value.is_finite() => Some(format!("Invalid value {}", value)),
+            "steps_per_mm" | "max_feedrate" | "max_acceleration" | "acceleration" if value <= 0. => Some("Must be greater than 0".to_string()),
+            "jerk" | "hotend_pid" | "bed_pid" if value < 0. => Some("Must not be negative".to_string()),
+            "linear_advance" if !(0. ..=10.).contains(&value) => Some("Must be between 0 and 10".to_string()),
+            _ => None
+        }
+    }
+
+    pub fn validate(&self) -> std::io::Result<()> {
+        let mut fields : Vec<FieldError> = self.named_groups().into_iter()
+        .flat_map(|(label, name, values)| values.iter().filter_map(move |(param, value)| {
+            FirmwareSettings::check_value(name, *value).map(|message| FieldError { field: format!("{}.{}", label, param), message })
+        })).collect();
+
+        for (index, extruder) in self.extruders.iter() {
+            for (name, _) in extruder.groups().into_iter().filter(|(name, values)| !values.is_empty() && !EXTRUDER_GROUPS.contains(name)) {
+                fields.push(FieldError { field: format!("extruders.{}.{}", index, name), message: "Not a per extruder setting".to_string() });
+            }
+        }
+
+        if fields.is_empty() {
+            return Ok(());
+        }
+        let messages : Vec<String> = fields.iter().map(|f| format!("{}: {}", f.field, f.message)).collect();
+        Err(CodedError { code: ErrorCode::VALIDATIONFAILED, message: messages.join("; "), fields }.into_io())
+    }
+
+    // These settings with the given changes applied on top
+    pub fn merged(&self, changes: &FirmwareSettings) -> FirmwareSettings {
+        let mut merged = self.clone();
+        for (name, values) in changes.groups() {
+            merged.group_mut(name).unwrap().extend(values.iter());
+        }
+        for (index, extruder_changes) in changes.extruders.iter() {
+            let extruder = merged.extruders.get(index).cloned().unwrap_or_default().merged(extruder_changes);
+            merged.extruders.insert(*index, extruder);
+        }
+        merged
+    }
+
+    // Every value that's different in new, compared to these settings
+    pub fn diff(&self, new: &FirmwareSettings) -> Vec<SettingChange> {
+        let mut changes = self.group_changes(new, "");
+        let no_settings = FirmwareSettings::default();
+        for (index, new_extruder) in new.extruders.iter() {
+            changes.append(&mut self.extruders.get(index).unwrap_or(&no_settings).group_changes(new_extruder, &format!("extruders.{}.", index)));
+        }
+        changes
+    }
+
+    fn group_changes(&self, new: &FirmwareSettings, prefix: &str) -> Vec<SettingChange> {
+        const EPSILON: f64 = 0.00001;
+        let mut changes = Vec::new();
+
+        for (name, new_values) in new.groups() {
+            let old_values = self.group(name).unwrap();
+            for (param, new_value) in new_values.iter() {
+                let old = old_values.get(param).copied();
+                if old.is_none_or(|o| (o - new_value).abs() > EPSILON) {
+                    changes.push(SettingChange { setting: format!("{}{}", prefix, name), param: *param, old, new: *new_value });
+                }
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_and_diff() {
+        let mut current = FirmwareSettings::default();
+        current.steps_per_mm.extend([('X', 80.), ('Y', 80.), ('E', 96.)]);
+        current.hotend_pid.extend([('P', 52.44), ('I', 7.19), ('D', 95.57)]);
+
+        let mut changes = FirmwareSettings::default();
+        changes.steps_per_mm.extend([('E', 93.5), ('X', 80.)]);
+        changes.linear_advance.insert('K', 0.05);
+
+        let merged = current.merged(&changes);
+        assert_eq!(merged.steps_per_mm.get(&'E'), Some(&93.5));
+        assert_eq!(merged.steps_per_mm.get(&'Y'), Some(&80.));
+        assert_eq!(merged.hotend_pid, current.hotend_pid);
+
+        let diff = current.diff(&merged);
+        assert_eq!(diff, vec![SettingChange{setting: "steps_per_mm".to_string(), param: 'E', old: Some(96.), new: 93.5},
+            SettingChange{setting: "linear_advance".to_string(), param: 'K', old: None, new: 0.05}]);
+    }
+
+    #[test]
+    fn reject_nan() {
+        let mut settings = FirmwareSettings::default();
+        settings.jerk.insert('J', f64::NAN);
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn reject_out_of_range() {
+        let mut settings = FirmwareSettings::default();
+        settings.home_offset.insert('Z', -1.5);
+        settings.jerk.insert('J', 0.);
+        assert!(settings.validate().is_ok());
+
+        settings.steps_per_mm.insert('E', 0.);
+        settings.max_feedrate.insert('X', -300.);
+        settings.linear_advance.insert('K', 12.);
+        let coded = CodedError::from_io(&settings.validate().unwrap_err());
+        let fields : Vec<&str> = coded.fields.iter().map(|f| f.field.as_str()).collect();
+        assert_eq!(fields, vec!["steps_per_mm.E", "max_feedrate.X", "linear_advance.K"]);
+    }
+
+    #[test]
+    fn extruders_kept_apart() {
+        let mut current = FirmwareSettings::default();
+        current.hotend_pid.extend([('P', 22.2), ('I', 1.08), ('D', 114.)]);
+        current.extruder_mut(1).hotend_pid.extend([('P', 25.), ('I', 2.), ('D', 80.)]);
+
+        let mut changes = FirmwareSettings::default();
+        changes.extruder_mut(1).hotend_pid.insert('P', 26.);
+        let merged = current.merged(&changes);
+        assert_eq!(merged.hotend_pid, current.hotend_pid);
+        assert_eq!(merged.extruders[&1].hotend_pid.get(&'P'), Some(&26.));
+        assert_eq!(current.diff(&merged), vec![SettingChange{setting: "extruders.1.hotend_pid".to_string(), param: 'P', old: Some(25.), new: 26.}]);
+
+        changes.extruder_mut(2).bed_pid.insert('P', 50.);
+        let coded = CodedError::from_io(&changes.validate().unwrap_err());
+        assert_eq!(coded.fields[0].field, "extruders.2.bed_pid");
+    }
+}
@@ -0,0 +1,95 @@
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+use rocket::serde::{Serialize, Deserialize};
+
+use crate::file::{self, GCodeFile};
+use crate::gcode_metadata::GCodeMetadata;
+use crate::motion_analyser::MotionLimits;
+
+pub const GCODE_INFO_DIR: &str = "gcode_info";
+
+// What we found in a gcode file, along with what the file looked like when we scanned it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct CachedInfo {
+    size: u64,
+    modified_secs_since_epoch: u64,
+    info: GCodeMetadata
+}
+
+fn cache_path(data_dir: &Path, gcode_path: &Path) -> PathBuf {
+    data_dir.join(GCODE_INFO_DIR).join(format!("{}.json", file::cache_key(data_dir, gcode_path)))
+}
+
+fn file_stamp(gcode_path: &Path) -> std::io::Result<(u64, u64)> {
+    let metadata = std::fs::metadata(gcode_path)?;
+    let modified = metadata.modified()?.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+    Ok((metadata.len(), modified))
+}
+
+// The info from the last scan, if the file hasn't changed since
+pub fn get_cached(data_dir: &Path, gcode_path: &Path) -> Option<GCodeMetadata> {
+    let (size, modified) = file_stamp(gcode_path).ok()?;
+    let contents = std::fs::read_to_string(cache_path(data_dir, gcode_path)).ok()?;
+    let cached = rocket::serde::json::from_str::<CachedInfo>(&contents).ok()?;
+
+    if cached.size == size && cached.modified_secs_since_epoch == modified {
+        Some(cached.info)
+    } else {
+        None
+    }
+}
+
+// Read the whole file to find out what's in it, and remember it for next time.
+// We don't know the printer's limits here, so any estimate we make ourselves uses the defaults.
+pub fn scan(data_dir: &Path, gcode_path: &Path) -> std::io::Result<GCodeMetadata> {
+    let (size, modified) = file_stamp(gcode_path)?;
+    let info = GCodeFile::new(gcode_path, &MotionLimits::default())?.metadata;
+    info!("Scanned {:?}", gcode_path);
+
+    let cached = CachedInfo { size, modified_secs_since_epoch: modified, info: info.clone() };
+    let as_string = rocket::serde::json::to_string(&cached)
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    std::fs::create_dir_all(data_dir.join(GCODE_INFO_DIR))?;
+    std::fs::write(cache_path(data_dir, gcode_path), as_string)?;
+
+    Ok(info)
+}
+
+pub fn get(data_dir: &Path, gcode_path: &Path) -> std::io::Result<GCodeMetadata> {
+    match get_cached(data_dir, gcode_path) {
+        Some(info) => {
+            debug!("Using cached info for {:?}", gcode_path);
+            Ok(info)
+        }
+        None => scan(data_dir, gcode_path)
+    }
+}
+
+// Forget about a file that's gone
+pub fn remove(data_dir: &Path, gcode_path: &Path) {
+    let _ = std::fs::remove_file(cache_path(data_dir, gcode_path));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_and_cache() {
+        let data_dir = std::env::temp_dir().join(format!("yoctoprint-gcode-info-{}", std::process::id()));
+        let gcode_path = std::env::current_dir().unwrap().join("samples/PSME_gruge-gard.gcode");
+
+        assert_eq!(get_cached(&data_dir, &gcode_path), None);
+        let info = get(&data_dir, &gcode_path).unwrap();
+        assert_eq!(info.slicer.as_deref(), Some("Cura_SteamEngine 4.11.0"));
+        assert_eq!(info.layer_height, Some(0.1));
+        assert_eq!(info.bounding_box.as_ref().map(|b| b.max_z), Some(23.1));
+        assert_eq!(info.bed_temperature, Some(70.));
+        assert!(info.codes_used.contains("G29"));
+
+        // The layers are only needed while printing, they aren't cached
+        assert_eq!(get_cached(&data_dir, &gcode_path), Some(GCodeMetadata{layers: Vec::new(), time_points: Vec::new(), ..info}));
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
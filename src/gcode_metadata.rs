@@ -0,0 +1,346 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use rocket::serde::{Serialize, Deserialize};
+
+// Cura
+const TIME: &str = ";TIME:";
+const TIME_ELAPSED: &str = ";TIME_ELAPSED:";
+const CURA_FILAMENT_USED: &str = ";Filament used:";
+const CURA_LAYER: &str = ";LAYER:";
+const CURA_LAYER_COUNT: &str = ";LAYER_COUNT:";
+const CURA_GENERATED_WITH: &str = ";Generated with ";
+const CURA_LAYER_HEIGHT: &str = ";Layer height:";
+const CURA_BOUNDS: [&str; 6] = [";MINX:", ";MINY:", ";MINZ:", ";MAXX:", ";MAXY:", ";MAXZ:"];
+// PrusaSlicer, SuperSlicer and OrcaSlicer
+const ESTIMATED_TIME: &str = "; estimated printing time (normal mode) =";
+const TOTAL_ESTIMATED_TIME: &str = "total estimated time:";
+const FILAMENT_USED_MM: &str = "; filament used [mm] =";
+const LAYER_CHANGE: &str = ";LAYER_CHANGE";
+const LAYER_Z: &str = ";Z:";
+const GENERATED_BY: &str = "; generated by ";
+const LAYER_HEIGHT: &str = "; layer_height =";
+const FILAMENT_USED_G: &str = "; filament used [g] =";
+// Simplify3D
+const S3D_BUILD_TIME: &str = ";   Build time:";
+const S3D_FILAMENT_LENGTH: &str = ";   Filament length:";
+const S3D_LAYER: &str = "; layer ";
+const S3D_GENERATED_BY: &str = "; G-Code generated by ";
+const S3D_LAYER_HEIGHT: &str = ";   layerHeight,";
+const S3D_PLASTIC_WEIGHT: &str = ";   Plastic weight:";
+// For the weight when the slicer doesn't tell us, assume 1.75mm PLA
+const FILAMENT_DIAMETER_MM: f64 = 1.75;
+const FILAMENT_DENSITY_G_CM3: f64 = 1.24;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BoundingBox {
+    pub min_x: f64,
+    pub min_y: f64,
+    pub min_z: f64,
+    pub max_x: f64,
+    pub max_y: f64,
+    pub max_z: f64
+}
+
+impl BoundingBox {
+    fn from_bounds(bounds: &[Option<f64>; 6]) -> Option<BoundingBox> {
+        Some(BoundingBox { min_x: bounds[0]?, min_y: bounds[1]?, min_z: bounds[2]?, max_x: bounds[3]?, max_y: bounds[4]?, max_z: bounds[5]? })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LayerChange {
+    // Line in the file where the layer starts
+    pub line: u32,
+    pub z: Option<f64>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct GCodeMetadata {
+    pub slicer: Option<String>,
+    pub estimated_duration_secs: Option<f64>,
+    pub filament_used_mm: Option<f64>,
+    pub filament_weight_g: Option<f64>,
+    pub layer_height: Option<f64>,
+    pub layer_count: Option<u32>,
+    pub bounding_box: Option<BoundingBox>,
+    // First temperatures set by the file
+    pub hotend_temperature: Option<f64>,
+    pub bed_temperature: Option<f64>,
+    pub max_hotend_temperature: Option<f64>,
+    pub max_bed_temperature: Option<f64>,
+    // Every G and M code in the file, and every tool it selects
+    pub codes_used: BTreeSet<String>,
+    pub tools_used: BTreeSet<u32>,
+    #[serde(skip)]
+    pub layers: Vec<LayerChange>,
+    // (elapsed seconds, line in file) as given by the slicer, for the print duration estimator
+    #[serde(skip)]
+    pub time_points: Vec<(f64, u32)>
+}
+
+// Picks the metadata out of the comments (and M73s) of the slicers we know about, one line at a time.
+#[derive(Default)]
+pub struct MetadataParser {
+    metadata: GCodeMetadata,
+    // (line in file, minutes remaining) from M73 R<minutes>
+    progress_remaining: Vec<(u32, f64)>,
+    // Last Z we saw a move to
+    z: Option<f64>,
+    // Cura's ;MINX: ... ;MAXZ:
+    bounds: [Option<f64>; 6]
+}
+
+fn parse_value<T: FromStr>(line: &str, prefix: &str) -> Option<T> {
+    line[prefix.len()..].trim().parse::<T>().ok()
+}
+
+// Sum of a comma separated list, one value per extruder
+fn parse_sum(values: &str) -> Option<f64> {
+    values.split(',')
+    .map(|v| v.trim().trim_end_matches(|c: char| c.is_alphabetic()).parse::<f64>().ok())
+    .sum()
+}
+
+// e.g: "1d 2h 3m 4s" or "1 hours 23 minutes"
+pub fn parse_duration_secs(in_str: &str) -> Option<f64> {
+    let mut total = 0.;
+    let mut found = false;
+    let mut chars = in_str.chars().peekable();
+
+    while chars.peek().is_some() {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let number : String = std::iter::from_fn(|| chars.next_if(|c| c.is_ascii_digit() || *c == '.')).collect();
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let unit : String = std::iter::from_fn(|| chars.next_if(|c| c.is_alphabetic())).collect();
+
+        if number.is_empty() {
+            if unit.is_empty() {
+                chars.next();
+            }
+            continue;
+        }
+
+        let multiplier = match unit.chars().next() {
+            Some('d') => 86400.,
+            Some('h') => 3600.,
+            Some('m') => 60.,
+            Some('s') | None => 1.,
+            _ => {return None;}
+        };
+        total += number.parse::<f64>().ok()? * multiplier;
+        found = true;
+    }
+
+    if found {Some(total)} else {None}
+}
+
+impl MetadataParser {
+    pub fn process_line(&mut self, line: &str, line_no: u32) {
+        let line = line.trim_end();
+        let metadata = &mut self.metadata;
+
+        if !line.starts_with(';') {
+            let code = line.split(';').next().unwrap_or_default();
+            let mut words = code.split_whitespace().skip_while(|w| w.starts_with('N'));
+            if let Some(cmd) = words.next().filter(|w| w.starts_with('G') || w.starts_with('M')) {
+                if !metadata.codes_used.contains(cmd) {
+                    metadata.codes_used.insert(cmd.to_string());
+                }
+
+                if matches!(cmd, "M104" | "M109" | "M140" | "M190") {
+                    let params : Vec<&str> = words.collect();
+                    let target = params.iter().find(|w| w.starts_with('S') || w.starts_with('R')).and_then(|w| w[1..].parse::<f64>().ok()).filter(|t| *t > 0.);
+                    let hotend = cmd == "M104" || cmd == "M109";
+                    let (first, max) = if hotend {
+                        (&mut metadata.hotend_temperature, &mut metadata.max_hotend_temperature)
+                    } else {
+                        (&mut metadata.bed_temperature, &mut metadata.max_bed_temperature)
+                    };
+                    *first = first.or(target);
+                    *max = match (*max, target) {
+                        (Some(m), Some(t)) => Some(m.max(t)),
+                        (m, t) => m.or(t)
+                    };
+
+                    if let Some(tool) = params.iter().find(|w| hotend && w.starts_with('T')).and_then(|w| w[1..].parse::<u32>().ok()) {
+                        metadata.tools_used.insert(tool);
+                    }
+                }
+            } else if let Some(tool) = code.trim().strip_prefix('T').and_then(|t| t.parse::<u32>().ok()) {
+                metadata.tools_used.insert(tool);
+            }
+
+            if line.starts_with("M73") {
+                let remaining = line.split_whitespace().find(|w| w.starts_with('R')).and_then(|w| w[1..].parse::<f64>().ok());
+                if let Some(minutes) = remaining {
+                    self.progress_remaining.push((line_no, minutes));
+                }
+            } else if line.starts_with('G') {
+                if let Some(z) = code.split_whitespace().find(|w| w.starts_with('Z')).and_then(|w| w[1..].parse::<f64>().ok()) {
+                    self.z = Some(z);
+                }
+                // Cura doesn't tell us the height of the layer, take it from the first extrusion in it
+                if let Some(layer) = metadata.layers.last_mut().filter(|l| l.z.is_none()) {
+                    if code.split_whitespace().any(|w| w.starts_with('E')) {
+                        layer.z = self.z;
+                    }
+                }
+            }
+            return;
+        }
+
+        if line.starts_with(TIME) {
+            metadata.estimated_duration_secs = parse_value(line, TIME);
+        } else if line.starts_with(TIME_ELAPSED) {
+            if let Some(elapsed) = parse_value(line, TIME_ELAPSED) {
+                metadata.time_points.push((elapsed, line_no));
+            }
+        } else if let Some(used) = line.strip_prefix(CURA_FILAMENT_USED) {
+            metadata.filament_used_mm = parse_sum(used).map(|m| m * 1000.);
+        } else if line.starts_with(CURA_LAYER_COUNT) {
+            metadata.layer_count = parse_value(line, CURA_LAYER_COUNT);
+        } else if line.starts_with(CURA_LAYER) || line.starts_with(LAYER_CHANGE) {
+            metadata.layers.push(LayerChange { line: line_no, z: None });
+        } else if line.starts_with(LAYER_Z) {
+            if let Some(layer) = metadata.layers.last_mut() {
+                layer.z = parse_value(line, LAYER_Z);
+            }
+        } else if line.starts_with(S3D_LAYER) && line.contains("Z = ") {
+            let z = line.rsplit("Z = ").next().and_then(|z| z.trim().parse::<f64>().ok());
+            metadata.layers.push(LayerChange { line: line_no, z });
+        } else if let Some(time) = line.strip_prefix(ESTIMATED_TIME) {
+            metadata.estimated_duration_secs = parse_duration_secs(time);
+        } else if let Some(pos) = line.find(TOTAL_ESTIMATED_TIME) {
+            let total = line[pos + TOTAL_ESTIMATED_TIME.len()..].split(';').next().unwrap_or_default();
+            metadata.estimated_duration_secs = metadata.estimated_duration_secs.or(parse_duration_secs(total));
+        } else if let Some(time) = line.strip_prefix(S3D_BUILD_TIME) {
+            metadata.estimated_duration_secs = parse_duration_secs(time);
+        } else if line.starts_with(CURA_LAYER_HEIGHT) {
+            metadata.layer_height = parse_value(line, CURA_LAYER_HEIGHT);
+        } else if line.starts_with(LAYER_HEIGHT) {
+            metadata.layer_height = parse_value(line, LAYER_HEIGHT);
+        } else if line.starts_with(S3D_LAYER_HEIGHT) {
+            metadata.layer_height = parse_value(line, S3D_LAYER_HEIGHT);
+        } else if let Some(idx) = CURA_BOUNDS.iter().position(|prefix| line.starts_with(prefix)) {
+            self.bounds[idx] = parse_value(line, CURA_BOUNDS[idx]);
+        } else if let Some(used) = line.strip_prefix(FILAMENT_USED_G) {
+            metadata.filament_weight_g = parse_sum(used);
+        } else if let Some(weight) = line.strip_prefix(S3D_PLASTIC_WEIGHT) {
+            let g = weight.split('g').next().unwrap_or_default();
+            metadata.filament_weight_g = g.trim().parse::<f64>().ok();
+        } else if let Some(used) = line.strip_prefix(FILAMENT_USED_MM) {
+            metadata.filament_used_mm = parse_sum(used);
+        } else if let Some(length) = line.strip_prefix(S3D_FILAMENT_LENGTH) {
+            let mm = length.split("mm").next().unwrap_or_default();
+            metadata.filament_used_mm = mm.trim().parse::<f64>().ok();
+        } else if metadata.slicer.is_none() {
+            for prefix in [CURA_GENERATED_WITH, GENERATED_BY, S3D_GENERATED_BY] {
+                if let Some(generated_with) = line.strip_prefix(prefix) {
+                    // Drop the date some slicers add after the version
+                    let slicer = generated_with.split(" on ").next().unwrap_or_default().trim();
+                    metadata.slicer = Some(slicer.to_string());
+                }
+            }
+        }
+    }
+
+    pub fn finish(mut self, line_count: u32) -> GCodeMetadata {
+        let metadata = &mut self.metadata;
+
+        // M73 counts down, so its first value is about as long as the whole print
+        if let Some((_, first_remaining)) = self.progress_remaining.first() {
+            let total = metadata.estimated_duration_secs.unwrap_or(first_remaining * 60.);
+            metadata.estimated_duration_secs = Some(total);
+            if metadata.time_points.is_empty() {
+                metadata.time_points = self.progress_remaining.iter()
+                .map(|(line, remaining)| ((total - remaining * 60.).max(0.), *line))
+                .collect();
+            }
+        }
+
+        if let Some(total) = metadata.estimated_duration_secs.filter(|t| *t > 0.) {
+            metadata.time_points.retain(|(_, line)| *line < line_count);
+            metadata.time_points.push((total, line_count));
+        }
+
+        if metadata.layer_count.is_none() && !metadata.layers.is_empty() {
+            metadata.layer_count = Some(metadata.layers.len() as u32);
+        }
+
+        if metadata.filament_weight_g.is_none() {
+            let area_mm2 = std::f64::consts::PI * (FILAMENT_DIAMETER_MM / 2.).powi(2);
+            metadata.filament_weight_g = metadata.filament_used_mm.map(|mm| mm * area_mm2 / 1000. * FILAMENT_DENSITY_G_CM3);
+        }
+        metadata.bounding_box = BoundingBox::from_bounds(&self.bounds);
+        self.metadata
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+
+    use super::*;
+
+    fn parse(lines: &[&str]) -> GCodeMetadata {
+        let mut parser = MetadataParser::default();
+        for (idx, line) in lines.iter().enumerate() {
+            parser.process_line(line, idx as u32 + 1);
+        }
+        parser.finish(lines.len() as u32)
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration_secs(" 1h 2m 3s"), Some(3723.));
+        assert_eq!(parse_duration_secs("1d 0h 0m 10s"), Some(86410.));
+        assert_eq!(parse_duration_secs(" 1 hours 23 minutes"), Some(4980.));
+        assert_eq!(parse_duration_secs("soon"), None);
+    }
+
+    #[test]
+    fn cura() {
+        let metadata = parse(&[";FLAVOR:Marlin", ";TIME:100", ";Filament used: 1.5m, 0.5m", ";Generated with Cura_SteamEngine 4.11.0",
+            ";LAYER_COUNT:2", ";LAYER:0", "G0 F3600 X1 Y1 Z0.3", "G1 X2 E1", ";TIME_ELAPSED:40", "G0 X2 Y2 Z0.5", ";LAYER:1", "G1 X3 E1", "M107", "T0"]);
+
+        assert_eq!(metadata.slicer.as_deref(), Some("Cura_SteamEngine 4.11.0"));
+        assert_eq!(metadata.estimated_duration_secs, Some(100.));
+        assert_approx_eq!(metadata.filament_used_mm.unwrap(), 2000.);
+        assert_eq!(metadata.layer_count, Some(2));
+        assert_eq!(metadata.layers, vec![LayerChange{line: 6, z: Some(0.3)}, LayerChange{line: 11, z: Some(0.5)}]);
+        assert_eq!(metadata.time_points, vec![(40., 9), (100., 14)]);
+        assert_approx_eq!(metadata.filament_weight_g.unwrap(), 5.96, 0.01);
+        assert_eq!(metadata.codes_used.iter().collect::<Vec<_>>(), vec!["G0", "G1", "M107"]);
+        assert_eq!(metadata.tools_used.iter().collect::<Vec<_>>(), vec![&0]);
+    }
+
+    #[test]
+    fn prusaslicer() {
+        let metadata = parse(&["; generated by PrusaSlicer 2.6.0+linux-x64-GTK3 on 2023-08-02 at 10:11:12 UTC", "M73 P0 R10", ";LAYER_CHANGE", ";Z:0.2",
+            "G1 Z.2 F720", "M73 P50 R5", ";LAYER_CHANGE", ";Z:0.4", "M73 P100 R0", "M109 S215", "; filament used [mm] = 1234.5",
+            "; estimated printing time (normal mode) = 10m 30s", "; filament used [g] = 3.68", "; layer_height = 0.2", "M104 S0"]);
+
+        assert_eq!(metadata.slicer.as_deref(), Some("PrusaSlicer 2.6.0+linux-x64-GTK3"));
+        assert_eq!(metadata.estimated_duration_secs, Some(630.));
+        assert_eq!(metadata.filament_used_mm, Some(1234.5));
+        assert_eq!(metadata.layer_count, Some(2));
+        assert_eq!(metadata.layers[1], LayerChange{line: 7, z: Some(0.4)});
+        assert_eq!(metadata.time_points, vec![(30., 2), (330., 6), (630., 9), (630., 15)]);
+        assert_eq!(metadata.layer_height, Some(0.2));
+        assert_eq!(metadata.filament_weight_g, Some(3.68));
+        assert_eq!(metadata.hotend_temperature, Some(215.));
+        assert_eq!(metadata.max_hotend_temperature, Some(215.));
+    }
+
+    #[test]
+    fn simplify3d() {
+        let metadata = parse(&["; G-Code generated by Simplify3D(R) Version 4.1.2", "; layer 1, Z = 0.200", "G1 X1 Y1",
+            "; layer 2, Z = 0.400", ";   Build time: 1 hours 2 minutes", ";   Filament length: 2811.9 mm (2.81 m)"]);
+
+        assert_eq!(metadata.slicer.as_deref(), Some("Simplify3D(R) Version 4.1.2"));
+        assert_eq!(metadata.estimated_duration_secs, Some(3720.));
+        assert_eq!(metadata.filament_used_mm, Some(2811.9));
+        assert_eq!(metadata.layers, vec![LayerChange{line: 2, z: Some(0.2)}, LayerChange{line: 4, z: Some(0.4)}]);
+    }
+}
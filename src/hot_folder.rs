@@ -0,0 +1,331 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::Sender;
+use inotify::{Inotify, WatchMask};
+use log::{debug, error, info, warn};
+use rocket::serde::{Serialize, Deserialize};
+
+use crate::config::HotFolderConfig;
+use crate::file;
+use crate::gcode_info;
+use crate::interval_timer::IntervalTimer;
+
+// How many imports to remember for the API
+const MAX_IMPORT_EVENTS: usize = 50;
+// What was imported already, so deleting the copy doesn't bring it back
+const IMPORTED_FILE: &str = "hot_folder_imported.json";
+
+// A source file as it was when it got imported
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct ImportedSource {
+    size: u64,
+    modified_secs: u64
+}
+
+impl ImportedSource {
+    fn of(metadata: &std::fs::Metadata) -> std::io::Result<ImportedSource> {
+        let modified_secs = metadata.modified()?.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+        Ok(ImportedSource { size: metadata.len(), modified_secs })
+    }
+}
+
+fn load_imported(data_dir: &Path) -> std::io::Result<HashMap<PathBuf, ImportedSource>> {
+    let contents = match std::fs::read_to_string(data_dir.join(IMPORTED_FILE)) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        other => other?
+    };
+    rocket::serde::json::from_str(&contents)
+    .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn save_imported(data_dir: &Path, imported: &HashMap<PathBuf, ImportedSource>) -> std::io::Result<()> {
+    let as_string = rocket::serde::json::to_string(imported)
+    .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+    std::fs::write(data_dir.join(IMPORTED_FILE), as_string)
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ImportEvent {
+    pub secs_since_epoch: u64,
+    pub source: String,
+    // Where it went, relative to the gcode dir
+    pub name: String,
+    pub linked: bool,
+    pub error: Option<String>
+}
+
+// Latest imports first, shared with the API
+pub type ImportLog = Arc<Mutex<VecDeque<ImportEvent>>>;
+
+// A copy ready to go into the gcode dir. The main loop puts it in place, it knows which file the printer is using.
+pub struct StagedImport {
+    pub staged: PathBuf,
+    // Where it should go, relative to the gcode dir
+    pub name: PathBuf,
+    // Where it ended up
+    pub placed: Sender<std::io::Result<PathBuf>>
+}
+
+// Move a staged copy into the gcode dir. If the file it replaces can't be released, because it's being printed, it goes next to it instead.
+pub fn place(data_dir: &Path, import: &StagedImport, release: impl FnOnce(&Path) -> std::io::Result<()>) -> std::io::Result<PathBuf> {
+    let mut name = import.name.clone();
+    if file::get_abs_gcode_path(data_dir, &name)?.exists() {
+        if let Err(e) = release(&name) {
+            name = free_name(data_dir, &name)?;
+            info!("{:?} is in use ({}), importing as {:?}", import.name, e, name);
+        }
+    }
+
+    std::fs::rename(&import.staged, file::get_abs_gcode_path(data_dir, &name)?)?;
+    Ok(name)
+}
+
+// clip.gcode.gz -> clip-1.gcode.gz, or the first number that isn't taken
+fn free_name(data_dir: &Path, name: &Path) -> std::io::Result<PathBuf> {
+    let file_name = name.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let (stem, ext) = file_name.split_once('.').unwrap_or((&file_name, "gcode"));
+    let mut n = 1;
+    loop {
+        let candidate = name.with_file_name(format!("{}-{}.{}", stem, n, ext));
+        if !file::get_abs_gcode_path(data_dir, &candidate)?.exists() {
+            return Ok(candidate);
+        }
+        n += 1;
+    }
+}
+
+// Imports gcode files that show up in a watched directory into the gcode dir
+pub struct HotFolder {
+    config: HotFolderConfig,
+    watched: PathBuf,
+    data_dir: PathBuf,
+    inotify: Inotify,
+    rescan_timer: IntervalTimer,
+    // Files that changed, with their size and when that last changed
+    pending: HashMap<PathBuf, (u64, Instant)>,
+    log: ImportLog,
+    imported_sources: HashMap<PathBuf, ImportedSource>,
+    staged: Sender<StagedImport>
+}
+
+impl HotFolder {
+    pub fn new(config: &HotFolderConfig, data_dir: &Path, log: ImportLog, staged: Sender<StagedImport>) -> std::io::Result<HotFolder> {
+        let watched = config.path.clone()
+        .ok_or(std::io::Error::new(ErrorKind::NotFound, "No hot folder configured"))?;
+        let inotify = Inotify::init()?;
+        inotify.watches().add(&watched, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::MODIFY | WatchMask::CREATE)?;
+        info!("Watching {:?} for new gcode files", watched);
+        let imported_sources = load_imported(data_dir).unwrap_or_else(|e| {
+            warn!("Couldn't read the hot folder imports, they may get imported again: {}", e);
+            HashMap::new()
+        });
+
+        Ok(HotFolder { config: config.clone(), watched, data_dir: data_dir.to_path_buf(), inotify,
+            rescan_timer: IntervalTimer::new(Duration::from_secs(config.rescan_secs)), pending: HashMap::new(), log, imported_sources, staged })
+    }
+
+    pub fn run(mut self) {
+        loop {
+            self.tick();
+            std::thread::sleep(Duration::from_millis(500));
+        }
+    }
+
+    fn tick(&mut self) {
+        let mut buffer = [0; 4096];
+        let mut changed : Vec<PathBuf> = Vec::new();
+        match self.inotify.read_events(&mut buffer) {
+            Ok(events) => {
+                changed.extend(events.filter_map(|e| e.name.map(|name| self.watched.join(name))));
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => error!("Error reading hot folder events: {}", e)
+        }
+
+        if self.rescan_timer.check() {
+            match std::fs::read_dir(&self.watched) {
+                Ok(entries) => changed.extend(entries.filter_map(|e| e.ok()).map(|e| e.path())),
+                Err(e) => error!("Error reading hot folder {:?}: {}", self.watched, e)
+            }
+        }
+
+        for path in changed {
+            if file::is_gcode_file(&path) && path.is_file() && !self.pending.contains_key(&path) {
+                self.pending.insert(path, (u64::MAX, Instant::now()));
+            }
+        }
+
+        self.import_stable();
+    }
+
+    fn import_stable(&mut self) {
+        let mut stable : Vec<PathBuf> = Vec::new();
+        self.pending.retain(|path, (size, since)| {
+            match std::fs::metadata(path) {
+                Ok(metadata) if metadata.len() != *size => {
+                    *size = metadata.len();
+                    *since = Instant::now();
+                    true
+                }
+                Ok(_) if since.elapsed() >= Duration::from_secs(self.config.stable_secs) => {
+                    stable.push(path.clone());
+                    false
+                }
+                Ok(_) => true,
+                // Gone before it was done
+                Err(_) => false
+            }
+        });
+
+        for path in stable {
+            let secs_since_epoch = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+            let event = match self.import(&path) {
+                Ok(None) => continue,
+                Ok(Some((name, linked))) => {
+                    info!("Imported {:?} from the hot folder as {:?}", path, name);
+                    ImportEvent { secs_since_epoch, source: path.to_string_lossy().to_string(), name: name.to_string_lossy().to_string(), linked, error: None }
+                }
+                Err(e) => {
+                    error!("Error importing {:?} from the hot folder: {}", path, e);
+                    ImportEvent { secs_since_epoch, source: path.to_string_lossy().to_string(), name: String::new(), linked: false, error: Some(e.to_string()) }
+                }
+            };
+
+            let mut log = self.log.lock().unwrap();
+            log.push_front(event);
+            log.truncate(MAX_IMPORT_EVENTS);
+        }
+    }
+
+    // Copy or link the file into the gcode dir, where it ended up. None if it was imported before, unchanged.
+    fn import(&mut self, source: &Path) -> std::io::Result<Option<(PathBuf, bool)>> {
+        let file_name = source.file_name().unwrap_or_default();
+        let name = Path::new(&self.config.target_folder).join(file_name);
+        let dest = file::get_abs_gcode_path(&self.data_dir, &name)?;
+
+        let source_metadata = ImportedSource::of(&std::fs::metadata(source)?)?;
+        if self.imported_sources.get(source) == Some(&source_metadata) {
+            debug!("{:?} was imported already", source);
+            return Ok(None);
+        }
+
+        // Put it in place in one go, so nothing reads it half copied
+        std::fs::create_dir_all(dest.parent().unwrap_or(&dest))?;
+        let tmp = dest.with_file_name(format!(".{}.importing", file_name.to_string_lossy()));
+        let _ = std::fs::remove_file(&tmp);
+        let linked = self.config.link && std::fs::hard_link(source, &tmp).is_ok();
+        if !linked {
+            std::fs::copy(source, &tmp)?;
+        }
+
+        let (placed_send, placed_recv) = crossbeam::channel::bounded(1);
+        let placed = self.staged.send(StagedImport { staged: tmp.clone(), name, placed: placed_send })
+        .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "Main loop is gone"))
+        .and_then(|_| placed_recv.recv().map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "Main loop is gone"))?);
+        let name = match placed {
+            Ok(name) => name,
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
+        let dest = file::get_abs_gcode_path(&self.data_dir, &name)?;
+
+        gcode_info::remove(&self.data_dir, &dest);
+        gcode_info::scan(&self.data_dir, &dest)?;
+        file::extract_thumbnails(&self.data_dir, &dest)?;
+
+        self.imported_sources.insert(source.to_path_buf(), source_metadata);
+        save_imported(&self.data_dir, &self.imported_sources)?;
+
+        Ok(Some((name, linked)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stands in for the main loop, which can't let go of the file it's printing
+    fn main_loop(data_dir: &Path, printing: Arc<Mutex<Option<PathBuf>>>) -> Sender<StagedImport> {
+        let (send, recv) = crossbeam::channel::unbounded::<StagedImport>();
+        let data_dir = data_dir.to_path_buf();
+        std::thread::spawn(move || {
+            for import in recv {
+                let placed = place(&data_dir, &import, |name| match printing.lock().unwrap().as_deref() {
+                    Some(printed) if printed == name => Err(std::io::Error::other("printing")),
+                    _ => Ok(())
+                });
+                let _ = import.placed.send(placed);
+            }
+        });
+        send
+    }
+
+    #[test]
+    fn imports_stable_files() {
+        let dir = std::env::temp_dir().join(format!("yoctoprint-hot-folder-{}", std::process::id()));
+        let watched = dir.join("watched");
+        let data_dir = dir.join("data");
+        let imported = data_dir.join(file::GCODE_DIR).join("hot_folder/clip.gcode");
+        std::fs::create_dir_all(&watched).unwrap();
+        std::fs::create_dir_all(data_dir.join(file::GCODE_DIR)).unwrap();
+
+        let config = HotFolderConfig { path: Some(watched.clone()), stable_secs: 0, auto_select: true, ..HotFolderConfig::default() };
+        let log = ImportLog::default();
+        let printing = Arc::new(Mutex::new(None));
+        let mut hot_folder = HotFolder::new(&config, &data_dir, log.clone(), main_loop(&data_dir, printing.clone())).unwrap();
+
+        std::fs::write(watched.join("clip.gcode"), "G28\nG1 X10 E1\n").unwrap();
+        std::fs::write(watched.join("notes.txt"), "not gcode").unwrap();
+        // First time the size is seen, then it's stable
+        hot_folder.tick();
+        hot_folder.tick();
+
+        assert!(imported.is_file());
+        assert_eq!(log.lock().unwrap().len(), 1);
+        assert_eq!(log.lock().unwrap()[0].name, "hot_folder/clip.gcode");
+        assert_eq!(log.lock().unwrap()[0].error, None);
+
+        // Already there, nothing to do
+        hot_folder.pending.insert(watched.join("clip.gcode"), (0, Instant::now()));
+        hot_folder.tick();
+        hot_folder.tick();
+        assert_eq!(log.lock().unwrap().len(), 1);
+        assert!(log.lock().unwrap()[0].secs_since_epoch > 0);
+
+        // Deleting the copy doesn't bring it back, not even after a restart
+        std::fs::remove_file(&imported).unwrap();
+        let mut hot_folder = HotFolder::new(&config, &data_dir, log.clone(), main_loop(&data_dir, printing.clone())).unwrap();
+        hot_folder.pending.insert(watched.join("clip.gcode"), (0, Instant::now()));
+        hot_folder.tick();
+        hot_folder.tick();
+        assert_eq!(log.lock().unwrap().len(), 1);
+        assert!(!imported.exists());
+
+        // Unless the source changes
+        std::fs::write(watched.join("clip.gcode"), "G28\nG1 X20 Y20 E2\n").unwrap();
+        hot_folder.pending.insert(watched.join("clip.gcode"), (0, Instant::now()));
+        hot_folder.tick();
+        hot_folder.tick();
+        assert_eq!(log.lock().unwrap().len(), 2);
+        assert!(imported.is_file());
+
+        // The file being printed stays as it is, the new version goes next to it
+        *printing.lock().unwrap() = Some(PathBuf::from("hot_folder/clip.gcode"));
+        std::fs::write(watched.join("clip.gcode"), "G28\nG1 X30 Y30 Z1 E3\n").unwrap();
+        hot_folder.pending.insert(watched.join("clip.gcode"), (0, Instant::now()));
+        hot_folder.tick();
+        hot_folder.tick();
+        assert_eq!(log.lock().unwrap().len(), 3);
+        assert_eq!(log.lock().unwrap()[0].name, "hot_folder/clip-1.gcode");
+        assert_eq!(std::fs::read_to_string(&imported).unwrap(), "G28\nG1 X20 Y20 E2\n");
+        assert_eq!(std::fs::read_to_string(imported.with_file_name("clip-1.gcode")).unwrap(), "G28\nG1 X30 Y30 Z1 E3\n");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -5,6 +5,11 @@ use rocket::serde::{Serialize};
 use serde::Deserialize;
 use enumset::{EnumSetType, EnumSet};
 use crossbeam::channel::{Sender, Receiver};
+use crate::temperature_history::TemperatureSample;
+use crate::bed_mesh::BedMesh;
+use crate::firmware_settings::{FirmwareSettings, SettingChange};
+use crate::print_validation::ValidationIssue;
+use crate::print_history::JobRecord;
 
 pub trait Validator {
     fn validate(&self) -> std::io::Result<()>;
@@ -17,7 +22,7 @@ macro_rules! add_validator {
 
             fn validate(&self) -> std::io::Result<()> {
                 if self.$field > $max || self.$field < $min {
-                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid value, must be between {} and {}", $min, $max)));
+                    return Err(crate::api_error::field_error(stringify!($field), format!("Invalid value, must be between {} and {}", $min, $max)));
                 }
                 return Ok(());
             }
@@ -32,7 +37,7 @@ pub struct FileInfo {
     pub size: u64
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Eq, Hash)]
 #[derive(Debug)]
 #[derive(Copy, Clone)]
 #[derive(Serialize, Deserialize)]
@@ -47,7 +52,7 @@ pub enum ProbePoint {
 }
 
 
-#[derive(EnumSetType, Debug)]
+#[derive(EnumSetType, Debug, Serialize)]
 pub enum Axis {
     X,
     Y,
@@ -100,29 +105,208 @@ pub struct FanSpeedTarget {
 add_validator!(FanSpeedTarget, target, 0., 1. );
 
 
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct PidValues {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64
+}
+
+#[derive(Debug)]
+#[derive(Copy, Clone, Deserialize)]
+pub struct PidAutotuneParams {
+    pub heater: ProbePoint,
+    pub index: Option<u32>,
+    pub target: f64,
+    pub cycles: u32
+}
+add_validator!(PidAutotuneParams, target, 0., 400. );
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Serialize)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum PidAutotuneState {
+    RUNNING,
+    FINISHED,
+    FAILED(String)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PidAutotuneStatus {
+    pub heater: ProbePoint,
+    pub index: u32,
+    pub target: f64,
+    pub cycles: u32,
+    pub cycles_done: u32,
+    pub state: PidAutotuneState,
+    pub result: Option<PidValues>
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum BedProbeState {
+    RUNNING,
+    FINISHED,
+    FAILED(String)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BedProbeStatus {
+    pub state: BedProbeState,
+    pub points_probed: u32,
+    pub secs_elapsed: u64
+}
+
+#[derive(Debug)]
+#[derive(Copy, Clone, Deserialize)]
+pub struct EStepsCalibrationParams {
+    pub temperature: f64,
+    pub length: f64,
+    pub feedrate: Option<f64> // mm/min
+}
+add_validator!(EStepsCalibrationParams, temperature, 170., 300. );
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Serialize)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum EStepsCalibrationStep {
+    HEATING,
+    EXTRUDING,
+    MEASURING,
+    DONE
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct EStepsCalibrationStatus {
+    pub step: EStepsCalibrationStep,
+    pub temperature: f64,
+    pub length: f64,
+    pub feedrate: f64,
+    pub current_steps_per_mm: f64,
+    pub extruded: Option<f64>,
+    pub new_steps_per_mm: Option<f64>
+}
+
+impl EStepsCalibrationStatus {
+    pub const DEFAULT_FEEDRATE: f64 = 50.;
+
+    pub fn new(params: &EStepsCalibrationParams, current_steps_per_mm: f64) -> Self {
+        EStepsCalibrationStatus { step: EStepsCalibrationStep::HEATING, temperature: params.temperature, length: params.length,
+            feedrate: params.feedrate.unwrap_or(EStepsCalibrationStatus::DEFAULT_FEEDRATE), current_steps_per_mm,
+            extruded: None, new_steps_per_mm: None }
+    }
+
+    // The filament was marked at mark_distance before extruding, and remaining is what's left from the mark to the extruder.
+    pub fn measured(&mut self, mark_distance: f64, remaining: f64) -> std::io::Result<()> {
+        let extruded = mark_distance - remaining;
+
+        if self.step != EStepsCalibrationStep::MEASURING && self.step != EStepsCalibrationStep::DONE {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Not waiting for a measurement ({:?})", self.step)));
+        }
+        if extruded <= 0. || extruded > self.length * 2. {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Measured extrusion of {}mm doesn't make sense for {}mm requested", extruded, self.length)));
+        }
+
+        self.extruded = Some(extruded);
+        self.new_steps_per_mm = Some(self.current_steps_per_mm * self.length / extruded);
+        self.step = EStepsCalibrationStep::DONE;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+#[derive(Copy, Clone, Deserialize)]
+pub struct EStepsMeasurement {
+    pub mark_distance: Option<f64>, // Defaults to the extruded length + 20mm
+    pub remaining: f64
+}
+
 #[derive(Debug)]
 #[derive(PartialEq)]
 #[derive(Copy, Clone)]
 #[derive(Serialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum PrintState {
     CONNECTED,
+    // Printing, but the printer is waiting for a heater to get to temperature
+    HEATING,
     STARTED,
+    PAUSING,
     PAUSED,
-    DONE,
+    RESUMING,
+    CANCELLING,
+    // Whole file sent, waiting for the last moves to finish
+    FINISHING,
+    COMPLETED,
+    CANCELLED,
+    // The print failed, PrinterStatus.last_job says why
+    ERROR,
     DEAD
 }
 
+impl PrintState {
+    // A print is under way, even if paused
+    pub fn is_printing(&self) -> bool {
+        matches!(self, PrintState::HEATING | PrintState::STARTED | PrintState::PAUSING | PrintState::PAUSED |
+            PrintState::RESUMING | PrintState::CANCELLING | PrintState::FINISHING)
+    }
+
+    // Nothing going on, the printer is free for something else
+    pub fn is_idle(&self) -> bool {
+        matches!(self, PrintState::CONNECTED | PrintState::COMPLETED | PrintState::CANCELLED | PrintState::ERROR)
+    }
+
+    pub fn can_transition_to(&self, new_state: PrintState) -> bool {
+        use PrintState::*;
+        match (*self, new_state) {
+            // Losing the printer can happen any time, coming back from it means connecting again
+            (_, DEAD) => true,
+            (DEAD, _) => false,
+            (from, STARTED | CONNECTED) if from.is_idle() => true,
+            (HEATING, STARTED) | (STARTED, HEATING) | (RESUMING, STARTED) => true,
+            (STARTED, PAUSING) | (PAUSING, PAUSED) | (PAUSED, RESUMING) => true,
+            (HEATING | STARTED, FINISHING) | (FINISHING, COMPLETED) => true,
+            (from, CANCELLING) if from.is_printing() && from != CANCELLING => true,
+            (CANCELLING, CANCELLED) => true,
+            (from, ERROR) if from.is_printing() => true,
+            _ => false
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Alert {
+    pub secs_since_epoch: u64,
+    pub message: String
+}
+
 #[derive(Serialize, Debug, Clone)]
 pub struct PrinterStatus {
     pub printer_connected: bool,
     pub manual_control_enabled: bool,
+    pub homed_axes: Vec<Axis>,
     pub state: PrintState,
     pub temperatures: Vec<Temperature>,
     pub position: Position,
     pub gcode_lines_done_total: Option<(String, u32, u32)>,
     pub print_time_remaining: Option<std::time::Duration>,
     pub print_time_elapsed: Option<std::time::Duration>,
-    pub fan_speed: Vec<f64>
+    pub fan_speed: Vec<f64>,
+    pub alerts: Vec<Alert>,
+    pub idle_timeout_remaining: Option<std::time::Duration>,
+    pub pid_autotune: Option<PidAutotuneStatus>,
+    pub esteps_calibration: Option<EStepsCalibrationStatus>,
+    pub bed_probe: Option<BedProbeStatus>,
+    pub layer: Option<LayerProgress>,
+    // How the last print ended
+    pub last_job: Option<JobRecord>
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -132,27 +316,61 @@ pub struct PrinterInfo {
 
 impl Default for PrinterStatus {
     fn default() -> PrinterStatus {
-        PrinterStatus { printer_connected: false, manual_control_enabled: false,state: PrintState::DEAD, temperatures: Vec::new(), gcode_lines_done_total: None, position: Position::default(), print_time_remaining: None,
-        print_time_elapsed: None, fan_speed: Vec::new() }
+        PrinterStatus { printer_connected: false, manual_control_enabled: false, homed_axes: Vec::new(),state: PrintState::DEAD, temperatures: Vec::new(), gcode_lines_done_total: None, position: Position::default(), print_time_remaining: None,
+        print_time_elapsed: None, fan_speed: Vec::new(), alerts: Vec::new(),
+        idle_timeout_remaining: None, pid_autotune: None, esteps_calibration: None, bed_probe: None, layer: None, last_job: None }
     }
 }
 
 
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LayerProgress {
+    // 1 based, 0 until the first layer starts
+    pub current: u32,
+    pub total: u32,
+    pub z: Option<f64>
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LayerDuration {
+    pub layer: u32,
+    pub z: Option<f64>,
+    pub secs: f64
+}
+
 pub enum PrinterCommand {
     Connect(PathBuf, u32),
     Disconnect,
     SetGcodeFile(PathBuf),
     DeleteGcodeFile(PathBuf),
-    StartPrint,
+    DeleteGcodeFolder(PathBuf),
+    // From, to
+    MoveGcode(PathBuf, PathBuf),
+    StartPrint(bool),
+    ValidatePrint,
     PausePrint,
     StopPrint,
     GetStatus,
     ManualMove(Position),
     Home(EnumSet<Axis>),
+    DisableSteppers(EnumSet<Axis>),
     SetTemperature(TemperatureTarget),
     SetFanSpeed(FanSpeedTarget),
     OpenConsole,
-    GetPrinterInfo
+    GetPrinterInfo,
+    GetTemperatureHistory(Option<f64>),
+    StartPidAutotune(PidAutotuneParams),
+    ApplyPidAutotune(bool),
+    ReadBedMesh,
+    StartBedProbe,
+    ReadFirmwareSettings,
+    // Changes to apply, whether to save to EEPROM, whether to only report the changes without applying them
+    WriteFirmwareSettings(Box<FirmwareSettings>, bool, bool),
+    StartEStepsCalibration(EStepsCalibrationParams),
+    SubmitEStepsMeasurement(EStepsMeasurement),
+    ApplyEStepsCalibration(bool),
+    CancelEStepsCalibration,
+    GetLayerDurations
 }
 
 #[derive(Clone, Debug)]
@@ -166,7 +384,37 @@ pub struct ConsoleMessage {
 #[derive(Debug)]
 pub enum PrinterResponse {
     GenericResult(std::io::Result<()>),
-    Status(std::io::Result<PrinterStatus>),
+    // Boxed, it's much bigger than the rest
+    Status(std::io::Result<Box<PrinterStatus>>),
     ConsoleChannel((Sender<ConsoleMessage>, Receiver<ConsoleMessage>)),
-    Info(std::io::Result<PrinterInfo>)
-}
\ No newline at end of file
+    Info(std::io::Result<PrinterInfo>),
+    TemperatureHistory(std::io::Result<Vec<TemperatureSample>>),
+    BedMesh(std::io::Result<BedMesh>),
+    FirmwareSettings(std::io::Result<FirmwareSettings>),
+    FirmwareSettingsChanges(std::io::Result<Vec<SettingChange>>),
+    LayerDurations(std::io::Result<Vec<LayerDuration>>),
+    ValidationIssues(std::io::Result<Vec<ValidationIssue>>)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn print_state_transitions() {
+        let print = [PrintState::STARTED, PrintState::PAUSING, PrintState::PAUSED, PrintState::RESUMING, PrintState::STARTED,
+            PrintState::HEATING, PrintState::STARTED, PrintState::FINISHING, PrintState::COMPLETED, PrintState::CONNECTED];
+        assert!(print.windows(2).all(|t| t[0].can_transition_to(t[1])), "{:?}", print);
+
+        assert!(PrintState::PAUSED.can_transition_to(PrintState::CANCELLING));
+        assert!(PrintState::CANCELLING.can_transition_to(PrintState::CANCELLED));
+        assert!(PrintState::HEATING.can_transition_to(PrintState::ERROR));
+        assert!(PrintState::ERROR.can_transition_to(PrintState::STARTED));
+
+        assert!(!PrintState::CONNECTED.can_transition_to(PrintState::PAUSED));
+        assert!(!PrintState::PAUSED.can_transition_to(PrintState::STARTED));
+        assert!(!PrintState::STARTED.can_transition_to(PrintState::COMPLETED));
+        assert!(!PrintState::CONNECTED.can_transition_to(PrintState::ERROR));
+        assert!(!PrintState::DEAD.can_transition_to(PrintState::CONNECTED));
+    }
+}
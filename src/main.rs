@@ -1,9 +1,11 @@
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 use log::{debug, info, error, warn};
 use std::io::ErrorKind;
 use std::fs::File;
 use crate::printer::{Printer, SimulatedPrinter, PrinterControl};
 use crate::internal_api::*;
+use crate::config::Config;
+use crate::api_error::{CodedError, ErrorCode};
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate rocket;
 use clap::Parser;
@@ -17,24 +19,97 @@ mod printer;
 mod marlin;
 mod interval_timer;
 mod recv_channel_async_wrapper;
+mod temperature_history;
+mod config;
+mod thermal_watchdog;
+mod bed_mesh;
+mod firmware_settings;
+mod motion_analyser;
+mod gcode_metadata;
+mod gcode_info;
+mod print_validation;
+mod upload;
+mod hot_folder;
+mod print_history;
+mod events;
+mod octoprint;
+mod prusalink;
+mod auth;
+mod api_error;
+
+// A file that's about to be deleted or moved can't stay loaded in the printer
+fn release_gcode_path(printer: &mut Option<Box<dyn PrinterControl>>, path: &PathBuf) -> std::io::Result<()> {
+    if let Some(printer_ref) = printer.as_mut() {
+        if let Ok(internal_api::PrinterStatus{gcode_lines_done_total: Some(gcode), ..}) = printer_ref.get_status() {
+            if std::path::Path::new(&gcode.0).starts_with(path) {
+                printer_ref.clear_gcode_file()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Forget what was cached about a gcode file, or all files in a folder
+fn forget_gcode_path(base_path: &Path, abs_path: &Path) {
+    let files = if abs_path.is_dir() {
+        file::find_gcode_files(abs_path).unwrap_or_default().into_iter().map(|f| f.path).collect()
+    } else {
+        vec![abs_path.to_path_buf()]
+    };
+
+    for path in files {
+        gcode_info::remove(base_path, &path);
+        file::remove_thumbnails(base_path, &path);
+    }
+}
+
+// Managing files doesn't need a printer, just making sure it isn't using them
+fn handle_file_cmd(printer: &mut Option<Box<dyn PrinterControl>>, cmd: &internal_api::PrinterCommand, base_path: &Path) -> Option<std::io::Result<()>> {
+    // Check it can be done first, a bad request shouldn't unload the current file
+    let abs_path = match cmd {
+        PrinterCommand::DeleteGcodeFile(path) => file::gcode_file_to_delete(base_path, path),
+        PrinterCommand::DeleteGcodeFolder(path) => file::gcode_folder_to_delete(base_path, path),
+        PrinterCommand::MoveGcode(from, to) => file::gcode_move_paths(base_path, from, to).map(|(abs_from, _)| abs_from),
+        _ => return None
+    };
+    let abs_path = match abs_path {
+        Ok(abs_path) => abs_path,
+        Err(e) => return Some(Err(e))
+    };
+
+    if let Err(e) = release_gcode_path(printer, &PathBuf::from(file::get_rel_gcode_path(base_path, &abs_path))) {
+        return Some(Err(e));
+    }
+    forget_gcode_path(base_path, &abs_path);
+
+    Some(match cmd {
+        PrinterCommand::DeleteGcodeFolder(path) => file::delete_gcode_folder(base_path, path),
+        PrinterCommand::MoveGcode(from, to) => file::move_gcode(base_path, from, to),
+        _ => std::fs::remove_file(abs_path)
+    })
+}
+
+fn handle_incoming_cmd(printer: &mut Option<Box<dyn PrinterControl>>, cmd: &internal_api::PrinterCommand, base_path: &Path, config: &Config) -> internal_api::PrinterResponse{
+    if let Some(result) = handle_file_cmd(printer, cmd, base_path) {
+        return internal_api::PrinterResponse::GenericResult(result);
+    }
 
-fn handle_incoming_cmd(printer: &mut Option<Box<dyn PrinterControl>>, cmd: &internal_api::PrinterCommand, base_path: &PathBuf) -> internal_api::PrinterResponse{
     if printer.is_none() {
         match cmd {
             PrinterCommand::GetStatus => {
-                return internal_api::PrinterResponse::Status(Ok(internal_api::PrinterStatus::default()))
+                return internal_api::PrinterResponse::Status(Ok(Box::default()))
             }
             PrinterCommand::Connect(path, baud)=> {
                 let path_str = path.to_str().unwrap();
     
                 if path_str == "sim" {
-                    *printer = Some(Box::new(SimulatedPrinter::new()));
+                    *printer = Some(Box::new(SimulatedPrinter::new(config)));
                     return internal_api::PrinterResponse::GenericResult(Ok(()))
                 } else {
                 match serial::PrinterComms::new(path_str, *baud) {
                     Ok(p) => {
                         info!("Will connect printer @: {} baud: {}", path_str, baud);
-                            match Printer::new(p) {
+                            match Printer::new(p, config) {
                                 Ok(p) => {
                                     *printer = Some(Box::new(p));
                                     return internal_api::PrinterResponse::GenericResult(Ok(()))
@@ -50,7 +125,7 @@ fn handle_incoming_cmd(printer: &mut Option<Box<dyn PrinterControl>>, cmd: &inte
                 }
             }
             _ => {
-                return PrinterResponse::GenericResult(Err(std::io::Error::new(ErrorKind::NotFound, "No printer connected")));
+                return PrinterResponse::GenericResult(Err(api_error::coded_error(ErrorCode::NOTCONNECTED, "No printer connected")));
             }
         }
     }
@@ -64,29 +139,28 @@ fn handle_incoming_cmd(printer: &mut Option<Box<dyn PrinterControl>>, cmd: &inte
 
         },
         PrinterCommand::SetGcodeFile(path) => {
-            internal_api::PrinterResponse::GenericResult(printer_ref.set_gcode_file(&file::get_abs_gcode_path(base_path, path)))
+            match file::get_abs_gcode_path(base_path, path) {
+                Ok(abs_path) => {
+                    let name = file::get_rel_gcode_path(base_path, &abs_path);
+                    internal_api::PrinterResponse::GenericResult(printer_ref.set_gcode_file(&abs_path, &name))
+                }
+                Err(e) => internal_api::PrinterResponse::GenericResult(Err(e))
+            }
         },
-        PrinterCommand::DeleteGcodeFile(path) => {
-            match printer_ref.get_status() {
-                Ok(status) => {
-                    match status.gcode_lines_done_total {
-                        Some(gcode) => {
-                            if gcode.0.ends_with(path.as_os_str().to_str().unwrap()) {
-                                if let Err(e) = printer_ref.clear_gcode_file() {
-                                    return internal_api::PrinterResponse::GenericResult(Err(e));
-                                }
-                            }
-                        },
-                        None => {}
-                    }
+        PrinterCommand::DeleteGcodeFile(_) | PrinterCommand::DeleteGcodeFolder(_) | PrinterCommand::MoveGcode(_, _) => {
+            internal_api::PrinterResponse::GenericResult(Err(api_error::coded_error(ErrorCode::INTERNAL, "File commands are handled by handle_file_cmd")))
+        },
+        PrinterCommand::StartPrint(force) => {
+            match printer_ref.start(*force) {
+                // Send back what's wrong with the file, so it can be fixed or started with force
+                Err(e) if CodedError::from_io(&e).code == ErrorCode::VALIDATIONFAILED => {
+                    internal_api::PrinterResponse::ValidationIssues(printer_ref.validate_print())
                 }
-                _ => {}
+                result => internal_api::PrinterResponse::GenericResult(result)
             }
-
-            internal_api::PrinterResponse::GenericResult(std::fs::remove_file(file::get_abs_gcode_path(base_path, path)))
         },
-        PrinterCommand::StartPrint => {
-            internal_api::PrinterResponse::GenericResult(printer_ref.start())
+        PrinterCommand::ValidatePrint => {
+            internal_api::PrinterResponse::ValidationIssues(printer_ref.validate_print())
         },
         PrinterCommand::PausePrint => {
             return internal_api::PrinterResponse::GenericResult(printer_ref.pause());
@@ -95,7 +169,7 @@ fn handle_incoming_cmd(printer: &mut Option<Box<dyn PrinterControl>>, cmd: &inte
             return internal_api::PrinterResponse::GenericResult(printer_ref.stop());
         },
         PrinterCommand::GetStatus => {
-            return internal_api::PrinterResponse::Status(printer_ref.get_status());
+            return internal_api::PrinterResponse::Status(printer_ref.get_status().map(Box::new));
         },
         PrinterCommand::ManualMove(rel_pos) => {
             return internal_api::PrinterResponse::GenericResult(printer_ref.move_relative(rel_pos));
@@ -103,6 +177,9 @@ fn handle_incoming_cmd(printer: &mut Option<Box<dyn PrinterControl>>, cmd: &inte
         PrinterCommand::SetTemperature(new_temp) => {
             return internal_api::PrinterResponse::GenericResult(printer_ref.set_temperature(new_temp));
         },
+        PrinterCommand::DisableSteppers(axes) => {
+            internal_api::PrinterResponse::GenericResult(printer_ref.disable_steppers(axes))
+        },
         PrinterCommand::Home(axes) => {
             return internal_api::PrinterResponse::GenericResult(printer_ref.go_home(axes));
         },
@@ -118,6 +195,42 @@ fn handle_incoming_cmd(printer: &mut Option<Box<dyn PrinterControl>>, cmd: &inte
         PrinterCommand::GetPrinterInfo => {
             return PrinterResponse::Info(printer_ref.get_info());
         }
+        PrinterCommand::GetLayerDurations => {
+            PrinterResponse::LayerDurations(printer_ref.get_layer_durations())
+        }
+        PrinterCommand::GetTemperatureHistory(since) => {
+            PrinterResponse::TemperatureHistory(printer_ref.get_temperature_history(*since))
+        }
+        PrinterCommand::StartPidAutotune(params) => {
+            PrinterResponse::GenericResult(printer_ref.start_pid_autotune(params))
+        }
+        PrinterCommand::ApplyPidAutotune(save) => {
+            PrinterResponse::GenericResult(printer_ref.apply_pid_autotune(*save))
+        }
+        PrinterCommand::ReadBedMesh => {
+            PrinterResponse::BedMesh(printer_ref.read_bed_mesh())
+        }
+        PrinterCommand::StartBedProbe => {
+            PrinterResponse::GenericResult(printer_ref.start_bed_probe())
+        }
+        PrinterCommand::ReadFirmwareSettings => {
+            PrinterResponse::FirmwareSettings(printer_ref.read_firmware_settings())
+        }
+        PrinterCommand::WriteFirmwareSettings(changes, save, dry_run) => {
+            PrinterResponse::FirmwareSettingsChanges(printer_ref.write_firmware_settings(changes, *save, *dry_run))
+        }
+        PrinterCommand::StartEStepsCalibration(params) => {
+            PrinterResponse::GenericResult(printer_ref.start_esteps_calibration(params))
+        }
+        PrinterCommand::SubmitEStepsMeasurement(measurement) => {
+            PrinterResponse::GenericResult(printer_ref.submit_esteps_measurement(measurement))
+        }
+        PrinterCommand::ApplyEStepsCalibration(save) => {
+            PrinterResponse::GenericResult(printer_ref.apply_esteps_calibration(*save))
+        }
+        PrinterCommand::CancelEStepsCalibration => {
+            PrinterResponse::GenericResult(printer_ref.cancel_esteps_calibration())
+        }
     }
 }
 
@@ -204,13 +317,32 @@ fn main() {
     }
 
     init_gcode_dir(&base_dir).unwrap();
+    let config = config::load(&base_dir);
 
     let (they_send, we_recv) = crossbeam::channel::unbounded();
     let (we_send, they_recv) = crossbeam::channel::unbounded::<PrinterResponse>();
 
+    let import_log = hot_folder::ImportLog::default();
+    let (staged_send, staged_recv) = crossbeam::channel::unbounded::<hot_folder::StagedImport>();
+    if config.hot_folder.path.is_some() {
+        match hot_folder::HotFolder::new(&config.hot_folder, &base_dir, import_log.clone(), staged_send) {
+            Ok(watcher) => {
+                std::thread::spawn(move || watcher.run());
+            }
+            Err(e) => {
+                error!("Cannot watch the hot folder: {}", e);
+            }
+        }
+    }
+
+    let event_bus = events::EventBus::default();
+    let mut event_timer = interval_timer::IntervalTimer::new(std::time::Duration::from_millis(250));
+
     let base_dir_api = base_dir.clone();
+    let config_api = config.clone();
+    let event_bus_api = event_bus.clone();
     let _api = std::thread::spawn( ||{
-        rest_api::run_api(they_send, they_recv, base_dir_api, args.web_ui.into());
+        rest_api::run_api(they_send, they_recv, base_dir_api, args.web_ui.into(), config_api, import_log, event_bus_api);
     });
 
     let ctrl_c_pressed = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
@@ -225,18 +357,41 @@ fn main() {
 
     while !ctrl_c_pressed.load(std::sync::atomic::Ordering::Relaxed) {
         if let Ok(new_msg) =  we_recv.try_recv() {
-            let resp = handle_incoming_cmd(&mut printer, &new_msg, &base_dir);
+            let resp = handle_incoming_cmd(&mut printer, &new_msg, &base_dir, &config);
 
             we_send.send(resp).expect("Error sending response to external API");
         }
 
+        if let Ok(import) = staged_recv.try_recv() {
+            let placed = hot_folder::place(&base_dir, &import, |name| release_gcode_path(&mut printer, &name.to_path_buf()));
+            match &placed {
+                Ok(imported) if config.hot_folder.auto_select => {
+                    if let PrinterResponse::GenericResult(Err(e)) = handle_incoming_cmd(&mut printer, &PrinterCommand::SetGcodeFile(imported.clone()), &base_dir, &config) {
+                        warn!("Cannot load {:?} from the hot folder: {}", imported, e);
+                    }
+                }
+                _ => {}
+            }
+            let _ = import.placed.send(placed);
+        }
+
         if let Some(ref mut cur_printer) = printer {
            cur_printer.next_action().expect("Error performing next printer action!");
+           for job in cur_printer.take_finished_jobs() {
+               if let Err(e) = print_history::add(&base_dir, job) {
+                   error!("Error saving print history: {}", e);
+               }
+           }
+           if let Some(mesh) = cur_printer.take_probed_mesh() {
+               if let Err(e) = bed_mesh::save(&base_dir, &mesh) {
+                   error!("Error saving the bed mesh: {}", e);
+               }
+           }
         } else if scan_timer.check() {
             info!("Looking for printer...");
             if let Ok(found) = serial::find_printer() {
                 info!("Found printer with capabilities: {:?}", found.fw_info);
-                match Printer::new(found) {
+                match Printer::new(found, &config) {
                     Ok(p) => {
                         printer = Some(Box::new(p))
                     }
@@ -246,6 +401,17 @@ fn main() {
                 }
             }
         }
+
+        if event_timer.check() {
+            let status = match &printer {
+                Some(cur_printer) => cur_printer.get_status(),
+                None => Ok(PrinterStatus::default())
+            };
+            match status {
+                Ok(status) => event_bus.update(status),
+                Err(e) => error!("Cannot get printer status for events: {}", e)
+            }
+        }
         std::thread::sleep(std::time::Duration::from_millis(1));
     }
 }
@@ -4,6 +4,7 @@ use regex::Regex;
 
 use crate::internal_api;
 use crate::serial::*;
+use crate::firmware_settings::{FirmwareSettings, SettingValues};
 use internal_api::*;
 use std::io::*;
 use enumset::EnumSet;
@@ -17,10 +18,19 @@ lazy_static! {
     static ref RX_POSITION_REGEX: Regex = Regex::new(r"([XYZE]):(-?[0-9]+\.?[0-9]*)").unwrap();
     static ref TX_POSITION_REGEX: Regex = Regex::new(r"([XYZE])(-?[0-9]+\.?[0-9]*)").unwrap();
     static ref LAST_LINE_REGEX: Regex = Regex::new(r"Last Line: ?([0-9]+)").unwrap();
+    // Matches Bed X: 15.000 Y: 15.000 Z: 0.125
+    static ref PROBE_POINT_REGEX: Regex = Regex::new(r"Bed X: ?(-?[0-9]+\.?[0-9]*) Y: ?(-?[0-9]+\.?[0-9]*) Z: ?(-?[0-9]+\.?[0-9]*)").unwrap();
+    // Matches Kp: 20.39 Ki: 2.08 Kd: 49.95
+    static ref PID_VALUES_REGEX: Regex = Regex::new(r"Kp: ?(-?[0-9]+\.?[0-9]*) +Ki: ?(-?[0-9]+\.?[0-9]*) +Kd: ?(-?[0-9]+\.?[0-9]*)").unwrap();
 }
 
 
 
+// The M-code that reports/sets each group of settings in M503's output, and the parameter that picks the extruder for per extruder groups
+const SETTINGS_CODES: &[(&str, &str, Option<char>)] = &[("M92", "steps_per_mm", Some('T')), ("M203", "max_feedrate", Some('T')),
+    ("M201", "max_acceleration", Some('T')), ("M204", "acceleration", None), ("M205", "jerk", None), ("M206", "home_offset", None),
+    ("M851", "probe_offset", None), ("M301", "hotend_pid", Some('E')), ("M304", "bed_pid", None), ("M900", "linear_advance", Some('T'))];
+
 pub struct Marlin {
 }
 
@@ -126,6 +136,104 @@ impl Marlin {
         ret_set
     }
 
+    // M17/M18/M84 with no axes apply to all of them
+    fn parse_stepper_axes(in_str: &str) -> EnumSet<Axis> {
+        let mut ret_set = EnumSet::<Axis>::empty();
+
+        for segment in in_str.split(' ').skip(1) {
+            match segment.chars().nth(0).unwrap_or('-') {
+                'X' | 'x' => ret_set |= Axis::X,
+                'Y' | 'y' => ret_set |= Axis::Y,
+                'Z' | 'z' => ret_set |= Axis::Z,
+                'E' | 'e' => ret_set |= Axis::E,
+                _ => {}
+            }
+        }
+
+        if ret_set.is_empty() {EnumSet::all()} else {ret_set}
+    }
+
+    fn parse_autotune_line(in_str: &str) -> Option<AutotuneUpdate> {
+        if in_str.contains("PID Autotune start") {
+            Some(AutotuneUpdate::Started)
+        } else if in_str.contains("PID Autotune finished") {
+            Some(AutotuneUpdate::Finished)
+        } else if in_str.contains("PID Autotune failed") {
+            Some(AutotuneUpdate::Failed(in_str.trim_start_matches("echo:").trim().to_string()))
+        } else if in_str.starts_with("bias:") {
+            Some(AutotuneUpdate::CycleDone)
+        } else if let Some(cap) = PID_VALUES_REGEX.captures(in_str) {
+            let values : Vec<f64> = (1..=3).map(|i| cap.get(i).unwrap().as_str().parse::<f64>().unwrap_or_default()).collect();
+            Some(AutotuneUpdate::Values(PidValues{kp: values[0], ki: values[1], kd: values[2]}))
+        } else {
+            None
+        }
+    }
+
+    // Parses a line of M503 output, e.g: "echo:  M92 X80.00 Y80.00 Z2020.00 E96.00" or "echo:  M301 E1 P22.20 I1.08 D114.00"
+    fn parse_setting_line(in_str: &str) -> Option<(&'static str, u32, SettingValues)> {
+        let mut tokens = in_str.trim_start_matches("echo:").split_whitespace();
+        let code = tokens.next()?;
+        let (_, group, extruder_param) = SETTINGS_CODES.iter().find(|(c, _, _)| *c == code)?;
+
+        let mut extruder = 0;
+        let mut values = SettingValues::new();
+        for token in tokens {
+            let param = token.chars().next()?.to_ascii_uppercase();
+            if !param.is_ascii_alphabetic() {
+                return None;
+            }
+            if Some(param) == *extruder_param {
+                extruder = token[1..].parse::<u32>().ok()?;
+            } else {
+                values.insert(param, token[1..].parse::<f64>().ok()?);
+            }
+        }
+        Some((group, extruder, values))
+    }
+
+    // Commands for the groups that changed. With an extruder, only its groups and with its index.
+    fn settings_cmds(old: &FirmwareSettings, new: &FirmwareSettings, extruder: Option<u32>) -> Vec<String> {
+        SETTINGS_CODES.iter()
+        .filter(|(_, _, extruder_param)| extruder.is_none_or(|i| i == 0 || extruder_param.is_some()))
+        .filter(|(_, group, _)| new.group(group) != old.group(group) && !new.group(group).unwrap().is_empty())
+        .map(|(code, group, extruder_param)| {
+            let cmd = match (extruder, extruder_param) {
+                (Some(index), Some(param)) => format!("{} {}{}", code, param, index),
+                _ => code.to_string()
+            };
+            new.group(group).unwrap().iter().fold(cmd, |cmd, (param, value)| format!("{} {}{}", cmd, param, value))
+        }).collect()
+    }
+
+    // Parses a row of the grid printed by M420 V, e.g: " 1 +0.030 -0.012 . +0.101"
+    // The row of column indices above the grid isn't a row, since it doesn't have any decimals.
+    fn parse_mesh_line(in_str: &str) -> Option<MeshLine> {
+        if let Some(cap) = PROBE_POINT_REGEX.captures(in_str) {
+            let values : Vec<f64> = (1..=3).map(|i| cap.get(i).unwrap().as_str().parse::<f64>().unwrap_or_default()).collect();
+            return Some(MeshLine::ProbePoint(values[0], values[1], values[2]));
+        }
+
+        let mut tokens = in_str.trim_start_matches("echo:").split_whitespace().filter(|t| *t != "|");
+        let row_idx = tokens.next()?.parse::<u32>().ok()?;
+
+        let mut values : Vec<Option<f64>> = Vec::new();
+        for token in tokens {
+            if token == "." || token.eq_ignore_ascii_case("nan") {
+                values.push(None);
+            } else if token.contains('.') {
+                values.push(Some(token.parse::<f64>().ok()?));
+            } else {
+                return None;
+            }
+        }
+
+        if values.is_empty() {
+            return None;
+        }
+        Some(MeshLine::Row(row_idx, values))
+    }
+
     fn parse_fan_speed(&self, in_str: &str) -> (u32, f64) {
         let mut ret_idx = 0u32;
         let mut ret_speed = 0.;
@@ -204,6 +312,17 @@ impl SerialProtocol for Marlin {
             return Ok(Response::NACK(capture.get(1).unwrap().as_str().parse::<u32>().unwrap() + 1));
         } else if trimmed_line.starts_with("Resend: ") { // Ignore Resend, we'll use the line number in the previous line
             return Ok(Response::NONE);
+        } else if let Some(cmd) = trimmed_line.strip_prefix("echo:Unknown command:") {
+            let cmd = cmd.trim().trim_matches('"').split_whitespace().next().unwrap_or_default();
+            return Ok(Response::UNKNOWN(cmd.to_string()));
+        } else if let Some(message) = trimmed_line.strip_prefix("Error:") {
+            return Ok(Response::ERROR(message.trim().to_string()));
+        } else if let Some(update) = Self::parse_autotune_line(trimmed_line) {
+            return Ok(Response::AUTOTUNE(update));
+        } else if let Some((group, extruder, values)) = Self::parse_setting_line(trimmed_line) {
+            return Ok(Response::SETTING(group, extruder, values));
+        } else if let Some(mesh_line) = Self::parse_mesh_line(trimmed_line) {
+            return Ok(Response::MESH(mesh_line));
         }
 
         return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unknown rx line: {}", line)));
@@ -222,6 +341,18 @@ impl SerialProtocol for Marlin {
             Some(OutgoingCmd::FanSpeedChange(self.parse_fan_speed(out_cmd)))
         } else if out_cmd.starts_with("G28") {
             Some(OutgoingCmd::HomeAxes(self.parse_home_cmd(out_cmd)))
+        } else if matches!(out_cmd.split(' ').next(), Some("M18") | Some("M84")) {
+            // With S, M18/M84 only set the inactivity timeout
+            if let Some(secs) = out_cmd.split(' ').find(|s| s.starts_with('S') || s.starts_with('s')) {
+                let secs = secs[1..].parse::<f64>().ok()?;
+                Some(OutgoingCmd::SetStepperTimeout(if secs > 0. {Some(std::time::Duration::from_secs_f64(secs))} else {None}))
+            } else {
+                Some(OutgoingCmd::DisableSteppers(Marlin::parse_stepper_axes(out_cmd)))
+            }
+        } else if matches!(out_cmd.split(' ').next(), Some("M108") | Some("M112") | Some("M410")) {
+            Some(OutgoingCmd::Interrupt)
+        } else if out_cmd.split(' ').next() == Some("M17") {
+            Some(OutgoingCmd::EnableSteppers(Marlin::parse_stepper_axes(out_cmd)))
         } else if   out_cmd.starts_with("G0") || 
                     out_cmd.starts_with("G1") ||
                     out_cmd.starts_with("G2") ||
@@ -343,6 +474,71 @@ impl SerialProtocol for Marlin {
     fn get_recover_extruder_cmd(&self) -> String {
         return "G11".to_string();
     }
+
+    fn get_disable_steppers_cmd(&self, axes: &EnumSet<Axis>) -> String {
+        if axes.is_empty() || *axes == EnumSet::all() {
+            return "M84".to_string();
+        }
+
+        let mut cmd = "M84".to_owned();
+        for axis in axes.iter() {
+            cmd.push_str(&format!(" {:?}", axis));
+        }
+        cmd
+    }
+
+    fn get_pid_autotune_cmd(&self, params: &PidAutotuneParams) -> std::io::Result<String> {
+        let extruder = match params.heater {
+            ProbePoint::HOTEND => {params.index.unwrap_or(0) as i32}
+            ProbePoint::BED => {-1}
+            _ => {
+                return Err(std::io::Error::new(ErrorKind::InvalidInput, format!("Cannot autotune {:?}", params.heater)));
+            }
+        };
+        Ok(format!("M303 E{} S{} C{}", extruder, params.target.round() as u32, params.cycles))
+    }
+
+    fn get_set_pid_cmds(&self, heater: ProbePoint, index: u32, values: &PidValues) -> std::io::Result<Vec<String>> {
+        match heater {
+            ProbePoint::HOTEND => Ok(vec![format!("M301 E{} P{:.2} I{:.2} D{:.2}", index, values.kp, values.ki, values.kd)]),
+            ProbePoint::BED => Ok(vec![format!("M304 P{:.2} I{:.2} D{:.2}", values.kp, values.ki, values.kd)]),
+            _ => Err(std::io::Error::new(ErrorKind::InvalidInput, format!("Cannot set PID values for {:?}", heater)))
+        }
+    }
+
+    fn get_save_settings_cmd(&self) -> String {
+        "M500".to_string()
+    }
+
+    fn get_report_bed_mesh_cmd(&self) -> String {
+        "M420 V".to_string()
+    }
+
+    fn get_probe_bed_cmds(&self) -> Vec<String> {
+        vec!["G29".to_string()]
+    }
+
+    fn get_report_settings_cmd(&self) -> String {
+        "M503".to_string()
+    }
+
+    fn get_extrude_cmd(&self, length: f64, feedrate: f64) -> String {
+        format!("G1 E{:.3} F{}", length, feedrate.round() as u32)
+    }
+
+    fn get_wait_for_moves_cmd(&self) -> String {
+        "M400".to_string()
+    }
+
+    fn get_write_settings_cmds(&self, old: &FirmwareSettings, new: &FirmwareSettings) -> Vec<String> {
+        // Without an index, per extruder settings go to the active one, so only leave it off if there's just the one
+        let mut cmds = Self::settings_cmds(old, new, if new.extruders.is_empty() {None} else {Some(0)});
+        let no_settings = FirmwareSettings::default();
+        for (index, new_extruder) in new.extruders.iter() {
+            cmds.append(&mut Self::settings_cmds(old.extruders.get(index).unwrap_or(&no_settings), new_extruder, Some(*index)));
+        }
+        cmds
+    }
 }
 
 
@@ -387,6 +583,79 @@ mod tests {
         assert_eq!(Marlin{}.parse_rx_line(test_lines[0]).unwrap(), Response::NACK(2));
         assert_eq!(Marlin{}.parse_rx_line(test_lines[1]).unwrap(), Response::NONE);
         assert_eq!(Marlin{}.parse_rx_line(test_lines[2]).unwrap(), Response::OK);
+        assert_eq!(Marlin{}.parse_rx_line("Error:Thermal Runaway, system stopped! Heater_ID: 0").unwrap(),
+            Response::ERROR("Thermal Runaway, system stopped! Heater_ID: 0".to_string()));
+    }
+
+    #[test]
+    fn parse_autotune_lines() {
+        assert_eq!(Marlin{}.parse_rx_line("PID Autotune start").unwrap(), Response::AUTOTUNE(AutotuneUpdate::Started));
+        assert_eq!(Marlin{}.parse_rx_line(" bias: 92 d: 92 min: 196.56 max: 203.75").unwrap(), Response::AUTOTUNE(AutotuneUpdate::CycleDone));
+        assert_eq!(Marlin{}.parse_rx_line(" Kp: 20.39 Ki: 2.08 Kd: 49.95").unwrap(),
+            Response::AUTOTUNE(AutotuneUpdate::Values(PidValues{kp: 20.39, ki: 2.08, kd: 49.95})));
+        assert_eq!(Marlin{}.parse_rx_line("PID Autotune finished! Put the last Kp, Ki and Kd constants from below into Configuration.h").unwrap(),
+            Response::AUTOTUNE(AutotuneUpdate::Finished));
+        assert_eq!(Marlin{}.parse_rx_line("PID Autotune failed! Temperature too high").unwrap(),
+            Response::AUTOTUNE(AutotuneUpdate::Failed("PID Autotune failed! Temperature too high".to_string())));
+    }
+
+    #[test]
+    fn parse_mesh_lines() {
+        let lines = ["Bilinear Leveling Grid:", "      0      1      2", " 0 +0.030 +0.012 -0.010", " 1 -0.105 . +0.000",
+            "Bed X: 15.000 Y: 115.500 Z: -0.125"];
+
+        assert!(Marlin{}.parse_rx_line(lines[0]).is_err());
+        assert!(Marlin{}.parse_rx_line(lines[1]).is_err());
+        assert_eq!(Marlin{}.parse_rx_line(lines[2]).unwrap(), Response::MESH(MeshLine::Row(0, vec![Some(0.03), Some(0.012), Some(-0.01)])));
+        assert_eq!(Marlin{}.parse_rx_line(lines[3]).unwrap(), Response::MESH(MeshLine::Row(1, vec![Some(-0.105), None, Some(0.)])));
+        assert_eq!(Marlin{}.parse_rx_line(lines[4]).unwrap(), Response::MESH(MeshLine::ProbePoint(15., 115.5, -0.125)));
+    }
+
+    #[test]
+    fn parse_unknown_command() {
+        assert_eq!(Marlin{}.parse_rx_line("echo:Unknown command: \"M999 S1\"").unwrap(), Response::UNKNOWN("M999".to_string()));
+    }
+
+    #[test]
+    fn parse_settings_lines() {
+        assert_eq!(Marlin{}.parse_rx_line("echo: M92 X80.00 Y80.00 Z2020.00 E96.00").unwrap(),
+            Response::SETTING("steps_per_mm", 0, SettingValues::from([('X', 80.), ('Y', 80.), ('Z', 2020.), ('E', 96.)])));
+        assert_eq!(Marlin{}.parse_rx_line("echo:  M205 B20000.00 S0.00 T0.00 J0.01").unwrap(),
+            Response::SETTING("jerk", 0, SettingValues::from([('B', 20000.), ('S', 0.), ('T', 0.), ('J', 0.01)])));
+        assert_eq!(Marlin{}.parse_rx_line("echo:  M301 E1 P22.20 I1.08 D114.00").unwrap(),
+            Response::SETTING("hotend_pid", 1, SettingValues::from([('P', 22.2), ('I', 1.08), ('D', 114.)])));
+        assert_eq!(Marlin{}.parse_rx_line("echo:  M92 T1 E415.00").unwrap(),
+            Response::SETTING("steps_per_mm", 1, SettingValues::from([('E', 415.)])));
+        assert!(Marlin{}.parse_rx_line("echo:  G21    ; Units in mm (mm)").is_err());
+        assert!(Marlin{}.parse_rx_line("echo:; Steps per unit:").is_err());
+    }
+
+    #[test]
+    fn write_settings_cmds() {
+        let mut old = FirmwareSettings::default();
+        old.steps_per_mm.extend([('X', 80.), ('E', 96.)]);
+        old.hotend_pid.extend([('P', 52.44), ('I', 7.19), ('D', 95.57)]);
+
+        let mut new = old.clone();
+        new.steps_per_mm.insert('E', 93.25);
+        new.linear_advance.insert('K', 0.05);
+
+        assert_eq!(Marlin{}.get_write_settings_cmds(&old, &new), vec!["M92 E93.25 X80", "M900 K0.05"]);
+
+        // With more than one extruder, each one gets its index
+        let old = new.clone();
+        new.extruder_mut(1).steps_per_mm.insert('E', 415.);
+        new.extruder_mut(1).hotend_pid.insert('P', 25.);
+        new.jerk.insert('J', 0.02);
+        assert_eq!(Marlin{}.get_write_settings_cmds(&old, &new), vec!["M205 J0.02", "M92 T1 E415", "M301 E1 P25"]);
+    }
+
+    #[test]
+    fn pid_cmds() {
+        let params = PidAutotuneParams{heater: ProbePoint::BED, index: None, target: 70., cycles: 8};
+        assert_eq!(Marlin{}.get_pid_autotune_cmd(&params).unwrap(), "M303 E-1 S70 C8");
+        assert_eq!(Marlin{}.get_set_pid_cmds(ProbePoint::HOTEND, 0, &PidValues{kp: 20.391, ki: 2.08, kd: 49.95}).unwrap()[0], "M301 E0 P20.39 I2.08 D49.95");
+        assert!(Marlin{}.get_set_pid_cmds(ProbePoint::CHAMBER, 0, &PidValues{kp: 1., ki: 1., kd: 1.}).is_err());
     }
 
     #[test]
@@ -445,6 +714,19 @@ mod tests {
         assert_eq!(Marlin{}.get_home_cmds(&(Axis::Y | Axis::Z | Axis::E))[1], "G28 Y Z");
     }
 
+    #[test]
+    fn parse_outgoing_steppers() {
+        assert_eq!(Marlin{}.parse_outgoing_cmd("M84").unwrap(), OutgoingCmd::DisableSteppers(EnumSet::all()));
+        assert_eq!(Marlin{}.parse_outgoing_cmd("M18 X E").unwrap(), OutgoingCmd::DisableSteppers(enum_set!(Axis::X | Axis::E)));
+        assert_eq!(Marlin{}.parse_outgoing_cmd("M84 S120").unwrap(), OutgoingCmd::SetStepperTimeout(Some(std::time::Duration::from_secs(120))));
+        assert_eq!(Marlin{}.parse_outgoing_cmd("M18 S0").unwrap(), OutgoingCmd::SetStepperTimeout(None));
+        assert_eq!(Marlin{}.parse_outgoing_cmd("M17").unwrap(), OutgoingCmd::EnableSteppers(EnumSet::all()));
+        assert_eq!(Marlin{}.parse_outgoing_cmd("M180"), None);
+
+        assert_eq!(Marlin{}.get_disable_steppers_cmd(&EnumSet::all()), "M84");
+        assert_eq!(Marlin{}.get_disable_steppers_cmd(&(Axis::Z | Axis::E)), "M84 Z E");
+    }
+
     #[test]
     fn parse_outgoing_position() {
         assert_eq!(Marlin{}.parse_outgoing_cmd("G0 F7200 X128.675 Y77.399").unwrap(), 
@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+
+use crate::firmware_settings::{FirmwareSettings, SettingValues};
+use crate::gcode_metadata::BoundingBox;
+
+const AXES: [char; 4] = ['X', 'Y', 'Z', 'E'];
+const AMBIENT_TEMPERATURE: f64 = 25.;
+// Rough guesses for moves whose duration depends on the printer more than the gcode
+const HOMING_SECS: f64 = 15.;
+const PROBING_SECS: f64 = 90.;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotionLimits {
+    // Per axis (X, Y, Z, E), in mm/s and mm/s^2
+    pub max_feedrate: [f64; 4],
+    pub max_acceleration: [f64; 4],
+    pub print_acceleration: f64,
+    pub retract_acceleration: f64,
+    pub travel_acceleration: f64,
+    // Speed we assume the toolhead keeps through a corner, mm/s
+    pub junction_speed: f64,
+    // Degrees per second
+    pub hotend_heating_rate: f64,
+    pub bed_heating_rate: f64
+}
+
+impl Default for MotionLimits {
+    // Marlin's defaults for a typical bed slinger
+    fn default() -> Self {
+        MotionLimits { max_feedrate: [500., 500., 5., 25.], max_acceleration: [500., 500., 100., 5000.],
+            print_acceleration: 500., retract_acceleration: 500., travel_acceleration: 500., junction_speed: 8.,
+            hotend_heating_rate: 2.5, bed_heating_rate: 0.6 }
+    }
+}
+
+impl From<&FirmwareSettings> for MotionLimits {
+    // Use whatever the printer reported, falling back to the defaults for the rest
+    fn from(settings: &FirmwareSettings) -> Self {
+        let mut limits = MotionLimits::default();
+
+        fn per_axis(values: &SettingValues, limits: &mut [f64; 4]) {
+            for (i, axis) in AXES.iter().enumerate() {
+                if let Some(v) = values.get(axis).filter(|v| **v > 0.) {
+                    limits[i] = *v;
+                }
+            }
+        }
+        per_axis(&settings.max_feedrate, &mut limits.max_feedrate);
+        per_axis(&settings.max_acceleration, &mut limits.max_acceleration);
+
+        let positive = |values: &SettingValues, param: char| values.get(&param).copied().filter(|v| *v > 0.);
+        limits.print_acceleration = positive(&settings.acceleration, 'P').unwrap_or(limits.print_acceleration);
+        limits.retract_acceleration = positive(&settings.acceleration, 'R').unwrap_or(limits.retract_acceleration);
+        limits.travel_acceleration = positive(&settings.acceleration, 'T').unwrap_or(limits.travel_acceleration);
+        limits.junction_speed = positive(&settings.jerk, 'X').unwrap_or(limits.junction_speed);
+        limits
+    }
+}
+
+// Heats at a constant rate towards its target, cools instantly.
+struct HeaterModel {
+    temperature: f64,
+    target: f64,
+    since: f64,
+    rate: f64
+}
+
+impl HeaterModel {
+    fn new(rate: f64) -> Self {
+        HeaterModel { temperature: AMBIENT_TEMPERATURE, target: AMBIENT_TEMPERATURE, since: 0., rate }
+    }
+
+    fn temperature_at(&self, elapsed: f64) -> f64 {
+        if self.target <= self.temperature {
+            return self.target.max(AMBIENT_TEMPERATURE);
+        }
+        (self.temperature + self.rate * (elapsed - self.since)).min(self.target)
+    }
+
+    fn set_target(&mut self, target: f64, elapsed: f64) {
+        self.temperature = self.temperature_at(elapsed);
+        self.target = target;
+        self.since = elapsed;
+    }
+
+    // How long until we reach the target, if we're waiting for it at elapsed
+    fn wait_secs(&self, elapsed: f64) -> f64 {
+        (self.target - self.temperature_at(elapsed)).max(0.) / self.rate
+    }
+}
+
+// Walks the gcode, keeping a running estimate of how long it takes to print, based on the printer's motion limits.
+pub struct MotionAnalyser {
+    limits: MotionLimits,
+    position: [f64; 4],
+    relative_xyz: bool,
+    relative_e: bool,
+    // mm/s
+    feedrate: f64,
+    hotend: HeaterModel,
+    bed: HeaterModel,
+    elapsed_secs: f64,
+    // Height of the last layer we saw extrusion on, and whether the last line started a new one
+    layer_z: Option<f64>,
+    new_layer: Option<f64>,
+    // Min and max X, Y, Z of everything extruded
+    extrusion_bounds: Option<([f64; 3], [f64; 3])>
+}
+
+impl MotionAnalyser {
+    pub fn new(limits: MotionLimits) -> Self {
+        let hotend = HeaterModel::new(limits.hotend_heating_rate);
+        let bed = HeaterModel::new(limits.bed_heating_rate);
+        MotionAnalyser { limits, position: [0.; 4], relative_xyz: false, relative_e: false, feedrate: 25., hotend, bed, elapsed_secs: 0., layer_z: None, new_layer: None, extrusion_bounds: None }
+    }
+
+    pub fn elapsed_secs(&self) -> f64 {
+        self.elapsed_secs
+    }
+
+    // If the last line extruded higher up than anything before it, the Z of the new layer
+    pub fn take_layer_change(&mut self) -> Option<f64> {
+        self.new_layer.take()
+    }
+
+    pub fn extrusion_bounds(&self) -> Option<BoundingBox> {
+        self.extrusion_bounds.map(|(min, max)| BoundingBox { min_x: min[0], min_y: min[1], min_z: min[2], max_x: max[0], max_y: max[1], max_z: max[2] })
+    }
+
+    fn parse_params(words: &[&str]) -> BTreeMap<char, f64> {
+        words.iter().filter_map(|word| {
+            let mut chars = word.chars();
+            let letter = chars.next()?.to_ascii_uppercase();
+            Some((letter, chars.as_str().parse::<f64>().unwrap_or(0.)))
+        }).collect()
+    }
+
+    pub fn process_line(&mut self, line: &str) {
+        let code = match line.find(';') {
+            Some(semicolon_pos) => &line[..semicolon_pos],
+            None => line
+        };
+        let mut words : Vec<&str> = code.split_whitespace().collect();
+        if words.first().is_some_and(|w| w.starts_with('N') || w.starts_with('n')) {
+            words.remove(0);
+        }
+        if words.is_empty() {
+            return;
+        }
+
+        let cmd = words[0].to_ascii_uppercase();
+        let params = MotionAnalyser::parse_params(&words[1..]);
+
+        match cmd.as_str() {
+            "G0" | "G1" => self.linear_move(&params),
+            "G2" | "G3" => self.arc_move(&params, cmd == "G2"),
+            "G4" => {
+                self.elapsed_secs += params.get(&'P').map(|ms| ms / 1000.).or(params.get(&'S').copied()).unwrap_or(0.);
+            }
+            "G28" => {
+                let all = !params.keys().any(|k| matches!(k, 'X' | 'Y' | 'Z'));
+                for (i, axis) in AXES[..3].iter().enumerate() {
+                    if all || params.contains_key(axis) {
+                        self.position[i] = 0.;
+                    }
+                }
+                self.elapsed_secs += HOMING_SECS;
+            }
+            "G29" => self.elapsed_secs += PROBING_SECS,
+            "G90" => {self.relative_xyz = false; self.relative_e = false;}
+            "G91" => {self.relative_xyz = true; self.relative_e = true;}
+            "M82" => self.relative_e = false,
+            "M83" => self.relative_e = true,
+            "G92" => {
+                for (i, axis) in AXES.iter().enumerate() {
+                    if let Some(v) = params.get(axis) {
+                        self.position[i] = *v;
+                    }
+                }
+            }
+            "M104" | "M109" | "M140" | "M190" => {
+                let heater = if cmd == "M104" || cmd == "M109" {&mut self.hotend} else {&mut self.bed};
+                if let Some(target) = params.get(&'S').or(params.get(&'R')) {
+                    heater.set_target(*target, self.elapsed_secs);
+                }
+                if cmd == "M109" || cmd == "M190" {
+                    self.elapsed_secs += heater.wait_secs(self.elapsed_secs);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Where the axes end up after a move with these params
+    fn move_target(&self, params: &BTreeMap<char, f64>) -> [f64; 4] {
+        let mut target = self.position;
+        for (i, axis) in AXES.iter().enumerate() {
+            if let Some(v) = params.get(axis) {
+                let relative = if i == 3 {self.relative_e} else {self.relative_xyz};
+                target[i] = if relative {self.position[i] + v} else {*v};
+            }
+        }
+        target
+    }
+
+    fn linear_move(&mut self, params: &BTreeMap<char, f64>) {
+        if let Some(f) = params.get(&'F').filter(|f| **f > 0.) {
+            self.feedrate = f / 60.;
+        }
+        let target = self.move_target(params);
+        let deltas = [0, 1, 2, 3].map(|i| target[i] - self.position[i]);
+        let xyz_length = (deltas[0].powi(2) + deltas[1].powi(2) + deltas[2].powi(2)).sqrt();
+
+        self.add_move(&deltas, xyz_length);
+        self.track_extrusion(&deltas, xyz_length, target[2]);
+        self.position = target;
+    }
+
+    fn arc_move(&mut self, params: &BTreeMap<char, f64>, clockwise: bool) {
+        if let Some(f) = params.get(&'F').filter(|f| **f > 0.) {
+            self.feedrate = f / 60.;
+        }
+        let target = self.move_target(params);
+        let deltas = [0, 1, 2, 3].map(|i| target[i] - self.position[i]);
+
+        let (i, j) = (params.get(&'I').copied().unwrap_or(0.), params.get(&'J').copied().unwrap_or(0.));
+        let radius = i.hypot(j);
+        let planar_length = if radius > 0. {
+            let (centre_x, centre_y) = (self.position[0] + i, self.position[1] + j);
+            let start_angle = (-j).atan2(-i);
+            let end_angle = (target[1] - centre_y).atan2(target[0] - centre_x);
+            let mut sweep = if clockwise {start_angle - end_angle} else {end_angle - start_angle};
+            if sweep <= 1e-9 {
+                sweep += 2. * std::f64::consts::PI;
+            }
+            radius * sweep
+        } else {
+            // R form or malformed, the chord will have to do
+            deltas[0].hypot(deltas[1])
+        };
+
+        self.add_move(&deltas, planar_length.hypot(deltas[2]));
+        self.track_extrusion(&deltas, planar_length, target[2]);
+        self.position = target;
+    }
+
+    // Travel and z-hops don't count, only printing moves
+    fn track_extrusion(&mut self, deltas: &[f64; 4], length: f64, z: f64) {
+        const Z_EPSILON: f64 = 0.0001;
+
+        if deltas[3] <= 0. || length <= 0. {
+            return;
+        }
+
+        if self.layer_z.is_none_or(|layer_z| z > layer_z + Z_EPSILON) {
+            self.layer_z = Some(z);
+            self.new_layer = Some(z);
+        }
+
+        let (start, end) = (&self.position, [self.position[0] + deltas[0], self.position[1] + deltas[1], z]);
+        let (min, max) = self.extrusion_bounds.get_or_insert(([start[0], start[1], start[2]], [start[0], start[1], start[2]]));
+        for i in 0..3 {
+            min[i] = min[i].min(start[i]).min(end[i]);
+            max[i] = max[i].max(start[i]).max(end[i]);
+        }
+    }
+
+    fn add_move(&mut self, deltas: &[f64; 4], xyz_length: f64) {
+        let length = if xyz_length > 0. {xyz_length} else {deltas[3].abs()};
+        if length <= 0. {
+            return;
+        }
+
+        let mut acceleration = if xyz_length == 0. {
+            self.limits.retract_acceleration
+        } else if deltas[3] == 0. {
+            self.limits.travel_acceleration
+        } else {
+            self.limits.print_acceleration
+        };
+        let mut speed = self.feedrate;
+
+        // No axis may go faster or accelerate harder than it's allowed to
+        for (i, delta) in deltas.iter().enumerate() {
+            if *delta != 0. {
+                let ratio = length / delta.abs();
+                speed = speed.min(self.limits.max_feedrate[i] * ratio);
+                acceleration = acceleration.min(self.limits.max_acceleration[i] * ratio);
+            }
+        }
+
+        self.elapsed_secs += MotionAnalyser::trapezoid_secs(length, speed, acceleration, self.limits.junction_speed.min(speed));
+    }
+
+    // Time to travel length, accelerating from and decelerating to junction_speed, cruising at speed if there's room to reach it
+    fn trapezoid_secs(length: f64, speed: f64, acceleration: f64, junction_speed: f64) -> f64 {
+        if acceleration <= 0. || speed <= junction_speed {
+            return length / speed.max(f64::EPSILON);
+        }
+
+        let accel_distance = (speed.powi(2) - junction_speed.powi(2)) / (2. * acceleration);
+        if 2. * accel_distance >= length {
+            let peak_speed = (acceleration * length + junction_speed.powi(2)).sqrt();
+            return 2. * (peak_speed - junction_speed) / acceleration;
+        }
+
+        2. * (speed - junction_speed) / acceleration + (length - 2. * accel_distance) / speed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+
+    use super::*;
+
+    fn no_acceleration() -> MotionLimits {
+        MotionLimits { max_acceleration: [1e9; 4], print_acceleration: 1e9, retract_acceleration: 1e9, travel_acceleration: 1e9, ..MotionLimits::default() }
+    }
+
+    #[test]
+    fn moves_at_feedrate() {
+        let mut analyser = MotionAnalyser::new(no_acceleration());
+        analyser.process_line("G1 X100 F6000 ; 100mm at 100mm/s");
+        analyser.process_line("G91");
+        analyser.process_line("G1 Y-50");
+        assert_approx_eq!(analyser.elapsed_secs(), 1.5, 0.001);
+
+        // Z is limited to 5mm/s
+        analyser.process_line("G1 Z10");
+        assert_approx_eq!(analyser.elapsed_secs(), 3.5, 0.001);
+    }
+
+    #[test]
+    fn acceleration() {
+        // 10mm at 100mm/s, 500mm/s^2 never gets to full speed: accelerate to sqrt(500 * 10 + 64) then back down
+        assert_approx_eq!(MotionAnalyser::trapezoid_secs(10., 100., 500., 8.), 2. * ((5064f64).sqrt() - 8.) / 500.);
+        // 100mm does, (100^2 - 8^2) / 1000 = 9.936mm to accelerate and the same to stop
+        assert_approx_eq!(MotionAnalyser::trapezoid_secs(100., 100., 500., 8.), 2. * 92. / 500. + (100. - 2. * 9.936) / 100.);
+    }
+
+    #[test]
+    fn dwell_and_heating() {
+        let mut analyser = MotionAnalyser::new(MotionLimits::default());
+        analyser.process_line("G4 P500");
+        analyser.process_line("G4 S2");
+        assert_approx_eq!(analyser.elapsed_secs(), 2.5);
+
+        // Heats from 25 to 200 at 2.5C/s, 4s of that happen while dwelling
+        analyser.process_line("M104 S200");
+        analyser.process_line("G4 S4");
+        analyser.process_line("M109 S200");
+        assert_approx_eq!(analyser.elapsed_secs(), 2.5 + 175. / 2.5);
+
+        // Already there
+        analyser.process_line("M109 S200");
+        assert_approx_eq!(analyser.elapsed_secs(), 2.5 + 175. / 2.5);
+    }
+
+    #[test]
+    fn layers_from_z() {
+        let mut analyser = MotionAnalyser::new(MotionLimits::default());
+        let mut layers = Vec::new();
+        for line in ["G1 Z0.2 F600", "G1 X10 E1", "G1 Z0.6", "G1 X20", "G1 Z0.2", "G1 X30 E2", "G1 Z0.4", "G1 X0 E3", "G1 E2.5"] {
+            analyser.process_line(line);
+            if let Some(z) = analyser.take_layer_change() {
+                layers.push(z);
+            }
+        }
+        assert_eq!(layers, vec![0.2, 0.4]);
+        assert_eq!(analyser.extrusion_bounds(), Some(BoundingBox{min_x: 0., min_y: 0., min_z: 0.2, max_x: 30., max_y: 0., max_z: 0.4}));
+    }
+
+    #[test]
+    fn arcs_and_extrusion() {
+        let mut analyser = MotionAnalyser::new(no_acceleration());
+        // Half circle of radius 10 around (10, 0) at 10mm/s
+        analyser.process_line("G2 X20 Y0 I10 J0 F600");
+        assert_approx_eq!(analyser.elapsed_secs(), std::f64::consts::PI, 0.001);
+
+        // E only move, limited to 25mm/s
+        analyser.process_line("M83");
+        analyser.process_line("G1 E-50 F3000");
+        assert_approx_eq!(analyser.elapsed_secs(), std::f64::consts::PI + 2., 0.001);
+    }
+}
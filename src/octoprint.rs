@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use rocket::serde::Serialize;
+
+use crate::file;
+use crate::internal_api::{PrintState, PrinterStatus, ProbePoint};
+
+// Slicers check the text starts with "OctoPrint" before they'll talk to us
+pub const SERVER_VERSION: &str = "1.9.0";
+pub const API_VERSION: &str = "0.1";
+
+#[derive(Serialize, Debug)]
+pub struct VersionInfo {
+    pub api: String,
+    pub server: String,
+    pub text: String
+}
+
+impl VersionInfo {
+    pub fn new() -> Self {
+        VersionInfo { api: API_VERSION.to_string(), server: SERVER_VERSION.to_string(), text: format!("OctoPrint {} (Yoctoprint)", SERVER_VERSION) }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileRefs {
+    pub resource: String
+}
+
+#[derive(Serialize, Debug)]
+pub struct UploadedFile {
+    pub name: String,
+    pub path: String,
+    pub origin: String,
+    pub refs: FileRefs
+}
+
+#[derive(Serialize, Debug)]
+pub struct UploadedFiles {
+    pub local: UploadedFile
+}
+
+#[derive(Serialize, Debug)]
+pub struct UploadResult {
+    pub files: UploadedFiles,
+    pub done: bool
+}
+
+impl UploadResult {
+    pub fn new(path: &str) -> Self {
+        let name = Path::new(path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        UploadResult { files: UploadedFiles { local: UploadedFile { name, path: path.to_string(), origin: "local".to_string(),
+            refs: FileRefs { resource: format!("/api/files/local/{}", path) } } }, done: true }
+    }
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StateFlags {
+    pub operational: bool,
+    pub printing: bool,
+    pub pausing: bool,
+    pub paused: bool,
+    pub cancelling: bool,
+    pub error: bool,
+    pub ready: bool,
+    pub closed_or_error: bool,
+    pub sd_ready: bool
+}
+
+#[derive(Serialize, Debug)]
+pub struct PrinterStateInfo {
+    pub text: String,
+    pub flags: StateFlags
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TemperatureInfo {
+    pub actual: f64,
+    pub target: f64,
+    pub offset: f64
+}
+
+#[derive(Serialize, Debug)]
+pub struct SdInfo {
+    pub ready: bool
+}
+
+#[derive(Serialize, Debug)]
+pub struct PrinterInfo {
+    pub temperature: HashMap<String, TemperatureInfo>,
+    pub sd: SdInfo,
+    pub state: PrinterStateInfo
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct JobFile {
+    pub name: Option<String>,
+    pub path: Option<String>,
+    pub origin: Option<String>,
+    pub size: Option<u64>,
+    pub date: Option<u64>
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct JobDetails {
+    pub file: JobFile,
+    pub estimated_print_time: Option<f64>
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct JobProgress {
+    pub completion: Option<f64>,
+    pub filepos: Option<u64>,
+    pub print_time: Option<u64>,
+    pub print_time_left: Option<u64>,
+    pub print_time_left_origin: Option<String>
+}
+
+#[derive(Serialize, Debug)]
+pub struct JobInfo {
+    pub job: JobDetails,
+    pub progress: JobProgress,
+    pub state: String
+}
+
+pub fn state_text(status: &PrinterStatus) -> &'static str {
+    if !status.printer_connected {
+        return "Offline";
+    }
+    match status.state {
+        PrintState::DEAD => "Offline",
+        PrintState::CONNECTED | PrintState::COMPLETED | PrintState::CANCELLED => "Operational",
+        PrintState::HEATING | PrintState::STARTED | PrintState::FINISHING => "Printing",
+        PrintState::PAUSING => "Pausing",
+        PrintState::PAUSED => "Paused",
+        PrintState::RESUMING => "Resuming",
+        PrintState::CANCELLING => "Cancelling",
+        PrintState::ERROR => "Error"
+    }
+}
+
+pub fn state_flags(status: &PrinterStatus) -> StateFlags {
+    let operational = status.printer_connected && status.state != PrintState::DEAD;
+    let error = status.state == PrintState::ERROR;
+    StateFlags {
+        operational,
+        printing: matches!(status.state, PrintState::HEATING | PrintState::STARTED | PrintState::FINISHING | PrintState::RESUMING),
+        pausing: status.state == PrintState::PAUSING,
+        paused: status.state == PrintState::PAUSED,
+        cancelling: status.state == PrintState::CANCELLING,
+        error,
+        ready: operational && status.state.is_idle(),
+        closed_or_error: !operational || error,
+        sd_ready: false
+    }
+}
+
+pub fn printer_info(status: &PrinterStatus) -> PrinterInfo {
+    let temperature = status.temperatures.iter().filter_map(|t| {
+        let name = match t.measured_from {
+            ProbePoint::HOTEND => format!("tool{}", t.index),
+            ProbePoint::BED => "bed".to_string(),
+            ProbePoint::CHAMBER => "chamber".to_string(),
+            _ => return None
+        };
+        Some((name, TemperatureInfo { actual: t.current, target: t.target, offset: 0. }))
+    }).collect();
+
+    PrinterInfo { temperature, sd: SdInfo { ready: false }, state: PrinterStateInfo { text: state_text(status).to_string(), flags: state_flags(status) } }
+}
+
+pub fn job_info(status: &PrinterStatus, data_dir: &Path) -> JobInfo {
+    let mut job = JobDetails::default();
+    let mut progress = JobProgress::default();
+
+    if let Some((name, lines_done, lines_total)) = &status.gcode_lines_done_total {
+        let metadata = file::get_abs_gcode_path(data_dir, Path::new(name)).and_then(std::fs::metadata).ok();
+        job.file = JobFile {
+            name: Path::new(name).file_name().map(|n| n.to_string_lossy().to_string()),
+            path: Some(name.clone()),
+            origin: Some("local".to_string()),
+            size: metadata.as_ref().map(|m| m.len()),
+            date: metadata.and_then(|m| m.modified().ok())
+                .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok()).map(|d| d.as_secs())
+        };
+        if *lines_total > 0 {
+            progress.completion = Some(*lines_done as f64 * 100. / *lines_total as f64);
+        }
+        progress.print_time = status.print_time_elapsed.map(|d| d.as_secs());
+        progress.print_time_left = status.print_time_remaining.map(|d| d.as_secs());
+        progress.print_time_left_origin = progress.print_time_left.map(|_| "estimate".to_string());
+        job.estimated_print_time = status.print_time_remaining.map(|r| (r + status.print_time_elapsed.unwrap_or_default()).as_secs_f64());
+    }
+
+    JobInfo { job, progress, state: state_text(status).to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal_api::Temperature;
+
+    #[test]
+    fn maps_printer_status() {
+        let mut status = PrinterStatus::default();
+        assert_eq!(state_text(&status), "Offline");
+        assert!(state_flags(&status).closed_or_error);
+
+        status.printer_connected = true;
+        status.state = PrintState::HEATING;
+        status.temperatures = vec![Temperature{measured_from: ProbePoint::HOTEND, index: 0, power: 0., current: 180., target: 210.},
+                                   Temperature{measured_from: ProbePoint::BED, index: 0, power: 0., current: 60., target: 60.}];
+        status.gcode_lines_done_total = Some(("parts/cube.gcode".to_string(), 25, 100));
+        status.print_time_elapsed = Some(std::time::Duration::from_secs(60));
+        status.print_time_remaining = Some(std::time::Duration::from_secs(180));
+
+        let printer = printer_info(&status);
+        assert_eq!(printer.state.text, "Printing");
+        assert_eq!(printer.state.flags, StateFlags { operational: true, printing: true, ..StateFlags::default() });
+        assert_eq!(printer.temperature["tool0"], TemperatureInfo { actual: 180., target: 210., offset: 0. });
+        assert_eq!(printer.temperature["bed"].target, 60.);
+
+        let job = job_info(&status, Path::new("/nonexistent"));
+        assert_eq!(job.job.file.name.as_deref(), Some("cube.gcode"));
+        assert_eq!(job.job.estimated_print_time, Some(240.));
+        assert_eq!(job.progress.completion, Some(25.));
+        assert_eq!(job.progress.print_time_left, Some(180));
+    }
+}
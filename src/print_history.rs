@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::info;
+use rocket::serde::{Serialize, Deserialize};
+
+use crate::file::GCodeFile;
+
+pub const HISTORY_FILE: &str = "print_history.json";
+
+lazy_static! {
+    // The printer loop adds jobs while the API reads and deletes them
+    static ref HISTORY_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum JobOutcome {
+    DONE,
+    CANCELLED,
+    FAILED
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JobRecord {
+    pub id: u64,
+    // Relative to the gcode dir
+    pub file: String,
+    pub file_sha256: Option<String>,
+    pub started_secs_since_epoch: u64,
+    pub ended_secs_since_epoch: u64,
+    // Time spent printing, pauses not included
+    pub duration_secs: f64,
+    pub outcome: JobOutcome,
+    // Why it failed
+    pub reason: Option<String>,
+    // What the slicer said the whole file needs, scaled down for prints that didn't finish
+    pub filament_used_mm: Option<f64>,
+    pub printer: String
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HistoryFilter {
+    pub outcome: Option<JobOutcome>,
+    // Part of the file name
+    pub file: Option<String>,
+    pub since_secs: Option<u64>,
+    pub until_secs: Option<u64>
+}
+
+impl HistoryFilter {
+    fn matches(&self, job: &JobRecord) -> bool {
+        self.outcome.is_none_or(|o| o == job.outcome)
+        && self.file.as_ref().is_none_or(|f| job.file.contains(f.as_str()))
+        && self.since_secs.is_none_or(|s| job.started_secs_since_epoch >= s)
+        && self.until_secs.is_none_or(|u| job.started_secs_since_epoch < u)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HistoryStats {
+    pub jobs: usize,
+    pub total_hours: f64,
+    // Fraction of jobs that finished
+    pub success_rate: Option<f64>,
+    // Keyed by "YYYY-MM", in UTC
+    pub filament_mm_per_month: BTreeMap<String, f64>
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// Keeps track of the job being printed, and the ones that ended but haven't been saved yet
+#[derive(Default)]
+pub struct JobTracker {
+    current: Option<JobRecord>,
+    finished: Vec<JobRecord>,
+    last: Option<JobRecord>
+}
+
+impl JobTracker {
+    pub fn begin(&mut self, file: &GCodeFile, printer: &str) {
+        self.current = Some(JobRecord { id: 0, file: file.name().to_string(), file_sha256: Some(file.sha256.clone()), started_secs_since_epoch: now_secs(),
+            ended_secs_since_epoch: 0, duration_secs: 0., outcome: JobOutcome::DONE, reason: None, filament_used_mm: None, printer: printer.to_string() });
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn finish(&mut self, outcome: JobOutcome, reason: Option<String>, file: Option<&GCodeFile>, duration: Duration) {
+        if let Some(mut job) = self.current.take() {
+            job.ended_secs_since_epoch = now_secs();
+            job.duration_secs = duration.as_secs_f64();
+            job.outcome = outcome;
+            job.reason = reason;
+            job.filament_used_mm = file.and_then(|f| {
+                let done = if outcome == JobOutcome::DONE || f.line_count == 0 { 1. } else { f.cur_line_in_file as f64 / f.line_count as f64 };
+                f.metadata.filament_used_mm.map(|mm| mm * done)
+            });
+            info!("Print of {} ended: {:?}", job.file, job.outcome);
+            self.last = Some(job.clone());
+            self.finished.push(job);
+        }
+    }
+
+    // The job that ended most recently, saved or not
+    pub fn last(&self) -> Option<&JobRecord> {
+        self.last.as_ref()
+    }
+
+    pub fn take_finished(&mut self) -> Vec<JobRecord> {
+        std::mem::take(&mut self.finished)
+    }
+}
+
+fn read_all(data_dir: &Path) -> std::io::Result<Vec<JobRecord>> {
+    match std::fs::read_to_string(data_dir.join(HISTORY_FILE)) {
+        Ok(contents) => rocket::serde::json::from_str::<Vec<JobRecord>>(&contents)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e)
+    }
+}
+
+fn write_all(data_dir: &Path, jobs: &Vec<JobRecord>) -> std::io::Result<()> {
+    let as_string = rocket::serde::json::to_string(jobs)
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    std::fs::write(data_dir.join(HISTORY_FILE), as_string)
+}
+
+// Save a job that ended, giving it the next id
+pub fn add(data_dir: &Path, mut job: JobRecord) -> std::io::Result<()> {
+    let _lock = HISTORY_LOCK.lock().unwrap();
+    let mut jobs = read_all(data_dir)?;
+    job.id = jobs.iter().map(|j| j.id).max().map_or(1, |id| id + 1);
+    jobs.push(job);
+    write_all(data_dir, &jobs)
+}
+
+// Latest first
+pub fn list(data_dir: &Path, filter: &HistoryFilter) -> std::io::Result<Vec<JobRecord>> {
+    let _lock = HISTORY_LOCK.lock().unwrap();
+    Ok(read_all(data_dir)?.into_iter().rev().filter(|j| filter.matches(j)).collect())
+}
+
+// Delete the matching jobs, returns how many there were
+pub fn delete(data_dir: &Path, id: Option<u64>, filter: &HistoryFilter) -> std::io::Result<usize> {
+    let _lock = HISTORY_LOCK.lock().unwrap();
+    let mut jobs = read_all(data_dir)?;
+    let count = jobs.len();
+    jobs.retain(|j| !(id.is_none_or(|id| id == j.id) && filter.matches(j)));
+    write_all(data_dir, &jobs)?;
+    Ok(count - jobs.len())
+}
+
+// "YYYY-MM" of a time, in UTC
+fn month_of(secs_since_epoch: u64) -> String {
+    // Days to civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs_since_epoch / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}", year, month)
+}
+
+pub fn stats(data_dir: &Path, filter: &HistoryFilter) -> std::io::Result<HistoryStats> {
+    let jobs = list(data_dir, filter)?;
+    let done = jobs.iter().filter(|j| j.outcome == JobOutcome::DONE).count();
+
+    let mut filament_mm_per_month = BTreeMap::new();
+    for job in jobs.iter() {
+        if let Some(mm) = job.filament_used_mm {
+            *filament_mm_per_month.entry(month_of(job.started_secs_since_epoch)).or_insert(0.) += mm;
+        }
+    }
+
+    Ok(HistoryStats {
+        jobs: jobs.len(),
+        total_hours: jobs.iter().map(|j| j.duration_secs).sum::<f64>() / 3600.,
+        success_rate: if jobs.is_empty() { None } else { Some(done as f64 / jobs.len() as f64) },
+        filament_mm_per_month
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(file: &str, started: u64, outcome: JobOutcome, filament: f64) -> JobRecord {
+        JobRecord { id: 0, file: file.to_string(), file_sha256: None, started_secs_since_epoch: started, ended_secs_since_epoch: started + 3600,
+            duration_secs: 3600., outcome, reason: None, filament_used_mm: Some(filament), printer: "Test".to_string() }
+    }
+
+    #[test]
+    fn history_and_stats() {
+        let data_dir = std::env::temp_dir().join(format!("yoctoprint-history-{}", std::process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+
+        // 2024-01-31 and 2024-02-01
+        add(&data_dir, job("parts/clip.gcode", 1706659200, JobOutcome::DONE, 1000.)).unwrap();
+        add(&data_dir, job("benchy.gcode", 1706745600, JobOutcome::CANCELLED, 200.)).unwrap();
+        add(&data_dir, job("parts/clip.gcode", 1706745601, JobOutcome::DONE, 1000.)).unwrap();
+
+        let all = list(&data_dir, &HistoryFilter::default()).unwrap();
+        assert_eq!(all.iter().map(|j| j.id).collect::<Vec<u64>>(), vec![3, 2, 1]);
+        let clips = list(&data_dir, &HistoryFilter { file: Some("clip".to_string()), ..HistoryFilter::default() }).unwrap();
+        assert_eq!(clips.len(), 2);
+
+        let stats = stats(&data_dir, &HistoryFilter::default()).unwrap();
+        assert_eq!(stats.jobs, 3);
+        assert_eq!(stats.total_hours, 3.);
+        assert_eq!(stats.success_rate, Some(2. / 3.));
+        assert_eq!(stats.filament_mm_per_month, BTreeMap::from([("2024-01".to_string(), 1000.), ("2024-02".to_string(), 1200.)]));
+
+        assert_eq!(delete(&data_dir, Some(2), &HistoryFilter::default()).unwrap(), 1);
+        assert_eq!(delete(&data_dir, None, &HistoryFilter { outcome: Some(JobOutcome::DONE), ..HistoryFilter::default() }).unwrap(), 2);
+        assert!(list(&data_dir, &HistoryFilter::default()).unwrap().is_empty());
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
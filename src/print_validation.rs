@@ -0,0 +1,146 @@
+use std::collections::{BTreeSet, HashMap};
+
+use rocket::serde::Serialize;
+
+use crate::api_error::{CodedError, ErrorCode, FieldError};
+use crate::config::{PrinterProfile, ThermalConfig};
+use crate::gcode_metadata::GCodeMetadata;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum ValidationIssueKind {
+    BOUNDS,
+    TEMPERATURE,
+    ARCS,
+    TOOL,
+    COMMAND
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ValidationIssue {
+    pub kind: ValidationIssueKind,
+    pub message: String
+}
+
+// Everything we know about what the printer can do
+pub struct PrinterLimits<'a> {
+    pub profile: &'a PrinterProfile,
+    pub thermal: &'a ThermalConfig,
+    // M115 capabilities
+    pub fw_info: &'a HashMap<String, String>,
+    // Commands the firmware replied "Unknown command" to
+    pub unknown_commands: &'a BTreeSet<String>
+}
+
+impl ValidationIssue {
+    fn new(kind: ValidationIssueKind, message: String) -> Self {
+        ValidationIssue { kind, message }
+    }
+}
+
+// Check that the file can be printed on this printer, returns every problem found.
+pub fn validate(metadata: &GCodeMetadata, limits: &PrinterLimits) -> Vec<ValidationIssue> {
+    const EPSILON: f64 = 0.001;
+    let mut issues = Vec::new();
+
+    if let Some(bounds) = &metadata.bounding_box {
+        let spans = [(bounds.min_x, bounds.max_x), (bounds.min_y, bounds.max_y), (bounds.min_z, bounds.max_z)];
+        for ((axis, range), (min, max)) in limits.profile.axis_ranges().into_iter().zip(spans) {
+            if min < range.min - EPSILON || max > range.max + EPSILON {
+                issues.push(ValidationIssue::new(ValidationIssueKind::BOUNDS,
+                    format!("Print spans {:.1} to {:.1} on {}, the printer only goes from {:.1} to {:.1}", min, max, axis, range.min, range.max)));
+            }
+        }
+    }
+
+    for (name, temperature, max) in [("Hotend", metadata.max_hotend_temperature, limits.thermal.hotend.max_temperature),
+                                     ("Bed", metadata.max_bed_temperature, limits.thermal.bed.max_temperature)] {
+        if let Some(temperature) = temperature.filter(|t| *t > max) {
+            issues.push(ValidationIssue::new(ValidationIssueKind::TEMPERATURE,
+                format!("{} temperature {:.0} is above the maximum of {:.0}", name, temperature, max)));
+        }
+    }
+
+    let uses_arcs = metadata.codes_used.contains("G2") || metadata.codes_used.contains("G3");
+    if uses_arcs && limits.fw_info.get("ARCS").is_some_and(|v| v == "0") {
+        issues.push(ValidationIssue::new(ValidationIssueKind::ARCS, "File uses arcs (G2/G3) but the firmware doesn't support them".to_string()));
+    }
+
+    let extruder_count = limits.fw_info.get("EXTRUDER_COUNT").and_then(|c| c.parse::<u32>().ok()).unwrap_or(1);
+    for tool in metadata.tools_used.iter().filter(|t| **t >= extruder_count) {
+        issues.push(ValidationIssue::new(ValidationIssueKind::TOOL,
+            format!("File uses tool T{} but the printer only has {} extruder(s)", tool, extruder_count)));
+    }
+
+    for cmd in metadata.codes_used.intersection(limits.unknown_commands) {
+        issues.push(ValidationIssue::new(ValidationIssueKind::COMMAND, format!("The firmware doesn't know {}", cmd)));
+    }
+
+    issues
+}
+
+// The error for a print that didn't start because of these issues, if there are any
+pub fn validation_result(issues: &[ValidationIssue]) -> std::io::Result<()> {
+    if issues.is_empty() {
+        return Ok(());
+    }
+
+    let messages : Vec<&str> = issues.iter().map(|i| i.message.as_str()).collect();
+    Err(CodedError { code: ErrorCode::VALIDATIONFAILED,
+        message: format!("File failed validation: {}. Start with force to print anyway.", messages.join("; ")),
+        fields: issues.iter().map(|i| FieldError { field: format!("{:?}", i.kind), message: i.message.clone() }).collect() }.into_io())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AxisRange;
+    use crate::gcode_metadata::BoundingBox;
+
+    #[test]
+    fn finds_problems() {
+        let profile = PrinterProfile::default();
+        let thermal = ThermalConfig::default();
+        let fw_info = HashMap::from([("ARCS".to_string(), "0".to_string()), ("EXTRUDER_COUNT".to_string(), "1".to_string())]);
+        let unknown_commands = BTreeSet::from(["M900".to_string()]);
+        let limits = PrinterLimits { profile: &profile, thermal: &thermal, fw_info: &fw_info, unknown_commands: &unknown_commands };
+
+        let mut metadata = GCodeMetadata {
+            bounding_box: Some(BoundingBox { min_x: 10., min_y: 10., min_z: 0.2, max_x: 200., max_y: 200., max_z: 20. }),
+            max_hotend_temperature: Some(210.),
+            max_bed_temperature: Some(60.),
+            codes_used: ["G0", "G1", "M104"].iter().map(|c| c.to_string()).collect(),
+            tools_used: BTreeSet::from([0]),
+            ..GCodeMetadata::default()
+        };
+        assert_eq!(validate(&metadata, &limits), vec![]);
+
+        metadata.bounding_box.as_mut().unwrap().max_x = 230.;
+        metadata.max_hotend_temperature = Some(300.);
+        metadata.codes_used.extend(["G2".to_string(), "M900".to_string()]);
+        metadata.tools_used.insert(1);
+
+        let kinds : Vec<ValidationIssueKind> = validate(&metadata, &limits).iter().map(|i| i.kind).collect();
+        assert_eq!(kinds, vec![ValidationIssueKind::BOUNDS, ValidationIssueKind::TEMPERATURE, ValidationIssueKind::ARCS,
+            ValidationIssueKind::TOOL, ValidationIssueKind::COMMAND]);
+    }
+
+    #[test]
+    fn axis_ranges_from_profile() {
+        let mut profile = PrinterProfile::default();
+        let thermal = ThermalConfig::default();
+        let (fw_info, unknown_commands) = (HashMap::new(), BTreeSet::new());
+
+        // Purge line off the front edge of the bed
+        let metadata = GCodeMetadata {
+            bounding_box: Some(BoundingBox { min_x: 0.1, min_y: -3., min_z: 0.2, max_x: 200., max_y: 200., max_z: 20. }),
+            ..GCodeMetadata::default()
+        };
+        let limits = PrinterLimits { profile: &profile, thermal: &thermal, fw_info: &fw_info, unknown_commands: &unknown_commands };
+        assert_eq!(validate(&metadata, &limits).len(), 1);
+
+        profile.y_range = Some(AxisRange { min: -5., max: 225. });
+        let limits = PrinterLimits { profile: &profile, thermal: &thermal, fw_info: &fw_info, unknown_commands: &unknown_commands };
+        assert!(validate(&metadata, &limits).is_empty());
+    }
+}
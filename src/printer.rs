@@ -6,12 +6,26 @@ use crate::internal_api::PrinterInfo;
 use crate::internal_api::Temperature;
 use crate::internal_api::TemperatureTarget;
 use crate::internal_api::Validator;
+use crate::api_error::{coded_error, field_error, ErrorCode};
 use crate::serial;
 use crate::internal_api;
 use crate::file;
 use crate::marlin;
-
-use std::collections::HashMap;
+use crate::temperature_history::{TemperatureHistory, TemperatureSample};
+use crate::thermal_watchdog::{ThermalWatchdog, ThermalFault};
+use crate::config::{Config, PrinterProfile, ThermalConfig};
+use crate::bed_mesh::{BedMesh, PendingMesh};
+use crate::firmware_settings::{FirmwareSettings, SettingChange};
+use crate::motion_analyser::MotionLimits;
+use crate::internal_api::Alert;
+use crate::internal_api::LayerDuration;
+use crate::print_validation::{self, PrinterLimits, ValidationIssue};
+use crate::print_history::{JobOutcome, JobRecord, JobTracker};
+use crate::internal_api::{PidAutotuneParams, PidAutotuneState, PidAutotuneStatus};
+use crate::internal_api::{BedProbeState, BedProbeStatus};
+use crate::internal_api::{EStepsCalibrationParams, EStepsCalibrationStatus, EStepsCalibrationStep, EStepsMeasurement, ProbePoint};
+
+use std::collections::{BTreeSet, HashMap};
 use std::ops::Div;
 use std::time::Duration;
 use std::vec;
@@ -20,7 +34,7 @@ use serial::*;
 use internal_api::PrintState;
 use std::io::Error;
 use std::io::{BufRead,Result};
-use std::path::PathBuf;
+use std::path::Path;
 use enumset::{EnumSet,enum_set};
 
 pub trait PrinterControl {
@@ -29,17 +43,39 @@ pub trait PrinterControl {
     fn next_action(&mut self) -> Result<()>;
     fn get_status(&self) -> Result<internal_api::PrinterStatus>;
     fn get_state(&self) -> PrintState;
-    fn set_gcode_file(&mut self, abs_path: &PathBuf) -> Result<()>;
+    // name is what to show for the file in the status
+    fn set_gcode_file(&mut self, abs_path: &Path, name: &str) -> Result<()>;
     fn clear_gcode_file(&mut self) -> Result<()>;
-    fn start(&mut self) -> Result<()>;
+    // Unless forced, a new print only starts if validate_print finds no problems
+    fn start(&mut self, force: bool) -> Result<()>;
+    fn validate_print(&self) -> Result<Vec<ValidationIssue>>;
     fn stop(&mut self) -> Result<()>;
     fn pause(&mut self) -> Result<()>;
     fn go_home(&mut self, axes: &EnumSet<Axis>) -> Result<()>;
     fn move_relative(&mut self, new_pos: &Position) -> Result<()>;
+    fn disable_steppers(&mut self, axes: &EnumSet<Axis>) -> Result<()>;
     fn set_temperature(&mut self, new_temp: &TemperatureTarget) -> Result<()>;
     fn set_fan_speed(&mut self, new_fan_speed: &FanSpeedTarget) -> Result<()>;
     fn create_external_console(&mut self) -> (Sender<ConsoleMessage>, Receiver<ConsoleMessage>);
     fn get_info(&self) -> Result<PrinterInfo>;
+    fn get_temperature_history(&self, since: Option<f64>) -> Result<Vec<TemperatureSample>>;
+    fn get_layer_durations(&self) -> Result<Vec<LayerDuration>>;
+    fn start_pid_autotune(&mut self, params: &PidAutotuneParams) -> Result<()>;
+    fn apply_pid_autotune(&mut self, save: bool) -> Result<()>;
+    // Reads the mesh the firmware already has, without probing
+    fn read_bed_mesh(&mut self) -> Result<BedMesh>;
+    // Probing takes minutes, its progress shows up in the status
+    fn start_bed_probe(&mut self) -> Result<()>;
+    // The mesh from a probe that finished since the last call, to be saved
+    fn take_probed_mesh(&mut self) -> Option<BedMesh>;
+    fn read_firmware_settings(&mut self) -> Result<FirmwareSettings>;
+    fn write_firmware_settings(&mut self, changes: &FirmwareSettings, save: bool, dry_run: bool) -> Result<Vec<SettingChange>>;
+    fn start_esteps_calibration(&mut self, params: &EStepsCalibrationParams) -> Result<()>;
+    fn submit_esteps_measurement(&mut self, measurement: &EStepsMeasurement) -> Result<()>;
+    fn apply_esteps_calibration(&mut self, save: bool) -> Result<()>;
+    fn cancel_esteps_calibration(&mut self) -> Result<()>;
+    // Jobs that ended since the last call, to be saved in the history
+    fn take_finished_jobs(&mut self) -> Vec<JobRecord>;
 }
 
 struct PrintTimer {
@@ -69,6 +105,60 @@ impl PrintTimer {
     }
 }
 
+// Counts down from the last thing we did to the printer, so heaters aren't left on forever.
+struct IdleTimeout {
+    timeout: Option<Duration>,
+    last_activity: std::time::Instant
+}
+
+impl IdleTimeout {
+    pub fn new(timeout_secs: u64) -> Self {
+        IdleTimeout { timeout: if timeout_secs == 0 {None} else {Some(Duration::from_secs(timeout_secs))}, last_activity: std::time::Instant::now() }
+    }
+
+    pub fn touch(&mut self) {
+        self.last_activity = std::time::Instant::now();
+    }
+
+    // Time left before we should shut down, if the printer is idle with heaters on
+    pub fn remaining(&self, state: PrintState, temperatures: &[Temperature]) -> Option<Duration> {
+        let timeout = self.timeout?;
+
+        if !state.is_idle() || !temperatures.iter().any(|t| t.target > 0.) {
+            return None;
+        }
+        Some(timeout.saturating_sub(self.last_activity.elapsed()))
+    }
+}
+
+// The firmware turns the steppers off after a while without moving, and they lose their position
+struct StepperTimeout {
+    timeout: Option<Duration>,
+    last_move: std::time::Instant
+}
+
+impl StepperTimeout {
+    // Marlin's DEFAULT_STEPPER_DEACTIVE_TIME, until we see M18/M84 S change it
+    const FIRMWARE_DEFAULT: Duration = Duration::from_secs(120);
+
+    pub fn new() -> Self {
+        StepperTimeout { timeout: Some(StepperTimeout::FIRMWARE_DEFAULT), last_move: std::time::Instant::now() }
+    }
+
+    // We only know when moves are sent, not when they finish, so this may run out a bit early. Homing again is the safe side.
+    pub fn moved(&mut self) {
+        self.last_move = std::time::Instant::now();
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn expired(&self) -> bool {
+        self.timeout.is_some_and(|t| self.last_move.elapsed() >= t)
+    }
+}
+
 struct ExternalConsole {
     rx_out: Sender<ConsoleMessage>,
     tx_in: Receiver<ConsoleMessage>,
@@ -190,12 +280,28 @@ pub struct Printer {
     to_print: Option<file::GCodeFile>,
     homed_axes: EnumSet<Axis>,
     temperatures: Vec<Temperature>,
+    temperature_history: TemperatureHistory,
     position: PositionData,
     state: PrintState,
     is_busy: bool,
     print_timer: PrintTimer,
     fan_speeds: Vec<f64>,
     external_console: ExternalConsole,
+    thermal_watchdog: ThermalWatchdog,
+    alerts: Vec<Alert>,
+    idle_timeout: IdleTimeout,
+    stepper_timeout: StepperTimeout,
+    pid_autotune: Option<PidAutotuneStatus>,
+    pending_mesh: Option<PendingMesh>,
+    bed_probe: Option<BedProbeStatus>,
+    bed_probe_started: std::time::Instant,
+    probed_mesh: Option<BedMesh>,
+    profile: PrinterProfile,
+    firmware_settings: Option<FirmwareSettings>,
+    pending_settings: Option<FirmwareSettings>,
+    esteps_calibration: Option<EStepsCalibrationStatus>,
+    unknown_commands: BTreeSet<String>,
+    jobs: JobTracker
 }
 
 impl PrinterControl for Printer {
@@ -236,23 +342,28 @@ impl PrinterControl for Printer {
         };
 
         let time_elapsed = match &self.state {
-            PrintState::STARTED | PrintState::PAUSED | PrintState::DONE => Some(self.print_timer.elapsed()),
-            _=> None
+            PrintState::CONNECTED | PrintState::DEAD => None,
+            _=> Some(self.print_timer.elapsed())
         };
         
         Ok(internal_api::PrinterStatus{ 
             printer_connected: true,
             manual_control_enabled: self.can_move_manually(), 
+            homed_axes: self.homed_axes.iter().collect(),
             state: self.state, 
             temperatures: self.temperatures.clone(), 
             position: self.position.current, 
-            gcode_lines_done_total: match &self.to_print {
-                Some(p) => {Some((p.name().to_string(), p.cur_line_in_file, p.line_count))}
-                None => None
-            },
+            gcode_lines_done_total: self.to_print.as_ref().map(|p| (p.name().to_string(), p.cur_line_in_file, p.line_count)),
             print_time_remaining: time_remaining,
             print_time_elapsed : time_elapsed,
-            fan_speed: self.fan_speeds.clone()
+            fan_speed: self.fan_speeds.clone(),
+            alerts: self.alerts.clone(),
+            idle_timeout_remaining: self.idle_timeout.remaining(self.state, &self.temperatures),
+            pid_autotune: self.pid_autotune.clone(),
+            esteps_calibration: self.esteps_calibration.clone(),
+            bed_probe: self.bed_probe.clone(),
+            layer: self.to_print.as_ref().and_then(|p| p.get_layer_progress()),
+            last_job: self.jobs.last().cloned()
         })
     }
 
@@ -260,14 +371,16 @@ impl PrinterControl for Printer {
         return self.state;
     }
     
-    fn set_gcode_file(&mut self, abs_path: &PathBuf) -> Result<()> {
-        if self.state != PrintState::CONNECTED && self.state != PrintState::DONE {
-            return Err(Error::new(std::io::ErrorKind::InvalidInput, format!("Cannot set gcode file in this state ({:?})!", self.state)));
+    fn set_gcode_file(&mut self, abs_path: &Path, name: &str) -> Result<()> {
+        if !self.state.is_idle() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Cannot set gcode file in this state ({:?})!", self.state)));
         }
        
-        match file::GCodeFile::new(&abs_path) {
+        let limits = self.firmware_settings.as_ref().map(MotionLimits::from).unwrap_or_default();
+        match file::GCodeFile::new(abs_path, &limits) {
 
-            Ok(f) => {
+            Ok(mut f) => {
+                f.name = name.to_string();
                 self.to_print = Some(f);
                 self.print_timer = PrintTimer::new();
                 if let Err(e) = self.send_cmd_read_until_response(self.protocol.get_reset_line_no_cmd(0).as_str(), None){
@@ -280,84 +393,105 @@ impl PrinterControl for Printer {
     }
 
     fn clear_gcode_file(&mut self) -> Result<()> {
-        if self.state != PrintState::CONNECTED && self.state != PrintState::DONE {
-            return Err(Error::new(std::io::ErrorKind::InvalidInput, format!("Cannot clear gcode file in this state ({:?})!", self.state)));
+        if !self.state.is_idle() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Cannot clear gcode file in this state ({:?})!", self.state)));
         }
 
         self.to_print = None;
         Ok(())
     }
 
-    fn start(&mut self) -> Result<()> {
-        if self.state != PrintState::CONNECTED && self.state != PrintState::PAUSED && self.state != PrintState::DONE {
-            return Err(Error::new(std::io::ErrorKind::InvalidInput, format!("Printer cannot be started from this state ({:?})!", self.state)));
+    fn start(&mut self, force: bool) -> Result<()> {
+        if !self.state.is_idle() && self.state != PrintState::PAUSED {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Printer cannot be started from this state ({:?})!", self.state)));
         }
         
-        if self.state == PrintState::CONNECTED && self.to_print.is_none() {
-            return Err(Error::new(std::io::ErrorKind::InvalidInput, format!("GCode file not loaded.")));
+        if self.to_print.is_none() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, "GCode file not loaded."));
         }
-        
-        if self.to_print.is_some() && self.to_print.as_ref().unwrap().command_line_no != 0 && self.state != PrintState::PAUSED {
-            let current_gcode_file_path = self.to_print.as_ref().unwrap().path.clone();
-            self.print_timer.skip();
-            if let Err(e) = self.set_gcode_file(&current_gcode_file_path) {
+
+        if self.is_pid_autotuning() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, "Cannot start printing while PID autotune is running."));
+        }
+
+        if self.is_bed_probing() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, "Cannot start printing while the bed is being probed."));
+        }
+
+        if self.esteps_calibration.is_some() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, "Cannot start printing while the extruder is being calibrated."));
+        }
+
+        if self.state == PrintState::PAUSED {
+            self.transition_state(PrintState::RESUMING)?;
+            if let Err(e) = self.send_resume_cmds() {
+                self.fail_print(format!("Resuming failed: {}", e));
                 return Err(e);
             }
+            self.transition_state(PrintState::STARTED)?;
+            return Ok(());
         }
 
-        if self.state == PrintState::PAUSED {
-            send_series_of_cmds_read_until_response!(self,
-                self.protocol.get_set_position_mode(&PositionMode::ABSOLUTE, &PositionMode::ABSOLUTE), 
-                self.protocol.get_move_cmds(&self.position.saved, false),
-                self.protocol.get_recover_extruder_cmd(),
-                self.protocol.get_set_position_mode(&self.position.move_mode_xyz_e.0, &self.position.move_mode_xyz_e.1));
+        if !force {
+            print_validation::validation_result(&self.validate_print()?)?;
         }
         
-        self.transition_state(PrintState::STARTED);
+        if self.to_print.as_ref().unwrap().command_line_no != 0 {
+            let current_gcode_file_path = self.to_print.as_ref().unwrap().path.clone();
+            let current_gcode_file_name = self.to_print.as_ref().unwrap().name.clone();
+            self.print_timer.skip();
+            self.set_gcode_file(&current_gcode_file_path, &current_gcode_file_name)?;
+        }
+
+        self.transition_state(PrintState::STARTED)?;
+        let printer_name = self.comms.fw_info.get("MACHINE_TYPE").or(self.comms.fw_info.get("FIRMWARE_NAME")).cloned().unwrap_or_default();
+        if let Some(file) = &self.to_print {
+            self.jobs.begin(file, &printer_name);
+        }
         Ok(())
     }
 
     fn stop(&mut self) -> Result<()> {
-        if self.state != PrintState::STARTED && self.state != PrintState::DONE && self.state != PrintState::PAUSED {
-            return Err(Error::new(std::io::ErrorKind::InvalidInput, format!("Printer cannot be stopped from this state ({:?})!", self.state)));
+        if self.state.is_printing() {
+            self.transition_state(PrintState::CANCELLING)?;
+            self.jobs.finish(JobOutcome::CANCELLED, None, self.to_print.as_ref(), self.print_timer.elapsed());
+            // If this fails the printer is gone, there's no cancelling it
+            self.send_stop_cmds()?;
+            return self.transition_state(PrintState::CANCELLED);
         }
 
-        if self.is_busy {
-            send_series_of_cmds_read_until_response!(self, self.protocol.get_stop_cmd(false));
+        // After a print, this turns everything off and gets ready for the next one
+        if matches!(self.state, PrintState::COMPLETED | PrintState::CANCELLED | PrintState::ERROR) {
+            self.send_stop_cmds()?;
+            return self.transition_state(PrintState::CONNECTED);
         }
 
-        send_series_of_cmds_read_until_response!(self,
-            self.protocol.get_fan_speed_cmd(0, 0.)
-        );
-
-        let res = self.disable_all_heaters();
-
-        self.transition_state(PrintState::CONNECTED);
-
-        if !res.is_ok() {
-            return res;
+        if self.is_calibrating() {
+            return self.send_stop_cmds();
         }
-        Ok(())
+
+        Err(coded_error(ErrorCode::INVALIDSTATE, format!("Printer cannot be stopped from this state ({:?})!", self.state)))
     }
 
     fn pause(&mut self) -> Result<()> {
         if self.state != PrintState::STARTED {
-            return Err(Error::new(std::io::ErrorKind::InvalidInput, format!("Printer cannot be paused from this state ({:?})!", self.state)));
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Printer cannot be paused from this state ({:?})!", self.state)));
         }
         self.print_timer.update();
+        self.transition_state(PrintState::PAUSING)?;
         
-        send_series_of_cmds_read_until_response!(self,
-            self.protocol.get_report_position_cmd(), 
-            self.protocol.get_retract_extruder_cmd()
-        );
+        if let Err(e) = self.send_pause_cmds() {
+            self.fail_print(format!("Pausing failed: {}", e));
+            return Err(e);
+        }
 
-        self.transition_state(PrintState::PAUSED);
+        self.transition_state(PrintState::PAUSED)?;
         Ok(())
     }
 
     fn go_home(&mut self, axes: &EnumSet<Axis>) -> Result<()> {
-        if !matches!(self.state, PrintState::CONNECTED |  PrintState::DONE | PrintState::PAUSED) {
-            return Err(Error::new(std::io::ErrorKind::InvalidInput, format!("Printer cannot be homed from this state ({:?})!", self.state)));
+        if !self.state.is_idle() && self.state != PrintState::PAUSED {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Printer cannot be homed from this state ({:?})!", self.state)));
         }
         
         for cmd in self.protocol.get_home_cmds(&axes) {
@@ -366,8 +500,8 @@ impl PrinterControl for Printer {
             }
         }
 
-        if self.state == PrintState::DONE {
-            self.transition_state(PrintState::CONNECTED);
+        if matches!(self.state, PrintState::COMPLETED | PrintState::CANCELLED | PrintState::ERROR) {
+            self.transition_state(PrintState::CONNECTED)?;
         }
         
         // The homed axes status of the printer will be updated when we parse the outgoing command
@@ -377,11 +511,11 @@ impl PrinterControl for Printer {
     fn move_relative(&mut self, new_pos: &Position) -> Result<()> {
         const MAX_REL_MOVE: f64 = 20.;
 
-        if !matches!(self.state, PrintState::CONNECTED |  PrintState::DONE | PrintState::PAUSED) {
-            return Err(Error::new(std::io::ErrorKind::InvalidInput, format!("Printer cannot be moved from this state ({:?})!", self.state)));
+        if !self.state.is_idle() && self.state != PrintState::PAUSED {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Printer cannot be moved from this state ({:?})!", self.state)));
         }
         if !self.can_move_manually() {
-            return Err(Error::new(std::io::ErrorKind::InvalidInput, "Printer cannot be moved manually, home it first?"));
+            return Err(coded_error(ErrorCode::INVALIDSTATE, "Printer cannot be moved manually, home it first?"));
         }
 
         if [new_pos.x, new_pos.y, new_pos.z].iter()
@@ -401,19 +535,29 @@ impl PrinterControl for Printer {
         Ok(())
     }
 
+    fn disable_steppers(&mut self, axes: &EnumSet<Axis>) -> Result<()> {
+        if !self.state.is_idle() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Steppers cannot be disabled from this state ({:?})!", self.state)));
+        }
+
+        // The homed axes status of the printer will be updated when we parse the outgoing command
+        self.send_cmd_read_until_response(self.protocol.get_disable_steppers_cmd(axes).as_str(), None)?;
+        Ok(())
+    }
+
     fn set_temperature(&mut self, new_temp: &TemperatureTarget) -> Result<()> {
         new_temp.validate()?;
 
         if matches!(self.state, PrintState::DEAD) {
-            return Err(Error::new(std::io::ErrorKind::InvalidInput, format!("Temperature cannot be modified from this state ({:?})!", self.state)));
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Temperature cannot be modified from this state ({:?})!", self.state)));
         }
 
         if !self.temperatures.iter().map(|t|t.measured_from).any(|p| p == new_temp.to_set) {
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid heater {:?}",new_temp.to_set)));
+            return Err(field_error("to_set", format!("No heater {:?}", new_temp.to_set)));
         }
 
         if new_temp.target > 300. || new_temp.target < 0. {
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid target temperature"));
+            return Err(field_error("target", "Invalid target temperature"));
         }
 
         info!("Set temperatures to: {:?}", new_temp);
@@ -448,12 +592,46 @@ impl PrinterControl for Printer {
             }
             None => {}
         }
+
+        if let Some(fault) = self.thermal_watchdog.check(std::time::Instant::now()) {
+            self.handle_thermal_fault(fault);
+        }
+
+        // Calibrations keep the heaters on on purpose, however long they take
+        if self.is_calibrating() {
+            self.idle_timeout.touch();
+        }
+        if self.idle_timeout.remaining(self.state, &self.temperatures) == Some(Duration::ZERO) {
+            self.handle_idle_timeout();
+        }
+
+        // The firmware keeps the steppers on while printing, even when waiting to heat up
+        if matches!(self.state, PrintState::HEATING | PrintState::STARTED | PrintState::FINISHING) {
+            self.stepper_timeout.moved();
+        } else if !self.homed_axes.is_empty() && self.stepper_timeout.expired() {
+            info!("Firmware turned the steppers off after being idle, axes need homing again");
+            self.homed_axes.clear();
+        }
+
+        self.advance_bed_probe();
+
+        if let Err(e) = self.advance_esteps_calibration() {
+            error!("E-steps calibration failed: {}", e);
+            self.raise_alert(format!("E-steps calibration failed: {}", e));
+            self.esteps_calibration = None;
+        }
         
-        if self.state == PrintState::STARTED {
-            return self.print_next_line();
-        } else {
-            self.poll_new_status();
-            Ok(())
+        match self.state {
+            PrintState::STARTED | PrintState::HEATING => self.print_next_line(),
+            PrintState::FINISHING if !self.is_busy => {
+                self.jobs.finish(JobOutcome::DONE, None, self.to_print.as_ref(), self.print_timer.elapsed());
+                self.transition_state(PrintState::COMPLETED)?;
+                Ok(())
+            }
+            _ => {
+                self.poll_new_status();
+                Ok(())
+            }
         }
     }
 
@@ -465,31 +643,289 @@ impl PrinterControl for Printer {
         Ok(PrinterInfo{values:self.comms.fw_info.clone()})
     }
 
+    fn validate_print(&self) -> Result<Vec<ValidationIssue>> {
+        match &self.to_print {
+            Some(p) => {
+                let limits = PrinterLimits { profile: &self.profile, thermal: self.thermal_watchdog.config(),
+                    fw_info: &self.comms.fw_info, unknown_commands: &self.unknown_commands };
+                Ok(print_validation::validate(&p.metadata, &limits))
+            }
+            None => Err(Error::new(std::io::ErrorKind::NotFound, "GCode file not loaded."))
+        }
+    }
+
+    fn get_temperature_history(&self, since: Option<f64>) -> Result<Vec<TemperatureSample>> {
+        Ok(self.temperature_history.get_since(since, TemperatureHistory::MAX_POINTS_RETURNED))
+    }
+
+    fn get_layer_durations(&self) -> Result<Vec<LayerDuration>> {
+        match &self.to_print {
+            Some(p) => Ok(p.get_layer_durations().clone()),
+            None => Err(Error::new(std::io::ErrorKind::NotFound, "GCode file not loaded."))
+        }
+    }
+
+    fn start_pid_autotune(&mut self, params: &PidAutotuneParams) -> Result<()> {
+        params.validate()?;
+
+        if !self.state.is_idle() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Cannot autotune from this state ({:?})!", self.state)));
+        }
+        if self.is_pid_autotuning() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, "PID autotune is already running."));
+        }
+        if self.is_calibrating() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, "Cannot autotune while a calibration is running."));
+        }
+        if params.cycles < 3 || params.cycles > 20 {
+            return Err(field_error("cycles", "Must be between 3 and 20"));
+        }
+
+        let cmd = self.protocol.get_pid_autotune_cmd(params)?;
+        info!("Starting PID autotune: {:?}", params);
+
+        self.pid_autotune = Some(PidAutotuneStatus{heater: params.heater, index: params.index.unwrap_or(0), target: params.target, cycles: params.cycles,
+            cycles_done: 0, state: PidAutotuneState::RUNNING, result: None});
+        // The printer will stay busy until autotune is done, the rest of the output gets picked up as we poll for status.
+        self.send_cmd_read_until_response(&cmd, None)
+    }
+
+    fn read_bed_mesh(&mut self) -> Result<BedMesh> {
+        // Nothing else gets done while waiting, the printer reports a stored mesh straight away
+        const READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+        if !self.state.is_idle() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Cannot read the bed mesh from this state ({:?})!", self.state)));
+        }
+        if self.is_bed_probing() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, "The bed is being probed, the mesh will be saved when it's done."));
+        }
+        if self.is_busy {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, "The printer is busy, try again when it's done."));
+        }
+
+        self.pending_mesh = Some(PendingMesh::default());
+        let result = self.send_cmd_read_until_response(&self.protocol.get_report_bed_mesh_cmd(), None);
+
+        let timeout_at = std::time::Instant::now() + READ_TIMEOUT;
+        while result.is_ok() && !self.pending_mesh.as_ref().unwrap().is_complete() && std::time::Instant::now() < timeout_at {
+            self.poll_new_status();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        let pending = self.pending_mesh.take().unwrap();
+        result?;
+        if !pending.is_complete() {
+            return Err(Error::new(std::io::ErrorKind::TimedOut, "Timed out waiting for the printer to report the bed mesh."));
+        }
+
+        self.mesh_from_pending(pending)
+        .ok_or(coded_error(ErrorCode::PRINTERERROR, "Printer did not report a bed mesh, is bed leveling enabled?"))
+    }
+
+    fn start_bed_probe(&mut self) -> Result<()> {
+        if !self.state.is_idle() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Cannot probe the bed from this state ({:?})!", self.state)));
+        }
+        if self.is_calibrating() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, "Cannot probe the bed while a calibration is running."));
+        }
+
+        let mut cmds = Vec::<String>::new();
+        if !self.homed_axes.is_superset(enum_set!(Axis::X | Axis::Y | Axis::Z)) {
+            cmds.append(&mut self.protocol.get_home_cmds(&enum_set!(Axis::X | Axis::Y | Axis::Z)));
+        }
+        cmds.append(&mut self.protocol.get_probe_bed_cmds());
+        cmds.push(self.protocol.get_report_bed_mesh_cmd());
+
+        info!("Probing the bed");
+        self.pending_mesh = Some(PendingMesh::default());
+        self.bed_probe = Some(BedProbeStatus{state: BedProbeState::RUNNING, points_probed: 0, secs_elapsed: 0});
+        self.bed_probe_started = std::time::Instant::now();
+        // The printer stays busy while probing, the grid gets picked up as we poll for status.
+        if let Err(e) = self.send_cmds_read_until_response(&cmds, None) {
+            self.fail_bed_probe(e.to_string());
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn take_probed_mesh(&mut self) -> Option<BedMesh> {
+        self.probed_mesh.take()
+    }
+
+    fn read_firmware_settings(&mut self) -> Result<FirmwareSettings> {
+        if matches!(self.state, PrintState::HEATING | PrintState::STARTED | PrintState::FINISHING) {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Cannot read firmware settings from this state ({:?})!", self.state)));
+        }
+
+        self.pending_settings = Some(FirmwareSettings::default());
+        let result = self.send_cmd_read_until_response(self.protocol.get_report_settings_cmd().as_str(), None);
+        let settings = self.pending_settings.take().unwrap();
+        result?;
+
+        if settings.is_empty() {
+            return Err(coded_error(ErrorCode::PRINTERERROR, "Printer did not report any settings."));
+        }
+        self.firmware_settings = Some(settings.clone());
+        Ok(settings)
+    }
+
+    fn write_firmware_settings(&mut self, changes: &FirmwareSettings, save: bool, dry_run: bool) -> Result<Vec<SettingChange>> {
+        changes.validate()?;
+
+        if matches!(self.state, PrintState::HEATING | PrintState::STARTED | PrintState::FINISHING) {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Cannot change firmware settings from this state ({:?})!", self.state)));
+        }
+
+        let current = match &self.firmware_settings {
+            Some(settings) => settings.clone(),
+            None => self.read_firmware_settings()?
+        };
+        let new = current.merged(changes);
+        let diff = current.diff(&new);
+
+        if dry_run || diff.is_empty() {
+            return Ok(diff);
+        }
+
+        let mut cmds = self.protocol.get_write_settings_cmds(&current, &new);
+        if save {
+            cmds.push(self.protocol.get_save_settings_cmd());
+        }
+        info!("Writing firmware settings: {:?}", cmds);
+        self.send_cmds_read_until_response(&cmds, None)?;
+
+        // Read them back, so we know what the printer actually accepted
+        self.read_firmware_settings()?;
+        Ok(diff)
+    }
+
+    fn start_esteps_calibration(&mut self, params: &EStepsCalibrationParams) -> Result<()> {
+        params.validate()?;
+
+        if !self.state.is_idle() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Cannot calibrate the extruder from this state ({:?})!", self.state)));
+        }
+        if self.is_calibrating() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, "Cannot calibrate the extruder while a calibration is running."));
+        }
+        if params.length < 10. || params.length > 200. {
+            return Err(field_error("length", "Must be between 10 and 200mm"));
+        }
+
+        let settings = match &self.firmware_settings {
+            Some(settings) => settings.clone(),
+            None => self.read_firmware_settings()?
+        };
+        let current_steps = match settings.steps_per_mm.get(&'E') {
+            Some(steps) => *steps,
+            None => {return Err(coded_error(ErrorCode::PRINTERERROR, "Printer did not report its extruder steps per mm"));}
+        };
+
+        self.set_temperature(&TemperatureTarget{to_set: ProbePoint::HOTEND, index: Some(0), target: params.temperature})?;
+        self.esteps_calibration = Some(EStepsCalibrationStatus::new(params, current_steps));
+        info!("Started e-steps calibration: {:?}", self.esteps_calibration);
+        Ok(())
+    }
+
+    fn submit_esteps_measurement(&mut self, measurement: &EStepsMeasurement) -> Result<()> {
+        match self.esteps_calibration.as_mut() {
+            Some(calibration) => {
+                calibration.measured(measurement.mark_distance.unwrap_or(calibration.length + 20.), measurement.remaining)
+            }
+            None => Err(coded_error(ErrorCode::INVALIDSTATE, "E-steps calibration not started."))
+        }
+    }
+
+    fn apply_esteps_calibration(&mut self, save: bool) -> Result<()> {
+        let new_steps = match &self.esteps_calibration {
+            Some(EStepsCalibrationStatus{step: EStepsCalibrationStep::DONE, new_steps_per_mm: Some(steps), ..}) => *steps,
+            _ => {return Err(coded_error(ErrorCode::INVALIDSTATE, "No finished e-steps calibration to apply."));}
+        };
+
+        let mut changes = FirmwareSettings::default();
+        changes.steps_per_mm.insert('E', new_steps);
+        self.write_firmware_settings(&changes, save, false)?;
+
+        self.esteps_calibration = None;
+        Ok(())
+    }
+
+    fn cancel_esteps_calibration(&mut self) -> Result<()> {
+        if self.esteps_calibration.take().is_none() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, "E-steps calibration not started."));
+        }
+
+        if self.is_busy {
+            self.send_cmd_read_until_response(self.protocol.get_stop_cmd(false).as_str(), None)?;
+        }
+        self.set_temperature(&TemperatureTarget{to_set: ProbePoint::HOTEND, index: Some(0), target: 0.})
+    }
+
+    fn take_finished_jobs(&mut self) -> Vec<JobRecord> {
+        self.jobs.take_finished()
+    }
+
+    fn apply_pid_autotune(&mut self, save: bool) -> Result<()> {
+        let (heater, index, values) = match &self.pid_autotune {
+            Some(PidAutotuneStatus{heater, index, state: PidAutotuneState::FINISHED, result: Some(values), ..}) => {(*heater, *index, *values)}
+            _ => {
+                return Err(coded_error(ErrorCode::INVALIDSTATE, "No finished PID autotune result to apply."));
+            }
+        };
+
+        let mut cmds = self.protocol.get_set_pid_cmds(heater, index, &values)?;
+        if save {
+            cmds.push(self.protocol.get_save_settings_cmd());
+        }
+
+        info!("Applying PID values {:?} to {:?} {}", values, heater, index);
+        self.send_cmds_read_until_response(&cmds, None)
+    }
 
 }
 
 impl Printer {
-    pub fn new(comms:PrinterComms) -> Result<Self> {
+    const MAX_ALERTS: usize = 20;
+
+    pub fn new(comms:PrinterComms, config: &Config) -> Result<Self> {
         if let Some(fw) = comms.fw_info.get("FIRMWARE_NAME") {
             if fw.to_lowercase().contains("marlin") {
                 let mut ret_printer = Printer{comms, protocol:Box::new(marlin::Marlin{}), to_print: None, state: PrintState::CONNECTED,
-                homed_axes:EnumSet::new(), temperatures: Vec::new(), position: PositionData::default(),
+                homed_axes:EnumSet::new(), temperatures: Vec::new(), temperature_history: TemperatureHistory::new(),
+                position: PositionData::default(),
                 is_busy: false,
                 print_timer: PrintTimer::new(),
-                fan_speeds: vec![0.], external_console: ExternalConsole::new()};
+                fan_speeds: vec![0.], external_console: ExternalConsole::new(),
+                thermal_watchdog: ThermalWatchdog::new(config.thermal.clone()),
+                alerts: Vec::new(),
+                idle_timeout: IdleTimeout::new(config.idle.timeout_secs),
+                stepper_timeout: StepperTimeout::new(),
+                pid_autotune: None,
+                pending_mesh: None,
+                bed_probe: None,
+                bed_probe_started: std::time::Instant::now(),
+                probed_mesh: None,
+                profile: config.printer.clone(),
+                firmware_settings: None,
+                pending_settings: None,
+                esteps_calibration: None,
+                unknown_commands: BTreeSet::new(),
+                jobs: JobTracker::default()};
 
                 for cmd in ret_printer.protocol.get_enable_temperature_updates_cmds(std::time::Duration::from_secs(2)) {
                     if let Err(e) = ret_printer.send_cmd_read_until_response(cmd.as_str(), None) {
-                        return Err(Error::new(std::io::ErrorKind::InvalidData, format!("Error probing initial temperatures: {e}")));
+                        return Err(coded_error(ErrorCode::PRINTERERROR, format!("Error probing initial temperatures: {e}")));
                     }
                 }
                 return Ok(ret_printer);
             } else {
-                return Err(Error::new(std::io::ErrorKind::InvalidData, "Unsupported firmware type."));
+                return Err(coded_error(ErrorCode::PRINTERERROR, "Unsupported firmware type."));
             }
         }
             
-        return Err(Error::new(std::io::ErrorKind::InvalidData, "Cannot find firmware type"));
+        Err(coded_error(ErrorCode::PRINTERERROR, "Cannot find firmware type"))
     }
 
     fn print_next_line(&mut self) -> std::io::Result<()> {
@@ -501,11 +937,11 @@ impl Printer {
         }
         
         if self.to_print.is_none() {
-            self.transition_state(PrintState::DEAD);
+            self.transition_state(PrintState::DEAD)?;
             return Err(Error::new(std::io::ErrorKind::NotFound, "No file to print!"));
         }
-        if self.state != PrintState::STARTED {
-            return Err(Error::new(std::io::ErrorKind::NotFound, format!("Printer is not in {:?} state ({:?})!", PrintState::STARTED, self.state)));
+        if !matches!(self.state, PrintState::STARTED | PrintState::HEATING) {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Printer is not in {:?} state ({:?})!", PrintState::STARTED, self.state)));
         }
         
         let (next_line_no, cmd) = 
@@ -515,8 +951,18 @@ impl Printer {
         };
 
         if cmd.len() == 0 {
-            self.transition_state(PrintState::DONE);
-            return Ok(());
+            self.to_print.as_mut().unwrap().finish_layer(self.print_timer.elapsed());
+            // Done once the printer has caught up with everything we sent
+            self.transition_state(PrintState::FINISHING)?;
+            return self.send_cmd_read_until_response(self.protocol.get_wait_for_moves_cmd().as_str(), None);
+        }
+        self.to_print.as_mut().unwrap().update_layer(self.print_timer.elapsed());
+
+        // The printer won't take the next line until it's done waiting
+        if is_heat_and_wait_cmd(&cmd) {
+            self.transition_state(PrintState::HEATING)?;
+        } else if self.state == PrintState::HEATING {
+            self.transition_state(PrintState::STARTED)?;
         }
 
         self.send_cmd_read_until_response(&cmd, Some(next_line_no))
@@ -533,7 +979,7 @@ impl Printer {
                             break;
                         }
                         serial::Response::OK => {
-                            self.is_busy = false;
+                            self.got_ok();
                             break;
                         }
                         serial::Response::NONE => {break;}
@@ -547,30 +993,250 @@ impl Printer {
         }
     }
 
-    fn transition_state(&mut self, new_state: PrintState) -> bool {
+    fn transition_state(&mut self, new_state: PrintState) -> Result<()> {
         if new_state == self.state {
-            return true;
+            return Ok(());
+        }
+        if !self.state.can_transition_to(new_state) {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Illegal printer state transition: {:?} -> {:?}", self.state, new_state)));
         }
         info!("Printer state transition: {:?} -> {:?}", self.state, new_state);
+        // Anything that ends a print on purpose records why before getting here
+        if self.jobs.is_active() && !new_state.is_printing() {
+            self.jobs.finish(JobOutcome::FAILED, Some(format!("Printer went from {:?} to {:?}", self.state, new_state)),
+                self.to_print.as_ref(), self.print_timer.elapsed());
+        }
         self.state = new_state;
+        self.idle_timeout.touch();
+        Ok(())
+    }
 
-        return true;
+    // Something went wrong with the print itself, give up on it
+    fn fail_print(&mut self, reason: String) {
+        if self.state.is_printing() {
+            self.raise_alert(format!("Print failed: {}", reason));
+            self.jobs.finish(JobOutcome::FAILED, Some(reason), self.to_print.as_ref(), self.print_timer.elapsed());
+            if let Err(e) = self.transition_state(PrintState::ERROR) {
+                error!("{}", e);
+            }
+        }
+    }
+
+    fn send_pause_cmds(&mut self) -> Result<()> {
+        send_series_of_cmds_read_until_response!(self,
+            self.protocol.get_report_position_cmd(), 
+            self.protocol.get_retract_extruder_cmd()
+        );
+        Ok(())
+    }
+
+    fn send_resume_cmds(&mut self) -> Result<()> {
+        send_series_of_cmds_read_until_response!(self,
+            self.protocol.get_set_position_mode(&PositionMode::ABSOLUTE, &PositionMode::ABSOLUTE), 
+            self.protocol.get_move_cmds(&self.position.saved, false),
+            self.protocol.get_recover_extruder_cmd(),
+            self.protocol.get_set_position_mode(&self.position.move_mode_xyz_e.0, &self.position.move_mode_xyz_e.1));
+        Ok(())
+    }
+
+    // Interrupt whatever the printer is doing and turn off the fans and heaters
+    fn send_stop_cmds(&mut self) -> Result<()> {
+        self.interrupt_pid_autotune();
+        self.fail_bed_probe("interrupted".to_string());
+        self.interrupt_esteps_calibration();
+        if self.is_busy {
+            send_series_of_cmds_read_until_response!(self, self.protocol.get_stop_cmd(false));
+        }
+
+        send_series_of_cmds_read_until_response!(self,
+            self.protocol.get_fan_speed_cmd(0, 0.)
+        );
+
+        self.disable_all_heaters()
     }
 
     fn update_status_from_response(&mut self, resp: &serial::Response) {
         match resp {
             serial::Response::TEMPERATURE(temp, _residency)  => {
                 self.temperatures = temp.clone();
+                self.temperature_history.add(temp);
+                self.thermal_watchdog.on_report(temp, matches!(self.state, PrintState::HEATING | PrintState::STARTED | PrintState::FINISHING), std::time::Instant::now());
             }
             serial::Response::POSITION(pos) => {
                 self.position.current = pos.clone();
-            } 
+            }
+            serial::Response::AUTOTUNE(update) => {
+                self.update_pid_autotune(update);
+            }
+            serial::Response::SETTING(group, extruder, values) => {
+                if let Some(settings) = self.pending_settings.as_mut() {
+                    settings.extruder_mut(*extruder).group_mut(group).unwrap().extend(values.iter());
+                }
+            }
+            serial::Response::ERROR(message) => {
+                error!("Printer reported an error: {}", message);
+                self.interrupt_pid_autotune();
+                self.fail_bed_probe("interrupted".to_string());
+            }
+            serial::Response::UNKNOWN(cmd) => {
+                warn!("Firmware doesn't know the command {}", cmd);
+                self.unknown_commands.insert(cmd.clone());
+            }
+            serial::Response::MESH(line) => {
+                match (self.pending_mesh.as_mut(), line) {
+                    (Some(mesh), MeshLine::Row(idx, values)) => {mesh.add_row(*idx, values.clone());}
+                    (Some(mesh), MeshLine::ProbePoint(x, y, z)) => {mesh.add_probe_point(*x, *y, *z);}
+                    (None, _) => {debug!("Ignoring mesh output: {:?}", line);}
+                }
+            }
             _ => {}
         }
-    } 
+    }
+
+    // Move the e-steps calibration along once the printer is ready for the next step
+    fn advance_esteps_calibration(&mut self) -> Result<()> {
+        const TEMPERATURE_TOLERANCE: f64 = 2.;
+
+        let (step, temperature, length, feedrate) = match &self.esteps_calibration {
+            Some(c) => (c.step.clone(), c.temperature, c.length, c.feedrate),
+            None => {return Ok(());}
+        };
+        // Extruding in the middle of a print would ruin it
+        if !self.state.is_idle() {
+            return Ok(());
+        }
+
+        match step {
+            EStepsCalibrationStep::HEATING => {
+                let hotend = self.temperatures.iter().find(|t| t.measured_from == ProbePoint::HOTEND && t.index == 0);
+                if hotend.is_some_and(|t| t.current >= temperature - TEMPERATURE_TOLERANCE) {
+                    info!("Hotend at temperature, extruding {}mm for e-steps calibration", length);
+                    self.esteps_calibration.as_mut().unwrap().step = EStepsCalibrationStep::EXTRUDING;
+
+                    // Sending the mode change updates move_mode_xyz_e, so keep the mode to go back to
+                    let (mode_xyz, mode_e) = self.position.move_mode_xyz_e;
+                    send_series_of_cmds_read_until_response!(self,
+                        self.protocol.get_set_position_mode(&mode_xyz, &PositionMode::RELATIVE),
+                        self.protocol.get_extrude_cmd(length, feedrate),
+                        self.protocol.get_set_position_mode(&mode_xyz, &mode_e),
+                        self.protocol.get_wait_for_moves_cmd());
+                }
+            }
+            EStepsCalibrationStep::EXTRUDING if !self.is_busy => {
+                self.esteps_calibration.as_mut().unwrap().step = EStepsCalibrationStep::MEASURING;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn got_ok(&mut self) {
+        self.is_busy = false;
+        if let Some(mesh) = self.pending_mesh.as_mut() {
+            mesh.got_ok();
+        }
+    }
+
+    fn update_pid_autotune(&mut self, update: &AutotuneUpdate) {
+        let autotune = match self.pid_autotune.as_mut() {
+            Some(a) => a,
+            None => {
+                warn!("Got autotune output without starting autotune: {:?}", update);
+                return;
+            }
+        };
+
+        match update {
+            AutotuneUpdate::Started => {}
+            AutotuneUpdate::CycleDone => {
+                autotune.cycles_done += 1;
+            }
+            AutotuneUpdate::Values(values) => {
+                autotune.result = Some(*values);
+            }
+            AutotuneUpdate::Finished => {
+                info!("PID autotune finished: {:?}", autotune.result);
+                autotune.state = if autotune.result.is_some() {PidAutotuneState::FINISHED} else {PidAutotuneState::FAILED("No PID values reported".to_string())};
+            }
+            AutotuneUpdate::Failed(reason) => {
+                error!("PID autotune failed: {}", reason);
+                autotune.state = PidAutotuneState::FAILED(reason.clone());
+            }
+        }
+    }
+
+    fn is_pid_autotuning(&self) -> bool {
+        self.pid_autotune.as_ref().is_some_and(|a| a.state == PidAutotuneState::RUNNING)
+    }
+
+    // The firmware only tells us when autotune ends by itself, anything else that stops it ends up here
+    fn interrupt_pid_autotune(&mut self) {
+        if let Some(autotune) = self.pid_autotune.as_mut().filter(|a| a.state == PidAutotuneState::RUNNING) {
+            warn!("PID autotune interrupted");
+            autotune.state = PidAutotuneState::FAILED("interrupted".to_string());
+        }
+    }
+
+    // Once the heaters go off the calibration can't carry on, it has to be started again
+    fn interrupt_esteps_calibration(&mut self) {
+        if self.esteps_calibration.take().is_some() {
+            warn!("E-steps calibration interrupted");
+        }
+    }
+
+    fn is_bed_probing(&self) -> bool {
+        self.bed_probe.as_ref().is_some_and(|p| p.state == BedProbeState::RUNNING)
+    }
+
+    fn fail_bed_probe(&mut self, reason: String) {
+        if let Some(probe) = self.bed_probe.as_mut().filter(|p| p.state == BedProbeState::RUNNING) {
+            warn!("Bed probing failed: {}", reason);
+            probe.state = BedProbeState::FAILED(reason);
+            self.pending_mesh = None;
+        }
+    }
+
+    // Probing carries on while we do other things, this checks whether the grid has shown up yet
+    fn advance_bed_probe(&mut self) {
+        const PROBE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+        if !self.is_bed_probing() {
+            return;
+        }
+        let (complete, points_probed) = match self.pending_mesh.as_ref() {
+            Some(pending) => (pending.is_complete(), pending.probe_point_count()),
+            None => (false, 0)
+        };
+        let elapsed = self.bed_probe_started.elapsed();
+        if let Some(probe) = self.bed_probe.as_mut() {
+            probe.points_probed = points_probed;
+            probe.secs_elapsed = elapsed.as_secs();
+        }
+
+        if complete {
+            let pending = self.pending_mesh.take().unwrap();
+            match self.mesh_from_pending(pending) {
+                Some(mesh) => {
+                    info!("Bed probing finished, {} points", points_probed);
+                    self.probed_mesh = Some(mesh);
+                    self.bed_probe.as_mut().unwrap().state = BedProbeState::FINISHED;
+                }
+                None => self.fail_bed_probe("Printer did not report a bed mesh, is bed leveling enabled?".to_string())
+            }
+        } else if elapsed > PROBE_TIMEOUT {
+            self.fail_bed_probe("Timed out waiting for the printer to report the bed mesh.".to_string());
+        }
+    }
+
+    fn mesh_from_pending(&self, pending: PendingMesh) -> Option<BedMesh> {
+        let inset = self.profile.mesh_inset;
+        pending.into_mesh(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs(),
+            (inset, self.profile.bed_size_x - inset), (inset, self.profile.bed_size_y - inset))
+    }
 
     fn send_cmd_read_until_response(&mut self, cmd: &str, line_no: Option<u32>) -> std::io::Result<()> {
         debug!("Send command: {}", cmd);
+        self.idle_timeout.touch();
         
         if let Some(tapped_cmd) = self.protocol.parse_outgoing_cmd(&cmd) {
             match tapped_cmd {
@@ -594,8 +1260,26 @@ impl Printer {
                 },
                 OutgoingCmd::HomeAxes(axes) => {
                     self.homed_axes |= axes;
+                    self.stepper_timeout.moved();
+                },
+                OutgoingCmd::EnableSteppers(_) => {
+                    self.stepper_timeout.moved();
+                },
+                OutgoingCmd::SetStepperTimeout(timeout) => {
+                    info!("Firmware stepper timeout set to {:?}", timeout);
+                    self.stepper_timeout.set_timeout(timeout);
+                    self.stepper_timeout.moved();
+                },
+                OutgoingCmd::Interrupt => {
+                    self.interrupt_pid_autotune();
+                },
+                OutgoingCmd::DisableSteppers(axes) => {
+                    // Once a stepper is off, its position is lost
+                    info!("Steppers disabled: {:?}", axes);
+                    self.homed_axes -= axes;
                 },
                 OutgoingCmd::PositionChange(new_pos) => {
+                    self.stepper_timeout.moved();
                     if self.position.move_mode_xyz_e.0 == PositionMode::RELATIVE {
                         self.position.current.x += new_pos.x;
                         self.position.current.y += new_pos.y;
@@ -622,7 +1306,7 @@ impl Printer {
         };
 
         if let Err(e) = self.send_to_printer(&to_send) {
-            self.transition_state(PrintState::DEAD);
+            self.transition_state(PrintState::DEAD)?;
             return Err(e);
         }
 
@@ -639,16 +1323,19 @@ impl Printer {
                             break;
                         }
                         serial::Response::OK => {
-                            self.is_busy = false;
+                            self.got_ok();
                             break;
                         }
                         serial::Response::NACK(line) => {
                             self.is_busy = false;
                             if self.to_print.is_none() {
-                                self.transition_state(PrintState::DEAD);
-                                return Err(Error::new(std::io::ErrorKind::InvalidData, format!("The printer is requesting a resend of line {}, but we don't have a loaded GCODE file?", line)));
+                                self.transition_state(PrintState::DEAD)?;
+                                return Err(coded_error(ErrorCode::PRINTERERROR, format!("The printer is requesting a resend of line {}, but we don't have a loaded GCODE file?", line)));
+                            }
+                            if let Err(e) = self.to_print.as_mut().unwrap().resend_gcode_line(line) {
+                                self.fail_print(format!("Cannot resend line {}: {}", line, e));
+                                break;
                             }
-                            self.to_print.as_mut().unwrap().resend_gcode_line(line);
                         }
                         _ => {self.update_status_from_response(&resp);}
                     }
@@ -658,7 +1345,7 @@ impl Printer {
                         warn!("Ignoring unparseable line. {}", e);
                         continue;
                     }
-                    self.transition_state(PrintState::DEAD);
+                    self.transition_state(PrintState::DEAD)?;
                     return Err(e);
                 }
             }
@@ -678,6 +1365,13 @@ impl Printer {
         Ok(())
     }
 
+    // Waiting on the user to measure the filament doesn't count, the heater shouldn't stay on if they never come back
+    fn is_calibrating(&self) -> bool {
+        self.is_pid_autotuning()
+        || self.is_bed_probing()
+        || self.esteps_calibration.as_ref().is_some_and(|c| matches!(c.step, EStepsCalibrationStep::HEATING | EStepsCalibrationStep::EXTRUDING))
+    }
+
     fn can_move_manually(&self) -> bool {
         self.homed_axes.is_superset(enum_set!(Axis::X | Axis::Y | Axis::Z)) || self.state == PrintState::PAUSED
     }
@@ -691,13 +1385,68 @@ impl Printer {
         Ok(())
     }
 
+    fn raise_alert(&mut self, message: String) {
+        if self.alerts.len() >= Printer::MAX_ALERTS {
+            self.alerts.remove(0);
+        }
+        self.alerts.push(Alert{secs_since_epoch: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs(), message});
+    }
+
+    // Something is wrong with the heaters, turn everything off and stop printing.
+    fn handle_thermal_fault(&mut self, fault: ThermalFault) {
+        error!("Thermal fault: {}", fault);
+        self.raise_alert(format!("Thermal safety shutdown: {}", fault));
+
+        if self.is_busy {
+            // Break out of any M109/M190 wait so the heater commands get through
+            if let Err(e) = self.send_cmd_read_until_response(self.protocol.get_stop_cmd(false).as_str(), None) {
+                error!("Error interrupting printer: {}", e);
+            }
+        }
+
+        self.interrupt_pid_autotune();
+        self.interrupt_esteps_calibration();
+        if let Err(e) = self.disable_all_heaters() {
+            error!("Error disabling heaters after thermal fault: {}", e);
+        }
+
+        if self.state.is_printing() {
+            self.jobs.finish(JobOutcome::FAILED, Some(format!("Thermal safety shutdown: {}", fault)), self.to_print.as_ref(), self.print_timer.elapsed());
+            if let Err(e) = self.transition_state(PrintState::ERROR) {
+                error!("{}", e);
+            }
+        }
+        self.thermal_watchdog.reset();
+    }
+
+    // Nobody has touched the printer in a while but the heaters are still on, shut everything down.
+    fn handle_idle_timeout(&mut self) {
+        info!("Printer idle for too long, turning off heaters, fans and steppers.");
+        self.raise_alert("Idle timeout: heaters, fans and steppers turned off".to_string());
+
+        if let Err(e) = self.disable_all_heaters() {
+            error!("Error disabling heaters after idle timeout: {}", e);
+        }
+
+        let mut cmds : Vec<String> = (0..self.fan_speeds.len()).map(|idx| self.protocol.get_fan_speed_cmd(idx as u32, 0.)).collect();
+        cmds.push(self.protocol.get_disable_steppers_cmd(&EnumSet::all()));
+        if let Err(e) = self.send_cmds_read_until_response(&cmds, None) {
+            error!("Error turning off fans and steppers after idle timeout: {}", e);
+        }
+    }
 }
 
 
+// Commands that keep the printer busy until a heater gets to temperature
+fn is_heat_and_wait_cmd(cmd: &str) -> bool {
+    matches!(cmd.split_whitespace().next(), Some("M109" | "M190" | "M191" | "M116"))
+}
+
 pub struct SimulatedPrinter {
     to_print: Option<file::GCodeFile>,
     homed_axes: EnumSet<Axis>,
     temperatures: Vec<Temperature>,
+    temperature_history: TemperatureHistory,
     position: PositionData,
     state: PrintState,
     last_line_at : std::time::Instant,
@@ -705,24 +1454,62 @@ pub struct SimulatedPrinter {
     print_timer: PrintTimer,
     gcode_send_interval:std::time::Duration,
     fan_speeds: Vec<f64>,
-    external_console : ExternalConsole
+    external_console : ExternalConsole,
+    idle_timeout: IdleTimeout,
+    pid_autotune: Option<PidAutotuneStatus>,
+    bed_probe: Option<BedProbeStatus>,
+    probed_mesh: Option<BedMesh>,
+    firmware_settings: FirmwareSettings,
+    esteps_calibration: Option<EStepsCalibrationStatus>,
+    profile: PrinterProfile,
+    thermal: ThermalConfig,
+    jobs: JobTracker
 }
 
 
 impl SimulatedPrinter {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         let init_temps = vec![Temperature{ measured_from: internal_api::ProbePoint::HOTEND, index: 0, power: 0., current: 25.0, target: 25.0 },
                                                 Temperature{ measured_from: internal_api::ProbePoint::BED, index: 0, power: 0., current: 21.0, target: 21.0 }];
         SimulatedPrinter { to_print: None, state: PrintState::CONNECTED,
-            homed_axes:EnumSet::new(), temperatures: init_temps, position: PositionData::default(), 
+            homed_axes:EnumSet::new(), temperatures: init_temps, temperature_history: TemperatureHistory::new(),
+            position: PositionData::default(),
             last_line_at: std::time::Instant::now(),
             last_temp_update: std::time::Instant::now(),
             print_timer: PrintTimer::new(),
             gcode_send_interval: Duration::ZERO,
             fan_speeds: vec![0.],
-            external_console: ExternalConsole::new()
+            external_console: ExternalConsole::new(),
+            idle_timeout: IdleTimeout::new(config.idle.timeout_secs),
+            pid_autotune: None,
+            bed_probe: None,
+            probed_mesh: None,
+            firmware_settings: FirmwareSettings {
+                steps_per_mm: [('X', 80.), ('Y', 80.), ('Z', 400.), ('E', 93.)].into(),
+                max_feedrate: [('X', 500.), ('Y', 500.), ('Z', 5.), ('E', 25.)].into(),
+                max_acceleration: [('X', 500.), ('Y', 500.), ('Z', 100.), ('E', 5000.)].into(),
+                acceleration: [('P', 500.), ('R', 500.), ('T', 1000.)].into(),
+                jerk: [('B', 20000.), ('S', 0.), ('T', 0.), ('J', 0.08)].into(),
+                home_offset: [('X', 0.), ('Y', 0.), ('Z', 0.)].into(),
+                hotend_pid: [('P', 21.73), ('I', 1.54), ('D', 76.55)].into(),
+                ..FirmwareSettings::default()
+            },
+            esteps_calibration: None,
+            profile: config.printer.clone(),
+            thermal: config.thermal.clone(),
+            jobs: JobTracker::default()
         }
     }
+
+    fn transition_state(&mut self, new_state: PrintState) -> Result<()> {
+        if !self.state.can_transition_to(new_state) {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Illegal printer state transition: {:?} -> {:?}", self.state, new_state)));
+        }
+        info!("Simulated printer state transition: {:?} -> {:?}", self.state, new_state);
+        self.state = new_state;
+        self.idle_timeout.touch();
+        Ok(())
+    }
 }
 
 impl PrinterControl for SimulatedPrinter {
@@ -744,6 +1531,11 @@ impl PrinterControl for SimulatedPrinter {
             },
             None => {}
         }
+        if self.state == PrintState::FINISHING {
+            self.jobs.finish(JobOutcome::DONE, None, self.to_print.as_ref(), self.print_timer.elapsed());
+            self.transition_state(PrintState::COMPLETED)?;
+        }
+
         if self.state == PrintState::STARTED {
             // Send next line
             self.print_timer.update();
@@ -752,13 +1544,24 @@ impl PrinterControl for SimulatedPrinter {
 
                 if to_print.cur_line_in_file < to_print.line_count {
                     to_print.cur_line_in_file += 1;
-                } else if self.state == PrintState::STARTED {
-                    self.state = PrintState::DONE;
+                    to_print.update_layer(self.print_timer.elapsed());
+                } else {
+                    to_print.finish_layer(self.print_timer.elapsed());
+                    self.transition_state(PrintState::FINISHING)?;
                 }
                 self.last_line_at = std::time::Instant::now();
             }
         }
 
+        if self.idle_timeout.remaining(self.state, &self.temperatures) == Some(Duration::ZERO) {
+            info!("Simulated printer idle for too long, turning off heaters, fans and steppers.");
+            for temp in &mut self.temperatures {
+                temp.target = 0.;
+            }
+            self.fan_speeds.iter_mut().for_each(|speed| *speed = 0.);
+            self.homed_axes = EnumSet::new();
+        }
+
         // Update status
         if std::time::Instant::now() - self.last_temp_update >= std::time::Duration::from_millis(750) {
             for temp in &mut self.temperatures {
@@ -769,6 +1572,7 @@ impl PrinterControl for SimulatedPrinter {
                     temp.current -= adjust;
                 }
             }
+            self.temperature_history.add(&self.temperatures);
             self.last_temp_update = std::time::Instant::now();
         }
 
@@ -783,32 +1587,38 @@ impl PrinterControl for SimulatedPrinter {
             None => None
         };
         let time_elapsed = match &self.state {
-            PrintState::STARTED | PrintState::PAUSED | PrintState::DONE => Some(self.print_timer.elapsed()),
-            _=> None
+            PrintState::CONNECTED | PrintState::DEAD => None,
+            _=> Some(self.print_timer.elapsed())
         };
 
         Ok(internal_api::PrinterStatus{ 
             printer_connected: true,
             manual_control_enabled: self.homed_axes.is_superset(enum_set!(Axis::X | Axis::Y | Axis::Z)), 
+            homed_axes: self.homed_axes.iter().collect(),
             state: self.state, 
             temperatures: self.temperatures.clone(), 
             position: self.position.current, 
-            gcode_lines_done_total: match &self.to_print {
-                Some(p) => {Some((p.path.file_name().unwrap().to_str().unwrap().to_string(), p.cur_line_in_file, p.line_count))}
-                None => None
-            },
+            gcode_lines_done_total: self.to_print.as_ref().map(|p| (p.name().to_string(), p.cur_line_in_file, p.line_count)),
             print_time_elapsed: time_elapsed,
             print_time_remaining: time_remaining,
-            fan_speed: self.fan_speeds.clone()})
+            fan_speed: self.fan_speeds.clone(),
+            alerts: Vec::new(),
+            idle_timeout_remaining: self.idle_timeout.remaining(self.state, &self.temperatures),
+            pid_autotune: self.pid_autotune.clone(),
+            esteps_calibration: self.esteps_calibration.clone(),
+            bed_probe: self.bed_probe.clone(),
+            layer: self.to_print.as_ref().and_then(|p| p.get_layer_progress()),
+            last_job: self.jobs.last().cloned()})
     }
 
     fn get_state(&self) -> PrintState {
         self.state
     }
 
-    fn set_gcode_file(&mut self, abs_path: &PathBuf) -> Result<()> {
-        match file::GCodeFile::new(abs_path) {
-            Ok(file) => {
+    fn set_gcode_file(&mut self, abs_path: &Path, name: &str) -> Result<()> {
+        match file::GCodeFile::new(abs_path, &MotionLimits::from(&self.firmware_settings)) {
+            Ok(mut file) => {
+                file.name = name.to_string();
                 match &file.get_duration_lines() {
                     Some((lines, dur)) => {
                         self.gcode_send_interval = dur.div(*lines);
@@ -827,26 +1637,50 @@ impl PrinterControl for SimulatedPrinter {
     }
 
     fn clear_gcode_file(&mut self) -> Result<()> {
-        if self.state != PrintState::CONNECTED && self.state != PrintState::DONE {
-            return Err(Error::new(std::io::ErrorKind::InvalidInput, format!("Cannot clear gcode file in this state ({:?})!", self.state)));
+        if !self.state.is_idle() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Cannot clear gcode file in this state ({:?})!", self.state)));
         }
 
         self.to_print = None;
         Ok(())
     }
 
-    fn start(&mut self) -> Result<()> {
+    fn start(&mut self, force: bool) -> Result<()> {
         if self.state == PrintState::PAUSED {
             self.print_timer.skip();
-        } else {
-            self.print_timer = PrintTimer::new()
+            self.transition_state(PrintState::RESUMING)?;
+            self.transition_state(PrintState::STARTED)?;
+            return Ok(());
+        }
+
+        if !self.state.is_idle() {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Printer cannot be started from this state ({:?})!", self.state)));
+        }
+
+        if !force {
+            print_validation::validation_result(&self.validate_print()?)?;
+        }
+
+        let to_print = self.to_print.as_mut().ok_or(coded_error(ErrorCode::INVALIDSTATE, "GCode file not loaded."))?;
+        to_print.cur_line_in_file = 0;
+        self.print_timer = PrintTimer::new();
+        self.transition_state(PrintState::STARTED)?;
+        if let Some(file) = &self.to_print {
+            self.jobs.begin(file, "Simulated printer");
         }
-        self.state = PrintState::STARTED;
         Ok(())
     }
 
     fn stop(&mut self) -> Result<()> {
-        self.state = PrintState::CONNECTED;
+        if self.state.is_printing() {
+            self.transition_state(PrintState::CANCELLING)?;
+            self.jobs.finish(JobOutcome::CANCELLED, None, self.to_print.as_ref(), self.print_timer.elapsed());
+            self.transition_state(PrintState::CANCELLED)?;
+        } else if matches!(self.state, PrintState::COMPLETED | PrintState::CANCELLED | PrintState::ERROR) {
+            self.transition_state(PrintState::CONNECTED)?;
+        } else {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Printer cannot be stopped from this state ({:?})!", self.state)));
+        }
 
         for temp in &mut self.temperatures {
             temp.target = 0.;
@@ -855,22 +1689,35 @@ impl PrinterControl for SimulatedPrinter {
     }
 
     fn pause(&mut self) -> Result<()> {
+        if self.state != PrintState::STARTED {
+            return Err(coded_error(ErrorCode::INVALIDSTATE, format!("Printer cannot be paused from this state ({:?})!", self.state)));
+        }
         self.print_timer.update();
-        self.state = PrintState::PAUSED;
+        self.transition_state(PrintState::PAUSING)?;
+        self.transition_state(PrintState::PAUSED)?;
         Ok(())
     }
 
     fn go_home(&mut self, axes: &EnumSet<Axis>) -> Result<()> {
+        self.idle_timeout.touch();
         self.homed_axes.insert_all(*axes);
         Ok(())
     }
 
     fn move_relative(&mut self, _new_pos: &Position) -> Result<()> {
+        self.idle_timeout.touch();
+        Ok(())
+    }
+
+    fn disable_steppers(&mut self, axes: &EnumSet<Axis>) -> Result<()> {
+        self.idle_timeout.touch();
+        self.homed_axes -= *axes;
         Ok(())
     }
 
     fn set_temperature(&mut self, new_temp: &TemperatureTarget) -> Result<()> {
        new_temp.validate()?;
+       self.idle_timeout.touch();
 
        for temp in &mut self.temperatures {
         if temp.measured_from == new_temp.to_set {
@@ -883,6 +1730,7 @@ impl PrinterControl for SimulatedPrinter {
 
     fn set_fan_speed(&mut self, new_fan_speed: &FanSpeedTarget) -> Result<()> {
         new_fan_speed.validate()?;
+        self.idle_timeout.touch();
 
         let vec_idx = new_fan_speed.index as usize;
         if vec_idx > self.fan_speeds.len() {
@@ -897,6 +1745,18 @@ impl PrinterControl for SimulatedPrinter {
         self.external_console.get_ext_channels()
     }
 
+    fn validate_print(&self) -> Result<Vec<ValidationIssue>> {
+        match &self.to_print {
+            Some(p) => {
+                let fw_info = self.get_info()?.values;
+                let unknown_commands = BTreeSet::new();
+                let limits = PrinterLimits { profile: &self.profile, thermal: &self.thermal, fw_info: &fw_info, unknown_commands: &unknown_commands };
+                Ok(print_validation::validate(&p.metadata, &limits))
+            }
+            None => Err(Error::new(std::io::ErrorKind::NotFound, "GCode file not loaded."))
+        }
+    }
+
     fn get_info(&self) -> Result<PrinterInfo> {
 
         Ok(PrinterInfo {values: HashMap::from(
@@ -933,4 +1793,246 @@ impl PrinterControl for SimulatedPrinter {
             ("BINARY_FILE_TRANSFER".to_owned(),"0".to_owned()),]
         )})
     }
-}
\ No newline at end of file
+
+    fn get_temperature_history(&self, since: Option<f64>) -> Result<Vec<TemperatureSample>> {
+        Ok(self.temperature_history.get_since(since, TemperatureHistory::MAX_POINTS_RETURNED))
+    }
+
+    fn get_layer_durations(&self) -> Result<Vec<LayerDuration>> {
+        match &self.to_print {
+            Some(p) => Ok(p.get_layer_durations().clone()),
+            None => Err(Error::new(std::io::ErrorKind::NotFound, "GCode file not loaded."))
+        }
+    }
+
+    fn start_pid_autotune(&mut self, params: &PidAutotuneParams) -> Result<()> {
+        params.validate()?;
+
+        // Pretend it finished straight away
+        self.pid_autotune = Some(PidAutotuneStatus{heater: params.heater, index: params.index.unwrap_or(0), target: params.target, cycles: params.cycles,
+            cycles_done: params.cycles, state: PidAutotuneState::FINISHED, result: Some(internal_api::PidValues{kp: 22.2, ki: 1.08, kd: 114.})});
+        Ok(())
+    }
+
+    fn read_bed_mesh(&mut self) -> Result<BedMesh> {
+        let mut pending = PendingMesh::default();
+        for y in 0..3 {
+            pending.add_row(y, (0..3).map(|x| Some(((x + y) as f64 - 2.) * 0.05 + rand::random::<f64>() * 0.01)).collect());
+        }
+        pending.got_ok();
+
+        pending.into_mesh(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs(), (10., 210.), (10., 210.))
+        .ok_or(Error::new(std::io::ErrorKind::NotFound, "No bed mesh"))
+    }
+
+    fn start_bed_probe(&mut self) -> Result<()> {
+        // Pretend it finished straight away
+        self.probed_mesh = Some(self.read_bed_mesh()?);
+        self.bed_probe = Some(BedProbeStatus{state: BedProbeState::FINISHED, points_probed: 9, secs_elapsed: 0});
+        Ok(())
+    }
+
+    fn take_probed_mesh(&mut self) -> Option<BedMesh> {
+        self.probed_mesh.take()
+    }
+
+    fn read_firmware_settings(&mut self) -> Result<FirmwareSettings> {
+        Ok(self.firmware_settings.clone())
+    }
+
+    fn write_firmware_settings(&mut self, changes: &FirmwareSettings, _save: bool, dry_run: bool) -> Result<Vec<SettingChange>> {
+        changes.validate()?;
+
+        let new = self.firmware_settings.merged(changes);
+        let diff = self.firmware_settings.diff(&new);
+        if !dry_run {
+            self.firmware_settings = new;
+        }
+        Ok(diff)
+    }
+
+    fn start_esteps_calibration(&mut self, params: &EStepsCalibrationParams) -> Result<()> {
+        params.validate()?;
+
+        // Skip straight to measuring
+        let mut calibration = EStepsCalibrationStatus::new(params, *self.firmware_settings.steps_per_mm.get(&'E').unwrap());
+        calibration.step = EStepsCalibrationStep::MEASURING;
+        self.esteps_calibration = Some(calibration);
+        Ok(())
+    }
+
+    fn submit_esteps_measurement(&mut self, measurement: &EStepsMeasurement) -> Result<()> {
+        match self.esteps_calibration.as_mut() {
+            Some(calibration) => {
+                calibration.measured(measurement.mark_distance.unwrap_or(calibration.length + 20.), measurement.remaining)
+            }
+            None => Err(coded_error(ErrorCode::INVALIDSTATE, "E-steps calibration not started."))
+        }
+    }
+
+    fn apply_esteps_calibration(&mut self, _save: bool) -> Result<()> {
+        match self.esteps_calibration.take() {
+            Some(EStepsCalibrationStatus{new_steps_per_mm: Some(steps), ..}) => {
+                self.firmware_settings.steps_per_mm.insert('E', steps);
+                Ok(())
+            }
+            _ => Err(coded_error(ErrorCode::INVALIDSTATE, "No finished e-steps calibration to apply."))
+        }
+    }
+
+    fn cancel_esteps_calibration(&mut self) -> Result<()> {
+        self.esteps_calibration = None;
+        Ok(())
+    }
+
+    fn take_finished_jobs(&mut self) -> Vec<JobRecord> {
+        self.jobs.take_finished()
+    }
+
+    fn apply_pid_autotune(&mut self, _save: bool) -> Result<()> {
+        match &self.pid_autotune {
+            Some(PidAutotuneStatus{state: PidAutotuneState::FINISHED, ..}) => Ok(()),
+            _ => Err(coded_error(ErrorCode::INVALIDSTATE, "No finished PID autotune result to apply."))
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_error::CodedError;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use serialport::SerialPort;
+
+    // A Marlin printer on the other end of a pseudo terminal, it says ok to everything and remembers what it got
+    fn fake_printer() -> (Printer, Arc<Mutex<Vec<String>>>) {
+        let (mut master, mut slave) = serialport::TTYPort::pair().unwrap();
+        slave.set_timeout(Duration::from_millis(10)).unwrap();
+        master.set_timeout(Duration::from_secs(60)).unwrap();
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_by_printer = received.clone();
+        std::thread::spawn(move || {
+            let mut reader = std::io::BufReader::new(master.try_clone_native().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 0) {
+                received_by_printer.lock().unwrap().push(line.trim().to_string());
+                line.clear();
+                if master.write_all(b"ok\n").is_err() {
+                    break;
+                }
+            }
+        });
+
+        let comms = PrinterComms { port: std::io::BufReader::new(Box::new(slave)),
+            fw_info: [("FIRMWARE_NAME".to_string(), "Marlin 2.1.2".to_string())].into() };
+        (Printer::new(comms, &Config::default()).unwrap(), received)
+    }
+
+    fn gcode_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("yoctoprint_printer_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn stopping_autotune_allows_printing() {
+        let (mut printer, received) = fake_printer();
+        printer.start_pid_autotune(&PidAutotuneParams{heater: ProbePoint::HOTEND, index: None, target: 200., cycles: 5}).unwrap();
+        assert!(received.lock().unwrap().iter().any(|l| l.starts_with("M303")));
+        assert!(printer.start(true).is_err());
+
+        printer.stop().unwrap();
+        assert_eq!(printer.get_status().unwrap().pid_autotune.unwrap().state, PidAutotuneState::FAILED("interrupted".to_string()));
+
+        let path = gcode_file("autotune.gcode", "G28\nG1 X10 Y10\n");
+        printer.set_gcode_file(&path, "autotune.gcode").unwrap();
+        printer.start(true).unwrap();
+        assert_eq!(printer.get_state(), PrintState::STARTED);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn esteps_calibration_restores_extruder_mode() {
+        let (mut printer, received) = fake_printer();
+        let params = EStepsCalibrationParams{temperature: 200., length: 100., feedrate: None};
+        printer.esteps_calibration = Some(EStepsCalibrationStatus::new(&params, 93.));
+        printer.temperatures = vec![Temperature{measured_from: ProbePoint::HOTEND, index: 0, power: 0.5, current: 200., target: 200.}];
+
+        printer.advance_esteps_calibration().unwrap();
+        assert_eq!(printer.esteps_calibration.as_ref().unwrap().step, EStepsCalibrationStep::EXTRUDING);
+        assert_eq!(printer.position.move_mode_xyz_e, (PositionMode::ABSOLUTE, PositionMode::ABSOLUTE));
+        let received = received.lock().unwrap();
+        let extrude_at = received.iter().position(|l| l.starts_with("G1 E100")).unwrap();
+        assert_eq!(received[extrude_at - 1], "M83");
+        assert_eq!(received[extrude_at + 2], "M82");
+    }
+
+    #[test]
+    fn esteps_calibration_stays_out_of_prints() {
+        let (mut printer, received) = fake_printer();
+        let params = EStepsCalibrationParams{temperature: 200., length: 100., feedrate: None};
+        let path = gcode_file("esteps.gcode", "G28\nG1 X10 Y10\nG1 X20 Y20\n");
+        printer.set_gcode_file(&path, "esteps.gcode").unwrap();
+
+        printer.esteps_calibration = Some(EStepsCalibrationStatus::new(&params, 93.));
+        assert!(printer.start(true).is_err());
+        assert!(printer.start_pid_autotune(&PidAutotuneParams{heater: ProbePoint::HOTEND, index: None, target: 200., cycles: 5}).is_err());
+
+        // A calibration still heating up when a print starts waits for it to finish
+        printer.esteps_calibration = None;
+        printer.start(true).unwrap();
+        printer.esteps_calibration = Some(EStepsCalibrationStatus::new(&params, 93.));
+        printer.temperatures = vec![Temperature{measured_from: ProbePoint::HOTEND, index: 0, power: 0.5, current: 200., target: 200.}];
+        printer.next_action().unwrap();
+        printer.next_action().unwrap();
+
+        assert_eq!(printer.esteps_calibration.as_ref().unwrap().step, EStepsCalibrationStep::HEATING);
+        assert!(!received.lock().unwrap().iter().any(|l| l.contains("G1 E")));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stopping_ends_esteps_calibration() {
+        let (mut printer, _) = fake_printer();
+        let params = EStepsCalibrationParams{temperature: 200., length: 100., feedrate: None};
+        printer.esteps_calibration = Some(EStepsCalibrationStatus::new(&params, 93.));
+        assert!(printer.is_calibrating());
+
+        printer.stop().unwrap();
+        assert!(printer.esteps_calibration.is_none());
+        assert!(!printer.is_calibrating());
+    }
+
+    #[test]
+    fn illegal_state_transitions_refused() {
+        let (mut printer, _) = fake_printer();
+        let err = printer.transition_state(PrintState::PAUSED).unwrap_err();
+        assert_eq!(CodedError::from_io(&err).code, ErrorCode::INVALIDSTATE);
+        assert_eq!(printer.get_state(), PrintState::CONNECTED);
+
+        // Nothing to pause, so nothing changes
+        assert!(printer.pause().is_err());
+        assert_eq!(printer.get_state(), PrintState::CONNECTED);
+    }
+
+    #[test]
+    fn firmware_stepper_timeout_unhomes() {
+        let (mut printer, _) = fake_printer();
+        printer.go_home(&EnumSet::all()).unwrap();
+        printer.next_action().unwrap();
+        assert!(printer.can_move_manually());
+
+        printer.stepper_timeout.last_move -= StepperTimeout::FIRMWARE_DEFAULT;
+        printer.next_action().unwrap();
+        assert!(printer.get_status().unwrap().homed_axes.is_empty());
+
+        // S0 turns the firmware's timeout off
+        printer.go_home(&EnumSet::all()).unwrap();
+        printer.send_cmd_read_until_response("M84 S0", None).unwrap();
+        printer.stepper_timeout.last_move -= StepperTimeout::FIRMWARE_DEFAULT;
+        printer.next_action().unwrap();
+        assert!(printer.can_move_manually());
+    }
+}
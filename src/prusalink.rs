@@ -0,0 +1,158 @@
+use std::path::Path;
+
+use rocket::serde::Serialize;
+
+use crate::file;
+use crate::internal_api::{PrintState, PrinterStatus, ProbePoint};
+
+// PrusaSlicer picks the storage to upload to from this, all of them end up in the gcode dir
+pub const STORAGE_NAMES: [&str; 2] = ["usb", "local"];
+
+#[derive(Serialize, Debug)]
+pub struct Storage {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub storage_type: String,
+    pub path: String,
+    pub read_only: bool,
+    pub available: bool,
+    pub free_space: Option<u64>
+}
+
+#[derive(Serialize, Debug)]
+pub struct StorageList {
+    pub storage_list: Vec<Storage>
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct PrinterInfo {
+    pub state: String,
+    pub temp_nozzle: Option<f64>,
+    pub target_nozzle: Option<f64>,
+    pub temp_bed: Option<f64>,
+    pub target_bed: Option<f64>,
+    pub axis_x: Option<f64>,
+    pub axis_y: Option<f64>,
+    pub axis_z: Option<f64>
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct JobProgress {
+    pub progress: f64,
+    pub time_remaining: Option<u64>,
+    pub time_printing: Option<u64>
+}
+
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub printer: PrinterInfo,
+    pub job: Option<JobProgress>
+}
+
+#[derive(Serialize, Debug)]
+pub struct JobFile {
+    pub name: String,
+    pub display_name: String,
+    pub path: String,
+    pub size: Option<u64>,
+    pub m_timestamp: Option<u64>
+}
+
+#[derive(Serialize, Debug)]
+pub struct Job {
+    pub state: String,
+    #[serde(flatten)]
+    pub progress: JobProgress,
+    pub file: JobFile
+}
+
+pub fn storage_list(data_dir: &Path) -> StorageList {
+    let free_space = fs2::available_space(data_dir).ok();
+    StorageList { storage_list: STORAGE_NAMES.iter().map(|name| Storage { name: name.to_string(), storage_type: name.to_uppercase(),
+        path: format!("/{}", name), read_only: false, available: true, free_space }).collect() }
+}
+
+pub fn state_text(status: &PrinterStatus) -> &'static str {
+    if !status.printer_connected {
+        return "ERROR";
+    }
+    match status.state {
+        PrintState::CONNECTED => "IDLE",
+        PrintState::HEATING | PrintState::STARTED | PrintState::PAUSING | PrintState::RESUMING | PrintState::FINISHING | PrintState::CANCELLING => "PRINTING",
+        PrintState::PAUSED => "PAUSED",
+        PrintState::COMPLETED => "FINISHED",
+        PrintState::CANCELLED => "STOPPED",
+        PrintState::ERROR | PrintState::DEAD => "ERROR"
+    }
+}
+
+fn job_progress(status: &PrinterStatus) -> Option<JobProgress> {
+    if matches!(status.state, PrintState::CONNECTED | PrintState::DEAD) {
+        return None;
+    }
+    let (_, lines_done, lines_total) = status.gcode_lines_done_total.as_ref()?;
+    Some(JobProgress {
+        progress: if *lines_total > 0 { *lines_done as f64 * 100. / *lines_total as f64 } else { 0. },
+        time_remaining: status.print_time_remaining.map(|d| d.as_secs()),
+        time_printing: status.print_time_elapsed.map(|d| d.as_secs())
+    })
+}
+
+pub fn status(status: &PrinterStatus) -> Status {
+    let mut printer = PrinterInfo { state: state_text(status).to_string(), ..PrinterInfo::default() };
+    for temp in status.temperatures.iter().filter(|t| t.index == 0) {
+        match temp.measured_from {
+            ProbePoint::HOTEND => (printer.temp_nozzle, printer.target_nozzle) = (Some(temp.current), Some(temp.target)),
+            ProbePoint::BED => (printer.temp_bed, printer.target_bed) = (Some(temp.current), Some(temp.target)),
+            _ => {}
+        }
+    }
+    if status.printer_connected {
+        (printer.axis_x, printer.axis_y, printer.axis_z) = (Some(status.position.x), Some(status.position.y), Some(status.position.z));
+    }
+
+    Status { printer, job: job_progress(status) }
+}
+
+// There's only a job once something started printing
+pub fn job(status: &PrinterStatus, data_dir: &Path) -> Option<Job> {
+    let progress = job_progress(status)?;
+    let (name, _, _) = status.gcode_lines_done_total.as_ref()?;
+    let metadata = file::get_abs_gcode_path(data_dir, Path::new(name)).and_then(std::fs::metadata).ok();
+    let display_name = Path::new(name).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+
+    Some(Job { state: state_text(status).to_string(), progress, file: JobFile { name: display_name.clone(), display_name,
+        path: format!("/{}/{}", STORAGE_NAMES[0], name), size: metadata.as_ref().map(|m| m.len()),
+        m_timestamp: metadata.and_then(|m| m.modified().ok()).and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok()).map(|d| d.as_secs()) } })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal_api::Temperature;
+
+    #[test]
+    fn maps_printer_status() {
+        let mut status = PrinterStatus { printer_connected: true, state: PrintState::CONNECTED,
+            temperatures: vec![Temperature{measured_from: ProbePoint::HOTEND, index: 0, power: 0., current: 200., target: 210.}],
+            gcode_lines_done_total: Some(("cube.gcode".to_string(), 0, 200)), ..PrinterStatus::default() };
+        assert_eq!(state_text(&status), "IDLE");
+        assert!(job(&status, Path::new("/nonexistent")).is_none());
+
+        status.state = PrintState::PAUSED;
+        status.gcode_lines_done_total = Some(("cube.gcode".to_string(), 50, 200));
+        status.print_time_elapsed = Some(std::time::Duration::from_secs(30));
+
+        let printer_status = super::status(&status);
+        assert_eq!(printer_status.printer.state, "PAUSED");
+        assert_eq!(printer_status.printer.target_nozzle, Some(210.));
+        assert_eq!(printer_status.printer.temp_bed, None);
+        assert_eq!(printer_status.job, Some(JobProgress { progress: 25., time_remaining: None, time_printing: Some(30) }));
+
+        let job = job(&status, Path::new("/nonexistent")).unwrap();
+        assert_eq!(job.file.path, "/usb/cube.gcode");
+
+        status.state = PrintState::CANCELLED;
+        assert_eq!(state_text(&status), "STOPPED");
+    }
+}
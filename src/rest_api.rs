@@ -1,18 +1,41 @@
 use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 
 use crossbeam::channel::{Sender, Receiver, RecvError};
-use rocket::data::ByteUnit;
+use rocket::data::{self, ByteUnit, FromData, Limits};
 use rocket::futures::pin_mut;
 use rocket::serde::{json::Json, Serialize, Deserialize};
+use rocket::serde::json::serde_json;
+use serde::de::DeserializeOwned;
 use rocket::{State,Data, Request};
+use rocket::form::{Form, FromForm};
+use rocket::fs::TempFile;
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::status::{Created, NoContent};
 use rocket::futures::future::Either;
 use rocket::fs::NamedFile;
-use rocket_cors::CorsOptions;
+use rocket_cors::{AllowedOrigins, CorsOptions};
 use rocket_ws::Message;
 use crate::internal_api;
 use crate::file;
 use crate::recv_channel_async_wrapper::RecvChannelAsyncWrapper;
+use crate::temperature_history::TemperatureSample;
+use crate::bed_mesh::{self, BedMeshReport};
+use crate::firmware_settings::{FirmwareSettings, SettingChange};
+use crate::gcode_info;
+use crate::gcode_metadata::GCodeMetadata;
+use crate::print_validation::{self, ValidationIssue};
+use crate::upload::{self, UploadStatus};
+use crate::config::{Config, UploadConfig};
+use crate::auth::{Auth, ApiKeyInfo, Credential, Identity, Role, UserInfo};
+use crate::hot_folder::{ImportEvent, ImportLog};
+use crate::print_history::{self, HistoryFilter, HistoryStats, JobOutcome, JobRecord};
+use crate::events::EventBus;
+use crate::octoprint;
+use crate::prusalink;
+use crate::api_error::{coded_error, field_error, CodedError, ErrorCode, FieldError};
 use internal_api::*;
 use enumset::EnumSet;
 
@@ -25,21 +48,59 @@ struct WebUiDir(PathBuf);
 
 type DataDir = PathBuf;
 
-#[derive(Serialize)]
+const MAX_UPLOAD_CHUNK: ByteUnit = ByteUnit::Mebibyte(16);
+
+#[derive(Serialize, Debug, Clone)]
 struct ApiError{
+    code: ErrorCode,
     kind: String,
-    description: String
+    description: String,
+    // Which values in the request were wrong
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+    // Why a print didn't start, same as /validate_print
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    issues: Vec<ValidationIssue>
 }
 
 impl From<std::io::Error> for ApiError{
     fn from(err: std::io::Error) -> Self {
+        let coded = CodedError::from_io(&err);
         ApiError {
+            code: coded.code,
             kind: err.kind().to_string(),
-            description: err.to_string()
+            description: coded.message,
+            fields: coded.fields,
+            issues: Vec::new()
         }
     }
 }
 
+impl ApiError {
+    fn new<M: Into<String>>(code: ErrorCode, message: M) -> Self {
+        ApiError::from(coded_error(code, message))
+    }
+
+    fn validation_failed(issues: Vec<ValidationIssue>) -> Self {
+        let mut err = match print_validation::validation_result(&issues) {
+            Err(e) => ApiError::from(e),
+            Ok(()) => ApiError::new(ErrorCode::VALIDATIONFAILED, "File failed validation")
+        };
+        err.issues = issues;
+        err
+    }
+
+    fn status(&self) -> Status {
+        Status::from_code(self.code.http_status()).unwrap_or(Status::InternalServerError)
+    }
+
+    // Failing guards only get to pick the status, this keeps the details for the catcher
+    fn reject<T, F>(self, request: &Request<'_>) -> rocket::outcome::Outcome<T, (Status, ApiError), F> {
+        request.local_cache(|| Some(self.clone()));
+        rocket::outcome::Outcome::Error((self.status(), self))
+    }
+}
+
 fn crossbeam_err_to_io_err<T>(crossbeam_err: T) -> ApiError 
 where T: std::error::Error + std::marker::Sync + std::marker::Send +'static{
     ApiError::from(Error::new(ErrorKind::BrokenPipe, crossbeam_err))
@@ -52,12 +113,121 @@ fn resp_generic_result_or_err(crossbeam_result: Result<PrinterResponse, RecvErro
                 match r {
                     PrinterResponse::GenericResult(Ok(())) => { Ok(())}
                     PrinterResponse::GenericResult(Err(e)) => {Err(ApiError::from(e))}
+                    // Starting a print can be refused over problems with the file
+                    PrinterResponse::ValidationIssues(Ok(issues)) => {Err(ApiError::validation_failed(issues))}
                     _ => {Err(ApiError::from(Error::new(ErrorKind::Unsupported, "Unexpected response")))}
                 }
             }
         }
 }
 
+const SESSION_COOKIE: &str = "session";
+
+// Who's asking, from an X-Api-Key header or the web UI's session cookie
+fn identify(request: &Request<'_>) -> Option<Identity> {
+    let auth = request.rocket().state::<Auth>()?;
+    if !auth.config.enabled {
+        return Some(Identity { name: "anonymous".to_string(), role: Role::ADMIN, credential: Credential::NONE });
+    }
+    if let Some(key) = request.headers().get_one("X-Api-Key") {
+        return auth.check_api_key(key);
+    }
+    request.cookies().get(SESSION_COOKIE).and_then(|cookie| auth.check_session(cookie.value()))
+}
+
+// A guard for each role, routes take the one they need
+macro_rules! role_guard {
+    ($name:ident, $role:expr) => {
+        #[allow(dead_code)] // Not every route cares who it is
+        struct $name(Identity);
+
+        #[rocket::async_trait]
+        impl<'r> FromRequest<'r> for $name {
+            type Error = ApiError;
+
+            async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+                match identify(request) {
+                    Some(identity) if identity.role >= $role => request::Outcome::Success($name(identity)),
+                    Some(identity) => ApiError::new(ErrorCode::FORBIDDEN,
+                        format!("{} isn't allowed to do this, it needs the {:?} role", identity.name, $role)).reject(request),
+                    None => ApiError::new(ErrorCode::UNAUTHORIZED, "Log in or send an X-Api-Key").reject(request)
+                }
+            }
+        }
+    }
+}
+
+role_guard!(Viewer, Role::VIEWER);
+role_guard!(Operator, Role::OPERATOR);
+role_guard!(Admin, Role::ADMIN);
+
+// Like Json, but a body that doesn't fit says which field was wrong
+struct ApiJson<T>(T);
+
+impl<T> ApiJson<T> {
+    fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> std::ops::Deref for ApiJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+// serde_json puts the position after the message, the field path says more
+fn json_error_message(err: &serde_json::Error) -> String {
+    let message = err.to_string();
+    match message.rfind(" at line ") {
+        Some(idx) => message[..idx].to_string(),
+        None => message
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for ApiJson<T> {
+    type Error = ApiError;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+        let body = match data.open(limit).into_string().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => return ApiError::new(ErrorCode::TOOLARGE, format!("Request body is bigger than {}", limit)).reject(request),
+            Err(e) => return ApiError::from(e).reject(request)
+        };
+
+        match serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(&body)) {
+            Ok(value) => data::Outcome::Success(ApiJson(value)),
+            Err(e) => {
+                let path = e.path().to_string();
+                let err = e.into_inner();
+                if err.is_syntax() || err.is_eof() {
+                    return ApiError::new(ErrorCode::INVALIDINPUT, format!("Request body isn't valid JSON: {}", err)).reject(request);
+                }
+                let message = json_error_message(&err);
+                // Missing fields are reported on the object that should have had them
+                let field = match message.strip_prefix("missing field `").and_then(|m| m.split('`').next()) {
+                    Some(name) if path == "." => name.to_string(),
+                    Some(name) => format!("{}.{}", path, name),
+                    None => path
+                };
+                ApiError::from(field_error(field, message)).reject(request)
+            }
+        }
+    }
+}
+
+// The same JSON errors for everything under /api, whether a route or Rocket itself failed
+#[catch(default)]
+fn api_catcher(status: Status, request: &Request<'_>) -> (Status, ApiError) {
+    let error = request.local_cache(|| None::<ApiError>).clone()
+        .unwrap_or_else(|| ApiError::new(ErrorCode::from_http_status(status.code), status.reason_lossy()));
+    (status, error)
+}
+
 #[derive(Debug, Deserialize, Clone)]
 struct ConnectParams {
     pub port : String,
@@ -65,7 +235,7 @@ struct ConnectParams {
 }
 
 #[post("/connect", format = "application/json", data = "<params>")]
-fn connect(comms: &State<InternalComms>, params: Json<ConnectParams>) -> Result<(), ApiError> {
+fn connect(_user: Admin, comms: &State<InternalComms>, params: ApiJson<ConnectParams>) -> Result<(), ApiError> {
     if let Err(e) = comms.to_internal.send(PrinterCommand::Connect(params.port.clone().into(), params.baud)) {
         return Err(crossbeam_err_to_io_err(e));
     }
@@ -74,7 +244,7 @@ fn connect(comms: &State<InternalComms>, params: Json<ConnectParams>) -> Result<
 }
 
 #[get("/printer_info")]
-fn printer_info(comms: &State<InternalComms>) -> Result<Json<PrinterInfo>, ApiError> {
+fn printer_info(_user: Viewer, comms: &State<InternalComms>) -> Result<Json<PrinterInfo>, ApiError> {
     if let Err(e) = comms.to_internal.send(PrinterCommand::GetPrinterInfo) {
         return Err(crossbeam_err_to_io_err(e));
     }
@@ -94,7 +264,11 @@ fn printer_info(comms: &State<InternalComms>) -> Result<Json<PrinterInfo>, ApiEr
 }
 
 #[get("/status")]
-fn status(comms: &State<InternalComms>) -> Result<Json<PrinterStatus>, ApiError> {
+fn status(_user: Viewer, comms: &State<InternalComms>) -> Result<Json<PrinterStatus>, ApiError> {
+    Ok(Json(get_status(comms)?))
+}
+
+fn get_status(comms: &InternalComms) -> Result<PrinterStatus, ApiError> {
     if let Err(e) = comms.to_internal.send(PrinterCommand::GetStatus) {
         return Err(crossbeam_err_to_io_err(e));
     }
@@ -102,7 +276,7 @@ fn status(comms: &State<InternalComms>) -> Result<Json<PrinterStatus>, ApiError>
     match comms.from_internal.recv() {
         Ok(resp) => {
             match resp {
-                PrinterResponse::Status(Ok(status)) => { Ok(Json(status)) }
+                PrinterResponse::Status(Ok(status)) => { Ok(*status) }
                 PrinterResponse::GenericResult(Err(e)) | PrinterResponse::Status(Err(e)) => {Err(ApiError::from(e))}
                 _ => {Err(ApiError::from(Error::new(ErrorKind::Unsupported, format!("Unexpected response"))))}
             }
@@ -113,13 +287,53 @@ fn status(comms: &State<InternalComms>) -> Result<Json<PrinterStatus>, ApiError>
     }
 }
 
+#[get("/layer_durations")]
+fn layer_durations(_user: Viewer, comms: &State<InternalComms>) -> Result<Json<Vec<LayerDuration>>, ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::GetLayerDurations) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    match comms.from_internal.recv() {
+        Ok(resp) => {
+            match resp {
+                PrinterResponse::LayerDurations(Ok(durations)) => { Ok(Json(durations)) }
+                PrinterResponse::GenericResult(Err(e)) | PrinterResponse::LayerDurations(Err(e)) => {Err(ApiError::from(e))}
+                _ => {Err(ApiError::from(Error::new(ErrorKind::Unsupported, "Unexpected response")))}
+            }
+        }
+        Err(e) => {
+            Err(crossbeam_err_to_io_err(e))
+        }
+    }
+}
+
+#[get("/temperature_history?<since>")]
+fn temperature_history(_user: Viewer, comms: &State<InternalComms>, since: Option<f64>) -> Result<Json<Vec<TemperatureSample>>, ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::GetTemperatureHistory(since)) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    match comms.from_internal.recv() {
+        Ok(resp) => {
+            match resp {
+                PrinterResponse::TemperatureHistory(Ok(history)) => { Ok(Json(history)) }
+                PrinterResponse::GenericResult(Err(e)) | PrinterResponse::TemperatureHistory(Err(e)) => {Err(ApiError::from(e))}
+                _ => {Err(ApiError::from(Error::new(ErrorKind::Unsupported, "Unexpected response")))}
+            }
+        }
+        Err(e) => {
+            Err(crossbeam_err_to_io_err(e))
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 struct HomeAxes {
     pub axes : Vec<String>
 }
 
 #[post("/home", format = "application/json", data = "<home_axes>")]
-fn home(comms: &State<InternalComms>, home_axes : Json<HomeAxes>) -> Result<(), ApiError> {
+fn home(_user: Operator, comms: &State<InternalComms>, home_axes : ApiJson<HomeAxes>) -> Result<(), ApiError> {
     let mut internal_axes : EnumSet<internal_api::Axis> = EnumSet::new();
 
     for axis in home_axes.axes.iter() {
@@ -139,6 +353,32 @@ fn home(comms: &State<InternalComms>, home_axes : Json<HomeAxes>) -> Result<(),
     resp_generic_result_or_err(comms.from_internal.recv())
 }
 
+#[post("/disable_steppers", format = "application/json", data = "<axes>")]
+fn disable_steppers(_user: Operator, comms: &State<InternalComms>, axes : ApiJson<HomeAxes>) -> Result<(), ApiError> {
+    let mut internal_axes : EnumSet<internal_api::Axis> = EnumSet::new();
+
+    for axis in axes.axes.iter() {
+        match axis.to_uppercase().trim() {
+            "X" => {internal_axes |= internal_api::Axis::X},
+            "Y" => {internal_axes |= internal_api::Axis::Y},
+            "Z" => {internal_axes |= internal_api::Axis::Z},
+            "E" => {internal_axes |= internal_api::Axis::E},
+            "ALL" => {internal_axes = EnumSet::all()},
+            _ => {}
+        }
+    }
+
+    if internal_axes.is_empty() {
+        return Err(ApiError::from(field_error("axes", "No axes given")));
+    }
+
+    if let Err(e) = comms.to_internal.send(PrinterCommand::DisableSteppers(internal_axes)) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+    
+    resp_generic_result_or_err(comms.from_internal.recv())
+}
+
 #[derive(Debug, Deserialize, Clone)]
 struct RelativeCoords {
     x : Option<f64>,
@@ -148,7 +388,7 @@ struct RelativeCoords {
 }
 
 #[post("/move", format = "application/json", data = "<relative_coords>")]
-fn move_rel(comms: &State<InternalComms>, relative_coords : Json<RelativeCoords>) -> Result<(), ApiError> {
+fn move_rel(_user: Operator, comms: &State<InternalComms>, relative_coords : ApiJson<RelativeCoords>) -> Result<(), ApiError> {
     if let Err(e) = comms.to_internal.send(
         PrinterCommand::ManualMove(internal_api::Position{x: relative_coords.x.unwrap_or(0.0), 
         y: relative_coords.y.unwrap_or(0.0), 
@@ -166,7 +406,7 @@ struct FanSpeed {
     speed: f64
 }
 #[post("/set_fan_speed", format = "application/json", data = "<fan_speed>")]
-fn set_fan_speed(comms: &State<InternalComms>, fan_speed : Json<FanSpeed>) -> Result<(), ApiError> {
+fn set_fan_speed(_user: Operator, comms: &State<InternalComms>, fan_speed : ApiJson<FanSpeed>) -> Result<(), ApiError> {
     if let Err(e) = comms.to_internal.send(PrinterCommand::SetFanSpeed(
         FanSpeedTarget {
             index: fan_speed.index.unwrap_or(0),
@@ -178,52 +418,223 @@ fn set_fan_speed(comms: &State<InternalComms>, fan_speed : Json<FanSpeed>) -> Re
     resp_generic_result_or_err(comms.from_internal.recv())
 }
 
+// Scan a new file now so listing the files doesn't have to
+fn scan_in_background(data_dir: PathBuf, full_path: PathBuf) {
+    rocket::tokio::task::spawn_blocking(move || {
+        if let Err(e) = gcode_info::scan(&data_dir, &full_path) {
+            error!("Error scanning {:?}: {}", full_path, e);
+        }
+        if let Err(e) = file::extract_thumbnails(&data_dir, &full_path) {
+            error!("Error extracting thumbnails from {:?}: {}", full_path, e);
+        }
+    });
+}
+
 #[put("/upload_gcode?<filename>", format="application/octet-stream", data = "<data>")]
-async fn upload_gcode(data: Data<'_>, filename: String, data_dir: &State<DataDir>) -> Result<(), ApiError> {
-    let size_limit: ByteUnit = "50 MB".parse().unwrap();
+async fn upload_gcode(_user: Operator, data: Data<'_>, filename: String, data_dir: &State<DataDir>, upload_config: &State<UploadConfig>) -> Result<(), ApiError> {
+    store_gcode(data, &PathBuf::from(&filename), data_dir, upload_config).await?;
+    Ok(())
+}
 
-    let mut full_path : PathBuf = data_dir.to_path_buf();
-    full_path.push(file::GCODE_DIR);
-    full_path.push(filename);
+// Streams an upload into a new gcode file, returns its name relative to the gcode dir
+async fn store_gcode(data: Data<'_>, path: &Path, data_dir: &DataDir, upload_config: &UploadConfig) -> Result<String, ApiError> {
+    let size_limit = ByteUnit::Mebibyte(upload_config.max_size_mb);
+    let filename = path.to_string_lossy();
+
+    let full_path = file::get_abs_gcode_path(data_dir, path)?;
+    if !file::is_gcode_file(&full_path) {
+        return Err(ApiError::from(Error::new(ErrorKind::InvalidInput, format!("{} isn't a .gcode, .gcode.gz or .gcode.zst file", filename))));
+    }
     
     if std::path::Path::exists(full_path.as_path()) {
-        return Err(ApiError::from(Error::new(std::io::ErrorKind::AlreadyExists, format!("file already exists @ {:?}", full_path))));
+        return Err(ApiError::from(Error::new(std::io::ErrorKind::AlreadyExists, format!("file already exists @ {}", filename))));
+    }
+    // We don't know the size up front, so at least keep the minimum free
+    upload::check_space(data_dir, 0, upload_config)?;
+    if let Some(folder) = full_path.parent() {
+        std::fs::create_dir_all(folder)?;
     }
     let stream = data.open(size_limit);
     let file = stream.into_file(full_path.as_path()).await?;
     
     if !file.is_complete() {
-        return Err(ApiError::from(Error::new(std::io::ErrorKind::BrokenPipe, "Unable to write entire file")));
+        let _ = std::fs::remove_file(&full_path);
+        return Err(ApiError::new(ErrorCode::TOOLARGE, format!("Unable to write entire file, the limit is {} MB", upload_config.max_size_mb)));
     }
+
+    let name = file::get_rel_gcode_path(data_dir, &full_path);
+    scan_in_background(data_dir.to_path_buf(), full_path);
     
+    Ok(name)
+}
+
+// Chunked uploads: start one, send the chunks in order, then finish it with the checksum of the whole file.
+// After an interruption, upload_status tells where to carry on from.
+#[post("/start_upload?<filename>&<size>")]
+fn start_upload(_user: Operator, data_dir: &State<DataDir>, upload_config: &State<UploadConfig>, filename: String, size: u64) -> Result<Json<UploadStatus>, ApiError> {
+    Ok(Json(upload::start(data_dir, &filename, size, upload_config)?))
+}
+
+#[get("/upload_status?<id>")]
+fn upload_status(_user: Viewer, data_dir: &State<DataDir>, id: String) -> Result<Json<UploadStatus>, ApiError> {
+    Ok(Json(upload::status(data_dir, &id)?))
+}
+
+#[put("/upload_chunk?<id>&<offset>", format="application/octet-stream", data = "<data>")]
+async fn upload_chunk(_user: Operator, data: Data<'_>, data_dir: &State<DataDir>, id: String, offset: u64) -> Result<Json<UploadStatus>, ApiError> {
+    let chunk = data.open(MAX_UPLOAD_CHUNK).into_bytes().await?;
+    if !chunk.is_complete() {
+        return Err(ApiError::new(ErrorCode::TOOLARGE, format!("Chunks can't be bigger than {}", MAX_UPLOAD_CHUNK)));
+    }
+
+    Ok(Json(upload::append(data_dir, &id, offset, &chunk)?))
+}
+
+#[post("/finish_upload?<id>&<sha256>")]
+fn finish_upload(_user: Operator, data_dir: &State<DataDir>, id: String, sha256: String) -> Result<(), ApiError> {
+    let full_path = upload::finish(data_dir, &id, &sha256)?;
+    scan_in_background(data_dir.to_path_buf(), full_path);
+
     Ok(())
 }
 
+#[delete("/cancel_upload?<id>")]
+fn cancel_upload(_user: Operator, data_dir: &State<DataDir>, id: String) -> Result<(), ApiError> {
+    Ok(upload::cancel(data_dir, &id)?)
+}
+
+// The filter the history endpoints take from the query string
+#[derive(FromForm)]
+struct HistoryQuery {
+    outcome: Option<String>,
+    file: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>
+}
+
+fn history_filter(query: HistoryQuery) -> Result<HistoryFilter, ApiError> {
+    let outcome = match query.outcome.map(|o| o.to_uppercase()).as_deref() {
+        None => None,
+        Some("DONE") => Some(JobOutcome::DONE),
+        Some("CANCELLED") => Some(JobOutcome::CANCELLED),
+        Some("FAILED") => Some(JobOutcome::FAILED),
+        Some(other) => {
+            return Err(ApiError::from(Error::new(ErrorKind::InvalidInput, format!("Unknown outcome {}, should be DONE, CANCELLED or FAILED", other))));
+        }
+    };
+
+    Ok(HistoryFilter { outcome, file: query.file, since_secs: query.since, until_secs: query.until })
+}
+
+#[get("/history?<limit>&<query..>")]
+fn get_history(_user: Viewer, data_dir: &State<DataDir>, limit: Option<usize>, query: HistoryQuery) -> Result<Json<Vec<JobRecord>>, ApiError> {
+    let mut jobs = print_history::list(data_dir, &history_filter(query)?)?;
+    if let Some(limit) = limit {
+        jobs.truncate(limit);
+    }
+    Ok(Json(jobs))
+}
+
+// Deletes one job by id, or everything matching the filter. No filter at all needs all=true.
+#[delete("/history?<id>&<all>&<query..>")]
+fn delete_history(_user: Admin, data_dir: &State<DataDir>, id: Option<u64>, all: Option<bool>, query: HistoryQuery) -> Result<Json<usize>, ApiError> {
+    let filter = history_filter(query)?;
+    if id.is_none() && filter == HistoryFilter::default() && all != Some(true) {
+        return Err(ApiError::from(Error::new(ErrorKind::InvalidInput, "Give an id or a filter, or all=true to delete the whole history")));
+    }
+    Ok(Json(print_history::delete(data_dir, id, &filter)?))
+}
+
+#[get("/history_stats?<query..>")]
+fn history_stats(_user: Viewer, data_dir: &State<DataDir>, query: HistoryQuery) -> Result<Json<HistoryStats>, ApiError> {
+    Ok(Json(print_history::stats(data_dir, &history_filter(query)?)?))
+}
+
+#[get("/hot_folder_imports")]
+fn hot_folder_imports(_user: Viewer, import_log: &State<ImportLog>) -> Json<Vec<ImportEvent>> {
+    Json(import_log.lock().unwrap().iter().cloned().collect())
+}
+
 #[derive(Debug, Serialize, Clone)]
 struct ApiFileInfo {
     pub last_modified_secs_since_epoch: u64,
     pub name: String,
-    pub size: u64
+    pub size: u64,
+    // Only if it's been scanned already
+    pub info: Option<GCodeMetadata>
 }
 #[derive(Debug, Serialize, Clone)]
 struct FileList {
-    pub files: Vec<ApiFileInfo>
+    pub files: Vec<ApiFileInfo>,
+    // Every folder, so empty ones show up too
+    pub folders: Vec<String>
 }
 #[get("/list_gcode")]
-fn list_gcode(data_dir: &State<DataDir>) -> Json<FileList> {
-    let api_files : Vec<ApiFileInfo> = file::find_gcode_files(data_dir).unwrap().iter()
+fn list_gcode(_user: Viewer, data_dir: &State<DataDir>) -> Result<Json<FileList>, ApiError> {
+    let gcode_dir = data_dir.join(file::GCODE_DIR);
+    let api_files : Vec<ApiFileInfo> = file::find_gcode_files(&gcode_dir)?.iter()
     .map(|file| {
-        ApiFileInfo{name:
-        file.path.file_name().unwrap().to_str().unwrap().to_string(),
+        ApiFileInfo{name: file::get_rel_gcode_path(data_dir, &file.path),
         size: file.size,
-        last_modified_secs_since_epoch: file.last_modified_since_epoch.as_secs()}
+        last_modified_secs_since_epoch: file.last_modified_since_epoch.as_secs(),
+        info: gcode_info::get_cached(data_dir, &file.path)}
     }).collect();
+    let folders = file::find_gcode_folders(&gcode_dir)?.iter()
+    .map(|folder| file::get_rel_gcode_path(data_dir, folder)).collect();
+
+    Ok(Json(FileList{files:api_files, folders}))
+}
+
+#[post("/create_gcode_folder?<path>")]
+fn create_gcode_folder(_user: Operator, data_dir: &State<DataDir>, path: String) -> Result<(), ApiError> {
+    Ok(file::create_gcode_folder(data_dir, &PathBuf::from(path))?)
+}
+
+#[delete("/delete_gcode_folder?<path>")]
+fn delete_gcode_folder(_user: Operator, comms: &State<InternalComms>, path: String) -> Result<(), ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::DeleteGcodeFolder(PathBuf::from(path))) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    resp_generic_result_or_err(comms.from_internal.recv())
+}
+
+// Renames too, works for both files and folders
+#[post("/move_gcode?<from>&<to>")]
+fn move_gcode(_user: Operator, comms: &State<InternalComms>, from: String, to: String) -> Result<(), ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::MoveGcode(PathBuf::from(from), PathBuf::from(to))) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    resp_generic_result_or_err(comms.from_internal.recv())
+}
+
+#[get("/gcode_info?<filename>")]
+fn get_gcode_info(_user: Viewer, data_dir: &State<DataDir>, filename: String) -> Result<Json<GCodeMetadata>, ApiError> {
+    let path = file::get_abs_gcode_path(data_dir, &PathBuf::from(&filename))?;
+    if !path.is_file() {
+        return Err(ApiError::from(Error::new(ErrorKind::NotFound, format!("No such file {}", filename))));
+    }
+
+    Ok(Json(gcode_info::get(data_dir, &path)?))
+}
+
+#[get("/gcode_thumbnail?<filename>&<size>")]
+async fn gcode_thumbnail(_user: Viewer, data_dir: &State<DataDir>, filename: String, size: Option<String>) -> Result<NamedFile, ApiError> {
+    let path = file::get_abs_gcode_path(data_dir, &PathBuf::from(&filename))?;
+    if !path.is_file() {
+        return Err(ApiError::from(Error::new(ErrorKind::NotFound, format!("No such file {}", filename))));
+    }
+
+    let data_dir = data_dir.to_path_buf();
+    let thumbnail = rocket::tokio::task::spawn_blocking(move || file::get_thumbnail(&data_dir, &path, size.as_deref())).await
+    .map_err(Error::other)??;
 
-    return Json(FileList{files:api_files});
+    Ok(NamedFile::open(thumbnail).await?)
 }
 
 #[post("/set_gcode?<filename>")]
-fn set_gcode(comms: &State<InternalComms>, filename: String) -> Result<(), ApiError> {
+fn set_gcode(_user: Operator, comms: &State<InternalComms>, filename: String) -> Result<(), ApiError> {
     if let Err(e) = comms.to_internal.send(PrinterCommand::SetGcodeFile(PathBuf::from(filename))) {
         return Err(crossbeam_err_to_io_err(e));
     }
@@ -232,7 +643,7 @@ fn set_gcode(comms: &State<InternalComms>, filename: String) -> Result<(), ApiEr
 }
 
 #[delete("/delete_gcode?<filename>")]
-fn delete_gcode(comms: &State<InternalComms>, filename: String) -> Result<(), ApiError> {
+fn delete_gcode(_user: Operator, comms: &State<InternalComms>, filename: String) -> Result<(), ApiError> {
     if let Err(e) = comms.to_internal.send(PrinterCommand::DeleteGcodeFile(PathBuf::from(filename))) {
         return Err(crossbeam_err_to_io_err(e));
     }
@@ -240,17 +651,37 @@ fn delete_gcode(comms: &State<InternalComms>, filename: String) -> Result<(), Ap
     resp_generic_result_or_err(comms.from_internal.recv())
 }
 
-#[post("/start_print")]
-fn start_print(comms: &State<InternalComms>) -> Result<(), ApiError> {
-    if let Err(e) = comms.to_internal.send(PrinterCommand::StartPrint) {
+#[post("/start_print?<force>")]
+fn start_print(_user: Operator, comms: &State<InternalComms>, force: Option<bool>) -> Result<(), ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::StartPrint(force.unwrap_or(false))) {
         return Err(crossbeam_err_to_io_err(e));
     }
 
     resp_generic_result_or_err(comms.from_internal.recv())
 }
 
+#[get("/validate_print")]
+fn validate_print(_user: Viewer, comms: &State<InternalComms>) -> Result<Json<Vec<ValidationIssue>>, ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::ValidatePrint) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    match comms.from_internal.recv() {
+        Ok(resp) => {
+            match resp {
+                PrinterResponse::ValidationIssues(Ok(issues)) => { Ok(Json(issues)) }
+                PrinterResponse::GenericResult(Err(e)) | PrinterResponse::ValidationIssues(Err(e)) => {Err(ApiError::from(e))}
+                _ => {Err(ApiError::from(Error::new(ErrorKind::Unsupported, "Unexpected response")))}
+            }
+        }
+        Err(e) => {
+            Err(crossbeam_err_to_io_err(e))
+        }
+    }
+}
+
 #[post("/stop_print")]
-fn stop_print(comms: &State<InternalComms>) -> Result<(), ApiError> {
+fn stop_print(_user: Operator, comms: &State<InternalComms>) -> Result<(), ApiError> {
     if let Err(e) = comms.to_internal.send(PrinterCommand::StopPrint) {
         return Err(crossbeam_err_to_io_err(e));
     }
@@ -259,7 +690,7 @@ fn stop_print(comms: &State<InternalComms>) -> Result<(), ApiError> {
 }
 
 #[post("/pause_print")]
-fn pause_print(comms: &State<InternalComms>) -> Result<(), ApiError> {
+fn pause_print(_user: Operator, comms: &State<InternalComms>) -> Result<(), ApiError> {
     if let Err(e) = comms.to_internal.send(PrinterCommand::PausePrint) {
         return Err(crossbeam_err_to_io_err(e));
     }
@@ -268,7 +699,7 @@ fn pause_print(comms: &State<InternalComms>) -> Result<(), ApiError> {
 }
 
 #[post("/set_temperature", format = "application/json", data = "<temperature>")]
-fn set_temperature(comms: &State<InternalComms>, temperature : Json<TemperatureTarget>) -> Result<(), ApiError> {
+fn set_temperature(_user: Operator, comms: &State<InternalComms>, temperature : ApiJson<TemperatureTarget>) -> Result<(), ApiError> {
     if let Err(e) = comms.to_internal.send(PrinterCommand::SetTemperature(*temperature)) {
         return Err(crossbeam_err_to_io_err(e));
     }
@@ -276,8 +707,352 @@ fn set_temperature(comms: &State<InternalComms>, temperature : Json<TemperatureT
     resp_generic_result_or_err(comms.from_internal.recv())
 }
 
+#[post("/pid_autotune", format = "application/json", data = "<params>")]
+fn pid_autotune(_user: Admin, comms: &State<InternalComms>, params : ApiJson<PidAutotuneParams>) -> Result<(), ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::StartPidAutotune(*params)) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    resp_generic_result_or_err(comms.from_internal.recv())
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct ApplyPid {
+    save: Option<bool>
+}
+
+#[post("/apply_pid_autotune", format = "application/json", data = "<params>")]
+fn apply_pid_autotune(_user: Admin, comms: &State<InternalComms>, params : ApiJson<ApplyPid>) -> Result<(), ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::ApplyPidAutotune(params.save.unwrap_or(false))) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    resp_generic_result_or_err(comms.from_internal.recv())
+}
+
+// The last probed mesh, compared to the one before it
+#[get("/bed_mesh")]
+fn get_bed_mesh(_user: Viewer, data_dir: &State<DataDir>) -> Result<Json<BedMeshReport>, ApiError> {
+    Ok(Json(BedMeshReport::new(bed_mesh::load(data_dir)?, bed_mesh::load_previous(data_dir).ok())))
+}
+
+// Compares what the printer has now to the last probed mesh, without saving it
+#[post("/bed_mesh")]
+fn read_bed_mesh(_user: Operator, comms: &State<InternalComms>, data_dir: &State<DataDir>) -> Result<Json<BedMeshReport>, ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::ReadBedMesh) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    let mesh = match comms.from_internal.recv() {
+        Ok(PrinterResponse::BedMesh(Ok(mesh))) => { mesh }
+        Ok(PrinterResponse::GenericResult(Err(e))) | Ok(PrinterResponse::BedMesh(Err(e))) => {return Err(ApiError::from(e));}
+        Ok(_) => {return Err(ApiError::from(Error::new(ErrorKind::Unsupported, "Unexpected response")));}
+        Err(e) => {return Err(crossbeam_err_to_io_err(e));}
+    };
+
+    Ok(Json(BedMeshReport::new(mesh, bed_mesh::load(data_dir).ok())))
+}
+
+#[post("/probe_bed")]
+fn probe_bed(_user: Operator, comms: &State<InternalComms>) -> Result<(), ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::StartBedProbe) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    resp_generic_result_or_err(comms.from_internal.recv())
+}
+
+#[get("/firmware_settings")]
+fn get_firmware_settings(_user: Viewer, comms: &State<InternalComms>) -> Result<Json<FirmwareSettings>, ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::ReadFirmwareSettings) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    match comms.from_internal.recv() {
+        Ok(PrinterResponse::FirmwareSettings(Ok(settings))) => { Ok(Json(settings)) }
+        Ok(PrinterResponse::GenericResult(Err(e))) | Ok(PrinterResponse::FirmwareSettings(Err(e))) => {Err(ApiError::from(e))}
+        Ok(_) => {Err(ApiError::from(Error::new(ErrorKind::Unsupported, "Unexpected response")))}
+        Err(e) => {Err(crossbeam_err_to_io_err(e))}
+    }
+}
+
+// Apply changes on top of the last settings read from the printer. With dry_run, only return what would change.
+#[post("/firmware_settings?<save>&<dry_run>", format = "application/json", data = "<changes>")]
+fn write_firmware_settings(_user: Admin, comms: &State<InternalComms>, changes: ApiJson<FirmwareSettings>, save: Option<bool>, dry_run: Option<bool>) -> Result<Json<Vec<SettingChange>>, ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::WriteFirmwareSettings(Box::new(changes.into_inner()), save.unwrap_or(false), dry_run.unwrap_or(false))) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    match comms.from_internal.recv() {
+        Ok(PrinterResponse::FirmwareSettingsChanges(Ok(diff))) => { Ok(Json(diff)) }
+        Ok(PrinterResponse::GenericResult(Err(e))) | Ok(PrinterResponse::FirmwareSettingsChanges(Err(e))) => {Err(ApiError::from(e))}
+        Ok(_) => {Err(ApiError::from(Error::new(ErrorKind::Unsupported, "Unexpected response")))}
+        Err(e) => {Err(crossbeam_err_to_io_err(e))}
+    }
+}
+
+#[post("/start_esteps_calibration", format = "application/json", data = "<params>")]
+fn start_esteps_calibration(_user: Operator, comms: &State<InternalComms>, params : ApiJson<EStepsCalibrationParams>) -> Result<(), ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::StartEStepsCalibration(*params)) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    resp_generic_result_or_err(comms.from_internal.recv())
+}
+
+#[post("/esteps_measurement", format = "application/json", data = "<measurement>")]
+fn esteps_measurement(_user: Operator, comms: &State<InternalComms>, measurement : ApiJson<EStepsMeasurement>) -> Result<(), ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::SubmitEStepsMeasurement(*measurement)) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    resp_generic_result_or_err(comms.from_internal.recv())
+}
+
+#[post("/apply_esteps_calibration?<save>")]
+fn apply_esteps_calibration(_user: Admin, comms: &State<InternalComms>, save: Option<bool>) -> Result<(), ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::ApplyEStepsCalibration(save.unwrap_or(false))) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    resp_generic_result_or_err(comms.from_internal.recv())
+}
+
+#[post("/cancel_esteps_calibration")]
+fn cancel_esteps_calibration(_user: Operator, comms: &State<InternalComms>) -> Result<(), ApiError> {
+    if let Err(e) = comms.to_internal.send(PrinterCommand::CancelEStepsCalibration) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    resp_generic_result_or_err(comms.from_internal.recv())
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginParams {
+    name: String,
+    password: String
+}
+
+#[post("/login", format = "application/json", data = "<params>")]
+fn login(auth: &State<Auth>, cookies: &CookieJar<'_>, from: Option<IpAddr>, params: ApiJson<LoginParams>) -> Result<Json<Identity>, ApiError> {
+    let (token, identity) = auth.login(&params.name, &params.password, from)?;
+    cookies.add(Cookie::build((SESSION_COOKIE, token)).http_only(true).same_site(SameSite::Strict)
+        .max_age(rocket::time::Duration::hours(auth.config.session_hours as i64)));
+    Ok(Json(identity))
+}
+
+#[post("/logout")]
+fn logout(auth: &State<Auth>, cookies: &CookieJar<'_>) {
+    if let Some(cookie) = cookies.get(SESSION_COOKIE) {
+        auth.logout(cookie.value());
+    }
+    cookies.remove(SESSION_COOKIE);
+}
+
+#[get("/whoami")]
+fn whoami(user: Viewer) -> Json<Identity> {
+    Json(user.0)
+}
+
+#[derive(Debug, Deserialize)]
+struct UserParams {
+    name: String,
+    // Leave out to keep the current password
+    password: Option<String>,
+    role: Role
+}
+
+#[get("/users")]
+fn get_users(_user: Admin, auth: &State<Auth>) -> Json<Vec<UserInfo>> {
+    Json(auth.users())
+}
+
+#[post("/users", format = "application/json", data = "<params>")]
+fn set_user(_user: Admin, auth: &State<Auth>, params: ApiJson<UserParams>) -> Result<(), ApiError> {
+    Ok(auth.set_user(&params.name, params.password.as_deref(), params.role)?)
+}
+
+#[delete("/users?<name>")]
+fn delete_user(_user: Admin, auth: &State<Auth>, name: String) -> Result<(), ApiError> {
+    Ok(auth.delete_user(&name)?)
+}
+
+#[derive(Debug, Deserialize)]
+struct PasswordParams {
+    password: String
+}
+
+#[post("/change_password", format = "application/json", data = "<params>")]
+fn change_password(user: Viewer, auth: &State<Auth>, params: ApiJson<PasswordParams>) -> Result<(), ApiError> {
+    Ok(auth.change_password(&user.0, &params.password)?)
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiKeyParams {
+    name: String,
+    role: Role
+}
+
+#[derive(Serialize)]
+struct NewApiKey {
+    #[serde(flatten)]
+    info: ApiKeyInfo,
+    // Only shown this once
+    key: String
+}
+
+#[get("/api_keys")]
+fn get_api_keys(_user: Admin, auth: &State<Auth>) -> Json<Vec<ApiKeyInfo>> {
+    Json(auth.api_keys())
+}
+
+#[post("/api_keys", format = "application/json", data = "<params>")]
+fn create_api_key(_user: Admin, auth: &State<Auth>, params: ApiJson<ApiKeyParams>) -> Result<Json<NewApiKey>, ApiError> {
+    let (info, key) = auth.create_api_key(&params.name, params.role)?;
+    Ok(Json(NewApiKey { info, key }))
+}
+
+#[delete("/api_keys?<id>")]
+fn delete_api_key(_user: Admin, auth: &State<Auth>, id: String) -> Result<(), ApiError> {
+    Ok(auth.delete_api_key(&id)?)
+}
+
+// The part of the OctoPrint API slicers use to upload and start prints
+#[derive(FromForm)]
+struct OctoPrintUpload<'r> {
+    file: TempFile<'r>,
+    // Folder to put it in
+    path: Option<String>,
+    select: Option<bool>,
+    print: Option<bool>
+}
+
+#[get("/version")]
+fn octoprint_version(_user: Viewer) -> Json<octoprint::VersionInfo> {
+    Json(octoprint::VersionInfo::new())
+}
+
+#[post("/files/local", data = "<upload>")]
+async fn octoprint_upload(_user: Operator, mut upload: Form<OctoPrintUpload<'_>>, comms: &State<InternalComms>, data_dir: &State<DataDir>,
+    upload_config: &State<UploadConfig>) -> Result<Created<Json<octoprint::UploadResult>>, ApiError> {
+    let filename = upload.file.raw_name().map(|n| n.dangerous_unsafe_unsanitized_raw().as_str().to_string())
+    .ok_or(Error::new(ErrorKind::InvalidInput, "Upload has no file name"))?;
+    let folder = PathBuf::from(upload.path.clone().unwrap_or_default().trim_start_matches('/'));
+
+    let full_path = file::get_abs_gcode_path(data_dir, &folder.join(&filename))?;
+    if !file::is_gcode_file(&full_path) {
+        return Err(ApiError::from(Error::new(ErrorKind::InvalidInput, format!("{} isn't a .gcode, .gcode.gz or .gcode.zst file", filename))));
+    }
+    let name = file::get_rel_gcode_path(data_dir, &full_path);
+    upload::check_space(data_dir, upload.file.len(), upload_config)?;
+
+    // Like OctoPrint, replace the file unless it's being printed
+    if full_path.exists() {
+        send_generic_cmd(comms, PrinterCommand::DeleteGcodeFile(PathBuf::from(&name)))?;
+    }
+    if let Some(folder) = full_path.parent() {
+        std::fs::create_dir_all(folder)?;
+    }
+    upload.file.move_copy_to(&full_path).await?;
+    scan_in_background(data_dir.to_path_buf(), full_path);
+
+    let print = upload.print.unwrap_or(false);
+    if print || upload.select.unwrap_or(false) {
+        send_generic_cmd(comms, PrinterCommand::SetGcodeFile(PathBuf::from(&name)))?;
+    }
+    if print {
+        send_generic_cmd(comms, PrinterCommand::StartPrint(false))?;
+    }
+
+    let result = octoprint::UploadResult::new(&name);
+    Ok(Created::new(result.files.local.refs.resource.clone()).body(Json(result)))
+}
+
+#[get("/job")]
+fn octoprint_job(_user: Viewer, comms: &State<InternalComms>, data_dir: &State<DataDir>) -> Result<Json<octoprint::JobInfo>, ApiError> {
+    Ok(Json(octoprint::job_info(&get_status(comms)?, data_dir)))
+}
+
+#[get("/printer")]
+fn octoprint_printer(_user: Viewer, comms: &State<InternalComms>) -> Result<Json<octoprint::PrinterInfo>, ApiError> {
+    let status = get_status(comms)?;
+    if !status.printer_connected {
+        return Err(ApiError::new(ErrorCode::NOTCONNECTED, "Printer is not operational"));
+    }
+    Ok(Json(octoprint::printer_info(&status)))
+}
+
+// PrusaSlicer's "PrusaLink" printers
+struct PrusaLinkUploadOptions {
+    print_after: bool,
+    overwrite: bool
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PrusaLinkUploadOptions {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        // Structured header booleans, ?1 or ?0
+        let flag = |name| request.headers().get_one(name) == Some("?1");
+        request::Outcome::Success(PrusaLinkUploadOptions { print_after: flag("Print-After-Upload"), overwrite: flag("Overwrite") })
+    }
+}
+
+#[put("/v1/files/<storage>/<path..>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn prusalink_upload(_user: Operator, options: PrusaLinkUploadOptions, storage: &str, path: PathBuf, data: Data<'_>, comms: &State<InternalComms>,
+    data_dir: &State<DataDir>, upload_config: &State<UploadConfig>) -> Result<Created<()>, ApiError> {
+    if !prusalink::STORAGE_NAMES.contains(&storage) {
+        return Err(ApiError::from(Error::new(ErrorKind::NotFound, format!("No storage called {}", storage))));
+    }
+
+    if options.overwrite && file::get_abs_gcode_path(data_dir, &path)?.exists() {
+        send_generic_cmd(comms, PrinterCommand::DeleteGcodeFile(path.clone()))?;
+    }
+    let name = store_gcode(data, &path, data_dir, upload_config).await?;
+
+    if options.print_after {
+        send_generic_cmd(comms, PrinterCommand::SetGcodeFile(PathBuf::from(&name)))?;
+        send_generic_cmd(comms, PrinterCommand::StartPrint(false))?;
+    }
+    Ok(Created::new(format!("/api/v1/files/{}/{}", storage, name)))
+}
+
+#[get("/v1/storage")]
+fn prusalink_storage(_user: Viewer, data_dir: &State<DataDir>) -> Json<prusalink::StorageList> {
+    Json(prusalink::storage_list(data_dir))
+}
+
+#[get("/v1/status")]
+fn prusalink_status(_user: Viewer, comms: &State<InternalComms>) -> Result<Json<prusalink::Status>, ApiError> {
+    Ok(Json(prusalink::status(&get_status(comms)?)))
+}
+
+#[derive(Responder)]
+enum PrusaLinkJob {
+    Job(Json<prusalink::Job>),
+    NoJob(NoContent)
+}
+
+#[get("/v1/job")]
+fn prusalink_job(_user: Viewer, comms: &State<InternalComms>, data_dir: &State<DataDir>) -> Result<PrusaLinkJob, ApiError> {
+    Ok(match prusalink::job(&get_status(comms)?, data_dir) {
+        Some(job) => PrusaLinkJob::Job(Json(job)),
+        None => PrusaLinkJob::NoJob(NoContent)
+    })
+}
+
+fn send_generic_cmd(comms: &InternalComms, cmd: PrinterCommand) -> Result<(), ApiError> {
+    if let Err(e) = comms.to_internal.send(cmd) {
+        return Err(crossbeam_err_to_io_err(e));
+    }
+
+    resp_generic_result_or_err(comms.from_internal.recv())
+}
+
 #[get("/console")]
-fn console(ws: rocket_ws::WebSocket, comms: &State<InternalComms>) -> rocket_ws::Channel<'_> {
+fn console(_user: Admin, ws: rocket_ws::WebSocket, comms: &State<InternalComms>) -> rocket_ws::Channel<'_> {
 
     ws.channel(move |mut ws_stream| Box::pin(async move {
         use rocket::futures::{StreamExt, SinkExt};
@@ -359,6 +1134,58 @@ fn console(ws: rocket_ws::WebSocket, comms: &State<InternalComms>) -> rocket_ws:
     }))
 }
 
+#[get("/events")]
+fn events(_user: Viewer, ws: rocket_ws::WebSocket, event_bus: &State<EventBus>) -> rocket_ws::Channel<'_> {
+
+    ws.channel(move |mut ws_stream| Box::pin(async move {
+        use rocket::futures::{StreamExt, SinkExt};
+
+        let mut async_recv_events = RecvChannelAsyncWrapper::new(event_bus.subscribe());
+
+        loop {
+            let ws_stream_future = async {
+                ws_stream.next().await
+            };
+            let async_recv_events_future = async {
+                async_recv_events.next().await
+            };
+
+            pin_mut!(ws_stream_future); // Clients only talk to us to close the socket
+            pin_mut!(async_recv_events_future);
+            match rocket::futures::future::select(ws_stream_future, async_recv_events_future).await {
+                Either::Left((ws_msg, _)) => {
+                    match ws_msg {
+                        Some(Ok(Message::Close(_))) | None => {
+                            info!("Events websocket closed");
+                            break;
+                        }
+                        Some(Err(e)) => {
+                            error!("Got events websocket error: {:?}", e);
+                            break;
+                        }
+                        Some(Ok(_)) => {}
+                    }
+                },
+                Either::Right((event, _)) => {
+                    match event {
+                        Some(event) => {
+                            if let Err(e) = ws_stream.send(rocket_ws::Message::Text(rocket::serde::json::to_string(&event).unwrap())).await {
+                                error!("Websocket send failed: {:?}", e);
+                                break;
+                            }
+                        },
+                        None => {
+                            info!("Event channel closed.");
+                            break;
+                        }
+                    }
+                }
+            };
+        }
+        Ok(())
+    }))
+}
+
 #[get("/")]
 async fn index(webui_dir: &State<WebUiDir>) -> std::option::Option<NamedFile> {
     info!("Current dir: {:?}. Index: {:?}", std::env::current_dir(), webui_dir.0.as_path().join("index.html"));
@@ -375,28 +1202,205 @@ impl<'r> rocket::response::Responder<'r, 'static> for ApiError {
         let as_string = rocket::serde::json::to_string(&self).unwrap();
         Ok(rocket::Response::build().
         header(rocket::http::ContentType::JSON).
-        status(rocket::http::Status::InternalServerError)
+        status(self.status())
         .sized_body(as_string.len(), std::io::Cursor::new(as_string))
         .finalize())
     }
 }
 
-pub fn run_api(to_internal: Sender<PrinterCommand>, from_internal: Receiver<PrinterResponse>, data_dir: PathBuf, webui_dir: PathBuf) {
-    let cors = CorsOptions::default().to_cors().unwrap();
+pub fn run_api(to_internal: Sender<PrinterCommand>, from_internal: Receiver<PrinterResponse>, data_dir: PathBuf, webui_dir: PathBuf, config: Config, import_log: ImportLog, event_bus: EventBus) {
+    let auth = match Auth::load(&data_dir, &config.auth) {
+        Ok(auth) => auth,
+        Err(e) => {
+            error!("Error loading users, the API isn't available: {}", e);
+            return;
+        }
+    };
+    // Slicers set up with the key from the config keep working
+    if let Some(key) = &config.octoprint.api_key {
+        match auth.import_api_key("octoprint", key, Role::OPERATOR) {
+            Ok(Some(info)) => warn!("Moved octoprint.api_key from the config into API key {} with the OPERATOR role, it can be removed from the config", info.id),
+            Ok(None) => {}
+            Err(e) => error!("Error moving octoprint.api_key from the config into the API keys: {}", e)
+        }
+    }
+
+    let api_rocket = build_api(InternalComms{to_internal: to_internal, from_internal:from_internal}, data_dir, webui_dir, config, auth, import_log, event_bus);
+    return rocket::execute(async move {
+        let _rocket = api_rocket.launch().await.expect("Error launching REST API");
+    });
+}
+
+fn build_api(comms: InternalComms, data_dir: PathBuf, webui_dir: PathBuf, config: Config, auth: Auth, import_log: ImportLog, event_bus: EventBus) -> rocket::Rocket<rocket::Build> {
+    let cors = CorsOptions {
+        allowed_origins: AllowedOrigins::some_exact(&config.auth.allowed_origins),
+        allow_credentials: true,
+        ..CorsOptions::default()
+    }.to_cors().unwrap();
+
+    // Slicers upload whole files as multipart forms
+    let upload_limit = ByteUnit::Mebibyte(config.upload.max_size_mb);
+    let limits = rocket::data::Limits::default().limit("file", upload_limit).limit("data-form", upload_limit + ByteUnit::Mebibyte(1));
+    let figment = rocket::Config::figment().merge(("limits", limits));
 
-    let api_rocket = rocket::build()
-    .mount("/api", routes![connect, status, home, move_rel, upload_gcode, 
-                                list_gcode, set_gcode, delete_gcode, start_print, stop_print, 
+    rocket::custom(figment)
+    .mount("/api", routes![connect, status, home, disable_steppers, move_rel, upload_gcode, start_upload, upload_status, upload_chunk, finish_upload, cancel_upload, hot_folder_imports,
+                                get_history, delete_history, history_stats,
+                                list_gcode, get_gcode_info, gcode_thumbnail, set_gcode, delete_gcode, create_gcode_folder, delete_gcode_folder, move_gcode, start_print, validate_print, stop_print, 
                                 pause_print, set_temperature, set_fan_speed, 
-                                console, printer_info])
+                                console, events, printer_info, temperature_history, layer_durations, pid_autotune,
+                                apply_pid_autotune, get_bed_mesh, read_bed_mesh, probe_bed, get_firmware_settings,
+                                write_firmware_settings, start_esteps_calibration, esteps_measurement,
+                                apply_esteps_calibration, cancel_esteps_calibration,
+
+                                octoprint_version, octoprint_upload, octoprint_job, octoprint_printer,
+                                prusalink_upload, prusalink_storage, prusalink_status, prusalink_job,
+
+                                login, logout, whoami, get_users, set_user, delete_user, change_password, get_api_keys, create_api_key, delete_api_key])
     .mount("/", routes![index, serve_file])
-    .manage(InternalComms{to_internal: to_internal, from_internal:from_internal})
+    .register("/api", catchers![api_catcher])
+    .manage(comms)
     .manage(data_dir as DataDir)
+    .manage(config.upload)
+    .manage(auth)
+    .manage(import_log)
+    .manage(event_bus)
     .manage(WebUiDir(webui_dir))
-    .attach(cors);
+    .attach(cors)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::{ContentType, Header};
+    use rocket::local::blocking::{Client, LocalResponse};
+    use rocket::serde::json::Value;
 
-    
-    return rocket::execute(async move {
-        let _rocket = api_rocket.launch().await.expect("Error launching REST API");
-    });
-}
\ No newline at end of file
+    // The API with a user for each role and an OPERATOR API key, there's no printer behind it
+    fn client(name: &str) -> (Client, String, PathBuf) {
+        let data_dir = std::env::temp_dir().join(format!("yoctoprint-rest-api-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let config = Config::default();
+        let auth = Auth::load(&data_dir, &config.auth).unwrap();
+        auth.set_user("viewer", Some("viewer password"), Role::VIEWER).unwrap();
+        auth.set_user("operator", Some("operator password"), Role::OPERATOR).unwrap();
+        let (_, api_key) = auth.create_api_key("slicer", Role::OPERATOR).unwrap();
+
+        let (to_internal, _) = crossbeam::channel::unbounded();
+        let (_, from_internal) = crossbeam::channel::unbounded();
+        let rocket = build_api(InternalComms { to_internal, from_internal }, data_dir.clone(), data_dir.join("ui"), config, auth,
+            ImportLog::default(), EventBus::default());
+        (Client::tracked(rocket).unwrap(), api_key, data_dir)
+    }
+
+    fn login(client: &Client, name: &str, password: &str) -> Status {
+        client.post("/api/login").header(ContentType::JSON)
+        .body(serde_json::json!({"name": name, "password": password}).to_string()).dispatch().status()
+    }
+
+    fn whoami(response: LocalResponse<'_>) -> (String, String) {
+        let identity = response.into_json::<Value>().unwrap();
+        (identity["role"].as_str().unwrap().to_string(), identity["credential"].as_str().unwrap().to_string())
+    }
+
+    fn error_code(response: LocalResponse<'_>) -> String {
+        response.into_json::<Value>().unwrap()["code"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn routes_need_their_role() {
+        let (client, _, data_dir) = client("roles");
+
+        // Nobody logged in
+        let response = client.get("/api/status").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(error_code(response), "UNAUTHORIZED");
+        assert_eq!(client.get("/api/console").dispatch().status(), Status::Unauthorized);
+
+        // Viewers can look, but not move the printer
+        assert_eq!(login(&client, "viewer", "viewer password"), Status::Ok);
+        assert_eq!(whoami(client.get("/api/whoami").dispatch()), ("VIEWER".to_string(), "SESSION".to_string()));
+        let response = client.post("/api/home").header(ContentType::JSON).body(r#"{"axes": ["X"]}"#).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(error_code(response), "FORBIDDEN");
+        assert_eq!(client.get("/api/console").dispatch().status(), Status::Forbidden);
+
+        // The console is only for admins
+        client.post("/api/logout").dispatch();
+        assert_eq!(client.get("/api/whoami").dispatch().status(), Status::Unauthorized);
+        assert_eq!(login(&client, "operator", "operator password"), Status::Ok);
+        assert_eq!(client.get("/api/console").dispatch().status(), Status::Forbidden);
+        assert_eq!(client.get("/api/users").dispatch().status(), Status::Forbidden);
+
+        let admin_password = std::fs::read_to_string(data_dir.join(crate::auth::ADMIN_PASSWORD_FILE)).unwrap();
+        assert_eq!(login(&client, "admin", &admin_password), Status::Ok);
+        assert!(![Status::Unauthorized, Status::Forbidden].contains(&client.get("/api/console").dispatch().status()));
+        assert_eq!(client.get("/api/users").dispatch().status(), Status::Ok);
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn api_key_or_session_cookie() {
+        let (client, api_key, data_dir) = client("api-key");
+
+        let response = client.get("/api/whoami").header(Header::new("X-Api-Key", api_key.clone())).dispatch();
+        assert_eq!(whoami(response), ("OPERATOR".to_string(), "APIKEY".to_string()));
+        assert_eq!(client.get("/api/whoami").header(Header::new("X-Api-Key", "not a key")).dispatch().status(), Status::Unauthorized);
+
+        // A key that's sent is the one that counts, even with a session cookie
+        assert_eq!(login(&client, "viewer", "viewer password"), Status::Ok);
+        assert_eq!(whoami(client.get("/api/whoami").dispatch()), ("VIEWER".to_string(), "SESSION".to_string()));
+        let response = client.get("/api/whoami").header(Header::new("X-Api-Key", api_key)).dispatch();
+        assert_eq!(whoami(response), ("OPERATOR".to_string(), "APIKEY".to_string()));
+        assert_eq!(client.get("/api/whoami").header(Header::new("X-Api-Key", "not a key")).dispatch().status(), Status::Unauthorized);
+
+        // Wrong passwords are turned away like a missing login
+        assert_eq!(login(&client, "viewer", "wrong password"), Status::Unauthorized);
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    fn error_fields(response: LocalResponse<'_>) -> Vec<String> {
+        response.into_json::<Value>().unwrap()["fields"].as_array().unwrap().iter()
+        .map(|f| f["field"].as_str().unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn json_errors() {
+        let (client, api_key, data_dir) = client("json");
+        let post_json = |path: &str, body: &str| client.post(path.to_string()).header(ContentType::JSON)
+            .header(Header::new("X-Api-Key", api_key.clone())).body(body).dispatch();
+
+        let response = post_json("/api/login", r#"{"name": "#);
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(error_code(response), "INVALIDINPUT");
+
+        // Missing and wrong fields are named, nested ones by their path
+        let response = post_json("/api/login", r#"{"name": "admin"}"#);
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(error_fields(response), vec!["password"]);
+        let response = post_json("/api/set_temperature", r#"{"to_set": "HOTEND", "target": "hot"}"#);
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(error_fields(response), vec!["target"]);
+        let admin_password = std::fs::read_to_string(data_dir.join(crate::auth::ADMIN_PASSWORD_FILE)).unwrap();
+        assert_eq!(login(&client, "admin", &admin_password), Status::Ok);
+        let response = client.post("/api/firmware_settings").header(ContentType::JSON).body(r#"{"steps_per_mm": {"E": "fast"}}"#).dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(error_fields(response), vec!["steps_per_mm.E"]);
+
+        // Rocket's own errors look the same
+        let response = client.get("/api/no_such_route").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        assert_eq!(error_code(response), "NOTFOUND");
+
+        // A failed guard keeps the reason it gave
+        let response = client.get("/api/users").header(Header::new("X-Api-Key", api_key.clone())).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let error = response.into_json::<Value>().unwrap();
+        assert_eq!(error["code"], "FORBIDDEN");
+        assert_eq!(error["description"], "slicer isn't allowed to do this, it needs the ADMIN role");
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
@@ -5,17 +5,43 @@ use std::io::*;
 use crate::internal_api;
 use enumset::EnumSet;
 use internal_api::*;
+use crate::firmware_settings::{FirmwareSettings, SettingValues};
 use log::{debug, info, error, warn};
 
 #[derive(Debug)]
 #[derive(PartialEq)]
+pub enum AutotuneUpdate {
+    Started,
+    CycleDone,
+    Values(PidValues),
+    Finished,
+    Failed(String)
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum MeshLine {
+    Row(u32, Vec<Option<f64>>),
+    ProbePoint(f64, f64, f64)
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Response {
     NONE,
     BUSY,
     OK,
     TEMPERATURE(Vec<Temperature>, Option<u32>),
     POSITION(Position),
-    NACK(u32)
+    NACK(u32),
+    AUTOTUNE(AutotuneUpdate),
+    MESH(MeshLine),
+    // Group, extruder index, values
+    SETTING(&'static str, u32, SettingValues),
+    UNKNOWN(String),
+    // Something went wrong in the firmware, e.g. a heater fault
+    ERROR(String)
 }
 
 #[derive(Debug, Default, PartialEq)]
@@ -39,7 +65,13 @@ pub enum OutgoingCmd {
     PositionModeChange(PositionModeCmd),
     FanSpeedChange((u32, f64)),
     HomeAxes(EnumSet<Axis>),
-    PositionChange(Position)
+    EnableSteppers(EnumSet<Axis>),
+    DisableSteppers(EnumSet<Axis>),
+    // How long the firmware waits without moving before turning the steppers off, None for never
+    SetStepperTimeout(Option<std::time::Duration>),
+    PositionChange(Position),
+    // Breaks out of whatever the printer is waiting on
+    Interrupt
 }
 
 pub trait SerialProtocol {
@@ -60,6 +92,17 @@ pub trait SerialProtocol {
     fn get_report_position_cmd(&self) -> String;
     fn get_retract_extruder_cmd(&self) -> String;
     fn get_recover_extruder_cmd(&self) -> String;
+    fn get_disable_steppers_cmd(&self, axes: &EnumSet<Axis>) -> String;
+    fn get_pid_autotune_cmd(&self, params: &PidAutotuneParams) -> std::io::Result<String>;
+    fn get_set_pid_cmds(&self, heater: ProbePoint, index: u32, values: &PidValues) -> std::io::Result<Vec<String>>;
+    fn get_save_settings_cmd(&self) -> String;
+    fn get_report_bed_mesh_cmd(&self) -> String;
+    fn get_probe_bed_cmds(&self) -> Vec<String>;
+    fn get_report_settings_cmd(&self) -> String;
+    fn get_extrude_cmd(&self, length: f64, feedrate: f64) -> String;
+    fn get_wait_for_moves_cmd(&self) -> String;
+    // Commands to change the settings that differ between old and new
+    fn get_write_settings_cmds(&self, old: &FirmwareSettings, new: &FirmwareSettings) -> Vec<String>;
 }
 
 pub struct PrinterComms {
@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rocket::serde::Serialize;
+
+use crate::internal_api::Temperature;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TemperatureSample {
+    pub secs_since_epoch: f64,
+    pub temperatures: Vec<Temperature>
+}
+
+// Bounded, time-stamped history of every heater reading we've seen.
+pub struct TemperatureHistory {
+    samples: VecDeque<TemperatureSample>,
+    max_age: Duration,
+    max_samples: usize
+}
+
+impl TemperatureHistory {
+    // At the 2s autoreport interval, this keeps a little over an hour of readings.
+    pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+    pub const DEFAULT_MAX_SAMPLES: usize = 2048;
+    // Longer windows get averaged down to at most this many points.
+    pub const MAX_POINTS_RETURNED: usize = 360;
+
+    pub fn new() -> Self {
+        TemperatureHistory::with_limits(TemperatureHistory::DEFAULT_MAX_AGE, TemperatureHistory::DEFAULT_MAX_SAMPLES)
+    }
+
+    pub fn with_limits(max_age: Duration, max_samples: usize) -> Self {
+        TemperatureHistory { samples: VecDeque::new(), max_age, max_samples }
+    }
+
+    fn now_secs() -> f64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+    }
+
+    pub fn add(&mut self, temperatures: &[Temperature]) {
+        self.add_at(temperatures, TemperatureHistory::now_secs());
+    }
+
+    pub fn add_at(&mut self, temperatures: &[Temperature], secs_since_epoch: f64) {
+        if temperatures.is_empty() {
+            return;
+        }
+
+        self.samples.push_back(TemperatureSample { secs_since_epoch, temperatures: temperatures.to_vec() });
+
+        let oldest_allowed = secs_since_epoch - self.max_age.as_secs_f64();
+        while self.samples.len() > self.max_samples ||
+            self.samples.front().is_some_and(|s| s.secs_since_epoch < oldest_allowed) {
+            self.samples.pop_front();
+        }
+    }
+
+    // Get every sample taken after since (seconds since epoch), or the whole history if None.
+    // If there are more than max_points, adjacent samples are averaged together.
+    pub fn get_since(&self, since: Option<f64>, max_points: usize) -> Vec<TemperatureSample> {
+        let selected : Vec<&TemperatureSample> = self.samples.iter()
+        .filter(|s| since.is_none_or(|since| s.secs_since_epoch > since))
+        .collect();
+
+        if max_points == 0 || selected.len() <= max_points {
+            return selected.into_iter().cloned().collect();
+        }
+
+        let bucket_size = selected.len().div_ceil(max_points);
+        selected.chunks(bucket_size).map(TemperatureHistory::average).collect()
+    }
+
+    fn average(bucket: &[&TemperatureSample]) -> TemperatureSample {
+        let last = bucket.last().unwrap();
+        let mut averaged = last.temperatures.clone();
+
+        for temp in averaged.iter_mut() {
+            let matching : Vec<&Temperature> = bucket.iter()
+            .flat_map(|s| s.temperatures.iter())
+            .filter(|t| t.measured_from == temp.measured_from && t.index == temp.index)
+            .collect();
+
+            let n = matching.len() as f64;
+            temp.current = matching.iter().map(|t| t.current).sum::<f64>() / n;
+            temp.power = matching.iter().map(|t| t.power).sum::<f64>() / n;
+            // Keep the latest target, averaging a setpoint change makes no sense
+        }
+
+        TemperatureSample { secs_since_epoch: last.secs_since_epoch, temperatures: averaged }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+
+    use super::*;
+    use crate::internal_api::ProbePoint;
+
+    fn hotend(current: f64, target: f64) -> Temperature {
+        Temperature { measured_from: ProbePoint::HOTEND, index: 0, power: 0.5, current, target }
+    }
+
+    #[test]
+    fn drops_old_samples() {
+        let mut history = TemperatureHistory::with_limits(Duration::from_secs(10), 100);
+        for t in 0..20 {
+            history.add_at(&[hotend(t as f64, 200.)], t as f64);
+        }
+
+        assert_eq!(history.get_since(None, 0).len(), 11);
+        assert_eq!(history.get_since(None, 0)[0].secs_since_epoch, 9.);
+    }
+
+    #[test]
+    fn bounded_sample_count() {
+        let mut history = TemperatureHistory::with_limits(Duration::from_secs(1000), 5);
+        for t in 0..20 {
+            history.add_at(&[hotend(t as f64, 200.)], t as f64);
+        }
+
+        assert_eq!(history.get_since(None, 0).len(), 5);
+    }
+
+    #[test]
+    fn since_filters_samples() {
+        let mut history = TemperatureHistory::with_limits(Duration::from_secs(1000), 100);
+        for t in 0..10 {
+            history.add_at(&[hotend(t as f64, 200.)], t as f64);
+        }
+
+        let since = history.get_since(Some(6.5), 0);
+        assert_eq!(since.len(), 3);
+        assert_eq!(since[0].secs_since_epoch, 7.);
+    }
+
+    #[test]
+    fn downsamples_long_windows() {
+        let mut history = TemperatureHistory::with_limits(Duration::from_secs(1000), 100);
+        for t in 0..10 {
+            history.add_at(&[hotend(t as f64, if t < 9 {200.} else {210.})], t as f64);
+        }
+
+        let downsampled = history.get_since(None, 5);
+        assert_eq!(downsampled.len(), 5);
+        assert_approx_eq!(downsampled[0].temperatures[0].current, 0.5);
+        assert_eq!(downsampled[0].secs_since_epoch, 1.);
+        assert_approx_eq!(downsampled[4].temperatures[0].current, 8.5);
+        assert_approx_eq!(downsampled[4].temperatures[0].target, 210.);
+    }
+}
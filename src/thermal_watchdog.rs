@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::{HeaterWatchConfig, ThermalConfig};
+use crate::internal_api::{ProbePoint, Temperature};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ThermalFault {
+    HeatingFailed { heater: ProbePoint, index: u32, current: f64, target: f64 },
+    TemperatureDropped { heater: ProbePoint, index: u32, current: f64, target: f64 },
+    OverTemperature { heater: ProbePoint, index: u32, current: f64, max: f64 },
+    StaleReport(Duration)
+}
+
+impl std::fmt::Display for ThermalFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThermalFault::HeatingFailed { heater, index, current, target } =>
+                write!(f, "{:?} {} failed to heat up (at {:.1}, target {:.1})", heater, index, current, target),
+            ThermalFault::TemperatureDropped { heater, index, current, target } =>
+                write!(f, "{:?} {} fell away from its target (at {:.1}, target {:.1})", heater, index, current, target),
+            ThermalFault::OverTemperature { heater, index, current, max } =>
+                write!(f, "{:?} {} is above its maximum temperature (at {:.1}, max {:.1})", heater, index, current, max),
+            ThermalFault::StaleReport(since) =>
+                write!(f, "No temperature report for {} seconds while heaters are on", since.as_secs())
+        }
+    }
+}
+
+struct HeaterWatch {
+    target: f64,
+    watch_started: Instant,
+    watch_start_temp: f64,
+    reached_target: bool,
+    deviating_since: Option<Instant>
+}
+
+impl HeaterWatch {
+    fn new(temp: &Temperature, now: Instant) -> Self {
+        HeaterWatch { target: temp.target, watch_started: now, watch_start_temp: temp.current, reached_target: false, deviating_since: None }
+    }
+}
+
+// Host-side check of the temperature reports, in case the firmware's own thermal protection is missing or misconfigured.
+pub struct ThermalWatchdog {
+    config: ThermalConfig,
+    heaters: HashMap<(ProbePoint, u32), HeaterWatch>,
+    last_report: Instant,
+    fault: Option<ThermalFault>
+}
+
+impl ThermalWatchdog {
+    pub fn new(config: ThermalConfig) -> Self {
+        ThermalWatchdog { config, heaters: HashMap::new(), last_report: Instant::now(), fault: None }
+    }
+
+    pub fn config(&self) -> &ThermalConfig {
+        &self.config
+    }
+
+    fn heater_config(&self, heater: ProbePoint) -> Option<&HeaterWatchConfig> {
+        match heater {
+            ProbePoint::HOTEND => Some(&self.config.hotend),
+            ProbePoint::BED => Some(&self.config.bed),
+            _ => None
+        }
+    }
+
+    // Feed a new temperature report. Any fault is kept until the next check().
+    pub fn on_report(&mut self, temps: &[Temperature], printing: bool, now: Instant) {
+        self.last_report = now;
+
+        if !self.config.enabled || self.fault.is_some() {
+            return;
+        }
+
+        for temp in temps {
+            if let Some(fault) = self.check_heater(temp, printing, now) {
+                self.fault = Some(fault);
+                return;
+            }
+        }
+    }
+
+    fn check_heater(&mut self, temp: &Temperature, printing: bool, now: Instant) -> Option<ThermalFault> {
+        let config = self.heater_config(temp.measured_from)?.clone();
+
+        if temp.current > config.max_temperature {
+            return Some(ThermalFault::OverTemperature { heater: temp.measured_from, index: temp.index, current: temp.current, max: config.max_temperature });
+        }
+
+        let watch = self.heaters.entry((temp.measured_from, temp.index)).or_insert_with(|| HeaterWatch::new(temp, now));
+        if watch.target != temp.target {
+            *watch = HeaterWatch::new(temp, now);
+        }
+
+        if temp.target <= 0. {
+            return None;
+        }
+
+        if !watch.reached_target {
+            if temp.current >= temp.target - config.hysteresis {
+                watch.reached_target = true;
+            } else if now - watch.watch_started >= Duration::from_secs(config.watch_period_secs) {
+                if temp.current < watch.watch_start_temp + config.watch_increase {
+                    return Some(ThermalFault::HeatingFailed { heater: temp.measured_from, index: temp.index, current: temp.current, target: temp.target });
+                }
+                watch.watch_started = now;
+                watch.watch_start_temp = temp.current;
+            }
+            return None;
+        }
+
+        if printing && temp.current < temp.target - config.max_deviation {
+            let deviating_since = *watch.deviating_since.get_or_insert(now);
+            if now - deviating_since >= Duration::from_secs(config.deviation_period_secs) {
+                return Some(ThermalFault::TemperatureDropped { heater: temp.measured_from, index: temp.index, current: temp.current, target: temp.target });
+            }
+        } else {
+            watch.deviating_since = None;
+        }
+        None
+    }
+
+    // Returns a fault found in the reports so far, or if the reports have stopped while heaters are on.
+    pub fn check(&mut self, now: Instant) -> Option<ThermalFault> {
+        if let Some(fault) = self.fault.take() {
+            return Some(fault);
+        }
+
+        let heaters_on = self.heaters.values().any(|h| h.target > 0.);
+        let since_report = now.saturating_duration_since(self.last_report);
+        if self.config.enabled && heaters_on && since_report >= Duration::from_secs(self.config.stale_report_secs) {
+            return Some(ThermalFault::StaleReport(since_report));
+        }
+        None
+    }
+
+    // Forget everything we know about the heaters, e.g: after we've turned them off due to a fault.
+    pub fn reset(&mut self) {
+        self.heaters.clear();
+        self.last_report = Instant::now();
+        self.fault = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hotend(current: f64, target: f64) -> Temperature {
+        Temperature { measured_from: ProbePoint::HOTEND, index: 0, power: 1., current, target }
+    }
+
+    fn secs(start: Instant, s: u64) -> Instant {
+        start + Duration::from_secs(s)
+    }
+
+    #[test]
+    fn heating_normally() {
+        let mut watchdog = ThermalWatchdog::new(ThermalConfig::default());
+        let start = Instant::now();
+
+        for s in 0..100 {
+            watchdog.on_report(&[hotend(20. + s as f64 * 2., 200.)], false, secs(start, s));
+            assert_eq!(watchdog.check(secs(start, s)), None);
+        }
+    }
+
+    #[test]
+    fn heater_not_rising() {
+        let mut watchdog = ThermalWatchdog::new(ThermalConfig::default());
+        let start = Instant::now();
+
+        watchdog.on_report(&[hotend(20., 200.)], false, start);
+        watchdog.on_report(&[hotend(21., 200.)], false, secs(start, 15));
+        assert_eq!(watchdog.check(secs(start, 15)), None);
+
+        watchdog.on_report(&[hotend(21., 200.)], false, secs(start, 31));
+        assert!(matches!(watchdog.check(secs(start, 31)), Some(ThermalFault::HeatingFailed{..})));
+    }
+
+    #[test]
+    fn temperature_drops_while_printing() {
+        let mut watchdog = ThermalWatchdog::new(ThermalConfig::default());
+        let start = Instant::now();
+
+        watchdog.on_report(&[hotend(199., 200.)], true, start);
+        watchdog.on_report(&[hotend(150., 200.)], true, secs(start, 1));
+        assert_eq!(watchdog.check(secs(start, 1)), None);
+
+        watchdog.on_report(&[hotend(140., 200.)], true, secs(start, 45));
+        assert!(matches!(watchdog.check(secs(start, 45)), Some(ThermalFault::TemperatureDropped{..})));
+    }
+
+    #[test]
+    fn temperature_drop_recovers() {
+        let mut watchdog = ThermalWatchdog::new(ThermalConfig::default());
+        let start = Instant::now();
+
+        watchdog.on_report(&[hotend(199., 200.)], true, start);
+        watchdog.on_report(&[hotend(150., 200.)], true, secs(start, 1));
+        watchdog.on_report(&[hotend(195., 200.)], true, secs(start, 20));
+        watchdog.on_report(&[hotend(150., 200.)], true, secs(start, 45));
+        assert_eq!(watchdog.check(secs(start, 45)), None);
+    }
+
+    #[test]
+    fn over_temperature() {
+        let mut watchdog = ThermalWatchdog::new(ThermalConfig::default());
+        let start = Instant::now();
+
+        watchdog.on_report(&[hotend(300., 0.)], false, start);
+        assert!(matches!(watchdog.check(start), Some(ThermalFault::OverTemperature{..})));
+    }
+
+    #[test]
+    fn stale_report() {
+        let mut watchdog = ThermalWatchdog::new(ThermalConfig::default());
+        let start = Instant::now();
+
+        watchdog.on_report(&[hotend(20., 0.)], false, start);
+        assert_eq!(watchdog.check(secs(start, 60)), None);
+
+        watchdog.on_report(&[hotend(20., 200.)], false, start);
+        assert!(matches!(watchdog.check(secs(start, 60)), Some(ThermalFault::StaleReport(_))));
+    }
+
+    #[test]
+    fn disabled() {
+        let mut watchdog = ThermalWatchdog::new(ThermalConfig { enabled: false, ..ThermalConfig::default() });
+        let start = Instant::now();
+
+        watchdog.on_report(&[hotend(300., 200.)], false, start);
+        assert_eq!(watchdog.check(secs(start, 60)), None);
+    }
+}
@@ -0,0 +1,231 @@
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{info, warn};
+use rocket::serde::{Serialize, Deserialize};
+
+use crate::api_error::{coded_error, ErrorCode};
+use crate::config::UploadConfig;
+use crate::file;
+
+pub const UPLOAD_DIR: &str = "uploads";
+
+const MB: u64 = 1024 * 1024;
+
+// Uploads nobody has added to for this long are given up on
+const UPLOAD_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+// An upload sent in chunks. If the connection drops, ask for the status and carry on from `received`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UploadStatus {
+    pub id: String,
+    // Where it'll end up, relative to the gcode dir
+    pub filename: String,
+    pub size: u64,
+    #[serde(skip_deserializing)]
+    pub received: u64
+}
+
+// Ids are only ever hex, anything else could be used to get at other files
+fn upload_path(data_dir: &Path, id: &str, extension: &str) -> std::io::Result<PathBuf> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid upload id {}", id)));
+    }
+    Ok(data_dir.join(UPLOAD_DIR).join(format!("{}.{}", id, extension)))
+}
+
+// The uploads that have been started, but not finished or cancelled
+fn outstanding(data_dir: &Path) -> std::io::Result<Vec<UploadStatus>> {
+    let dir = match std::fs::read_dir(data_dir.join(UPLOAD_DIR)) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        other => other?
+    };
+    let mut uploads = Vec::new();
+    for entry in dir {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            let id = path.file_stem().unwrap_or_default().to_string_lossy();
+            match status(data_dir, &id) {
+                Ok(upload) => uploads.push(upload),
+                Err(e) => warn!("Skipping upload {}: {}", id, e)
+            }
+        }
+    }
+    Ok(uploads)
+}
+
+// Throw away uploads that haven't had a chunk for a long time, their client isn't coming back
+pub fn remove_stale(data_dir: &Path) -> std::io::Result<()> {
+    for upload in outstanding(data_dir)? {
+        let modified = std::fs::metadata(upload_path(data_dir, &upload.id, "part")?)?.modified()?;
+        if modified.elapsed().unwrap_or_default() > UPLOAD_EXPIRY {
+            info!("Removing stale upload {} of {}", upload.id, upload.filename);
+            cancel(data_dir, &upload.id)?;
+        }
+    }
+    Ok(())
+}
+
+// Make sure a file of this size is allowed and there's room for it,
+// on top of what the uploads in progress still need
+pub fn check_space(data_dir: &Path, size: u64, config: &UploadConfig) -> std::io::Result<()> {
+    if size > config.max_size_mb * MB {
+        return Err(coded_error(ErrorCode::TOOLARGE, format!("File is bigger than the limit of {} MB", config.max_size_mb)));
+    }
+
+    remove_stale(data_dir)?;
+    let reserved: u64 = outstanding(data_dir)?.iter().map(|upload| upload.size - upload.received).sum();
+    let available = fs2::available_space(data_dir)?.saturating_sub(reserved);
+    if available < size + config.min_free_space_mb * MB {
+        return Err(Error::new(ErrorKind::StorageFull, format!("Not enough free space, {} MB available", available / MB)));
+    }
+    Ok(())
+}
+
+// The gcode file an upload will become, which must not exist yet
+fn destination(data_dir: &Path, filename: &str) -> std::io::Result<PathBuf> {
+    let path = file::get_abs_gcode_path(data_dir, Path::new(filename))?;
+    if !file::is_gcode_file(&path) {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{} isn't a .gcode, .gcode.gz or .gcode.zst file", filename)));
+    }
+    if path.exists() {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("file already exists @ {}", filename)));
+    }
+    Ok(path)
+}
+
+pub fn start(data_dir: &Path, filename: &str, size: u64, config: &UploadConfig) -> std::io::Result<UploadStatus> {
+    destination(data_dir, filename)?;
+    check_space(data_dir, size, config)?;
+
+    let upload = UploadStatus { id: format!("{:016x}", rand::random::<u64>()), filename: filename.to_string(), size, received: 0 };
+    let as_string = rocket::serde::json::to_string(&upload)
+    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    std::fs::create_dir_all(data_dir.join(UPLOAD_DIR))?;
+    std::fs::File::create(upload_path(data_dir, &upload.id, "part")?)?;
+    std::fs::write(upload_path(data_dir, &upload.id, "json")?, as_string)?;
+    info!("Started upload {} of {}, {} bytes", upload.id, filename, size);
+
+    Ok(upload)
+}
+
+pub fn status(data_dir: &Path, id: &str) -> std::io::Result<UploadStatus> {
+    let contents = match std::fs::read_to_string(upload_path(data_dir, id, "json")?) {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(Error::new(ErrorKind::NotFound, format!("No upload with id {}", id)));
+        }
+        other => other?
+    };
+    let mut upload = rocket::serde::json::from_str::<UploadStatus>(&contents)
+    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    upload.received = std::fs::metadata(upload_path(data_dir, id, "part")?)?.len();
+
+    Ok(upload)
+}
+
+// Add a chunk, which has to start right where the last one ended
+pub fn append(data_dir: &Path, id: &str, offset: u64, chunk: &[u8]) -> std::io::Result<UploadStatus> {
+    let mut upload = status(data_dir, id)?;
+    if offset != upload.received {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Chunk starts at {}, expected {}", offset, upload.received)));
+    }
+    if upload.received + chunk.len() as u64 > upload.size {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Chunk goes past the end of the file ({} bytes)", upload.size)));
+    }
+
+    let mut part = std::fs::OpenOptions::new().append(true).open(upload_path(data_dir, id, "part")?)?;
+    part.write_all(chunk)?;
+    upload.received += chunk.len() as u64;
+
+    Ok(upload)
+}
+
+// Check the whole file arrived intact and move it in with the rest of the gcode files
+pub fn finish(data_dir: &Path, id: &str, sha256: &str) -> std::io::Result<PathBuf> {
+    let upload = status(data_dir, id)?;
+    if upload.received != upload.size {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Only got {} of {} bytes", upload.received, upload.size)));
+    }
+
+    let part_path = upload_path(data_dir, id, "part")?;
+    let checksum = file::sha256(&part_path)?;
+    if !checksum.eq_ignore_ascii_case(sha256) {
+        cancel(data_dir, id)?;
+        return Err(Error::new(ErrorKind::InvalidData, format!("Checksum mismatch, got {}. The upload has to start over", checksum)));
+    }
+
+    let path = destination(data_dir, &upload.filename)?;
+    if let Some(folder) = path.parent() {
+        std::fs::create_dir_all(folder)?;
+    }
+    std::fs::rename(part_path, &path)?;
+    std::fs::remove_file(upload_path(data_dir, id, "json")?)?;
+    info!("Finished upload {} of {}", id, upload.filename);
+
+    Ok(path)
+}
+
+pub fn cancel(data_dir: &Path, id: &str) -> std::io::Result<()> {
+    status(data_dir, id)?;
+    std::fs::remove_file(upload_path(data_dir, id, "part")?)?;
+    std::fs::remove_file(upload_path(data_dir, id, "json")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn resume_and_finish() {
+        let data_dir = std::env::temp_dir().join(format!("yoctoprint-upload-{}", std::process::id()));
+        std::fs::create_dir_all(data_dir.join(file::GCODE_DIR)).unwrap();
+        let config = UploadConfig { max_size_mb: 1, min_free_space_mb: 0 };
+        let contents = b"G28\nG1 X10 Y10\n";
+
+        assert!(start(&data_dir, "big.gcode", 2 * MB, &config).is_err());
+        assert!(start(&data_dir, "../big.gcode", 10, &config).is_err());
+        assert!(status(&data_dir, "../config").is_err());
+
+        let upload = start(&data_dir, "parts/clip.gcode", contents.len() as u64, &config).unwrap();
+        append(&data_dir, &upload.id, 0, &contents[..4]).unwrap();
+        // Chunk got lost, the client finds out where to carry on from
+        assert!(append(&data_dir, &upload.id, 8, &contents[8..]).is_err());
+        assert_eq!(status(&data_dir, &upload.id).unwrap().received, 4);
+        assert!(finish(&data_dir, &upload.id, "").is_err());
+        append(&data_dir, &upload.id, 4, &contents[4..]).unwrap();
+
+        let checksum = format!("{:x}", Sha256::digest(contents));
+        let path = finish(&data_dir, &upload.id, &checksum).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), contents);
+        assert!(status(&data_dir, &upload.id).is_err());
+
+        let upload = start(&data_dir, "other.gcode", 1, &config).unwrap();
+        append(&data_dir, &upload.id, 0, b"x").unwrap();
+        assert_eq!(finish(&data_dir, &upload.id, &checksum).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(status(&data_dir, &upload.id).is_err());
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn stale_uploads_removed() {
+        let data_dir = std::env::temp_dir().join(format!("yoctoprint-upload-stale-{}", std::process::id()));
+        std::fs::create_dir_all(data_dir.join(file::GCODE_DIR)).unwrap();
+        let config = UploadConfig { max_size_mb: 1, min_free_space_mb: 0 };
+
+        let old = start(&data_dir, "old.gcode", 10, &config).unwrap();
+        let recent = start(&data_dir, "recent.gcode", 10, &config).unwrap();
+        append(&data_dir, &recent.id, 0, b"G28\n").unwrap();
+        assert_eq!(outstanding(&data_dir).unwrap().iter().map(|upload| upload.size - upload.received).sum::<u64>(), 16);
+
+        let part = std::fs::File::options().write(true).open(upload_path(&data_dir, &old.id, "part").unwrap()).unwrap();
+        part.set_modified(std::time::SystemTime::now() - UPLOAD_EXPIRY * 2).unwrap();
+        remove_stale(&data_dir).unwrap();
+        assert!(status(&data_dir, &old.id).is_err());
+        assert_eq!(status(&data_dir, &recent.id).unwrap().received, 4);
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
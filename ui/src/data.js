@@ -10,7 +10,7 @@ export function fetch_api(method, path, body = null) {
         'Accept': 'application/json',
         'content-type' : 'application/json'
     },
-    body : body, keepalive: true})
+    body : body, keepalive: true, credentials: 'include'})
     .then(resp => resp.json())
     .catch(err => {
         notify(err);
@@ -23,7 +23,7 @@ export function send_api_cmd(method, path, body = null) {
         'Accept': 'application/json',
         'content-type' : 'application/json'
     },
-    body : body, keepalive: true})
+    body : body, keepalive: true, credentials: 'include'})
     .then((rsp) => {
         if (!rsp.ok) {
             rsp.json().then(err => {
@@ -37,12 +37,47 @@ export function send_api_cmd(method, path, body = null) {
     });
 }
 
+// Rejects with the API's error, so problems found in the file can be shown and overridden
+export function start_print(force = false) {
+    return fetch(api_url() + "start_print" + (force ? "?force=true" : ""), {method: "POST",
+    headers: {
+        'Accept': 'application/json',
+        'content-type' : 'application/json'
+    },
+    credentials: 'include'})
+    .then((rsp) => {
+        refreshStatus();
+        if (!rsp.ok) {
+            return rsp.json().then(err => {throw err});
+        }
+    });
+}
+
+export function login(name, password) {
+    return fetch(api_url() + "login", {method: "POST",
+    headers: {
+        'Accept': 'application/json',
+        'content-type' : 'application/json'
+    },
+    body: JSON.stringify({"name": name, "password": password}), credentials: 'include'})
+    .then((rsp) => {
+        if (!rsp.ok) {
+            return rsp.json().then(err => {throw err.description});
+        }
+        refreshStatus();
+    });
+}
+
 export const server_addr = window.location.hostname;
 
 export function console_url() {
     return 'ws://' + server_addr + ":5000/api/console";
 }
 
+export function events_url() {
+    return 'ws://' + server_addr + ":5000/api/events";
+}
+
 export function api_url() {
     return 'http://' + server_addr + ":5000/api/";
 }
@@ -57,18 +92,50 @@ const default_status = {
 
 const status = readable(default_status, (set) => {
     refreshStatus =  () => 
-        fetch_api("GET", "status")
-        .then(data => {
-            data["host_connected"] = true;
-            set(data)
+        fetch(api_url() + "status", {credentials: 'include'})
+        .then(resp => {
+            // Not logged in, the login form takes it from here
+            if (resp.status == 401) {
+                current = {...default_status, "host_connected": true, "logged_in": false};
+                set(current);
+                return;
+            }
+            return resp.json().then(data => {
+                data["host_connected"] = true;
+                data["logged_in"] = true;
+                current = data;
+                set(data)
+            });
         }).catch(err => {
             set(default_status);
             console.error(err);
             notify(err);
         });
 
-    let refreshInterval = () => refreshStatus().then(() => {setTimeout(() => {refreshInterval()}, 1000)});
+    // Status changes get pushed over the events socket, only poll while it's down
+    let current = default_status;
+    let events = null;
+    let openEvents = () => {
+        events = new WebSocket(events_url());
+        events.onmessage = (msg_json) => {
+            let event = JSON.parse(msg_json.data);
+            if (event.STATUS) {
+                current = {...current, ...event.STATUS, "host_connected": true};
+                set(current);
+            }
+        }
+        events.onclose = (_ev) => {
+            events = null;
+            setTimeout(openEvents, 5000);
+        }
+    };
+
+    let refreshInterval = () => {
+        let refresh = (events && events.readyState == 1) ? Promise.resolve() : refreshStatus();
+        refresh.then(() => {setTimeout(() => {refreshInterval()}, 1000)});
+    };
     
+    openEvents();
     refreshInterval();
 });
 